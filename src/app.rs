@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use eframe::Frame;
 use egui::Context;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Mutex;
 
 use crate::scheduler::{ChannelAttachment, Command, GUICommand};
@@ -10,9 +11,10 @@ use crate::utils::Attach;
 
 pub struct Chaos {
     pub gui_state: Arc<Mutex<GUIState>>,
-    pub attachment: Arc<Mutex<ChannelAttachment>>,
+    pub tx: Sender<Command>,
     // pub independent_state: Arc<RwLock<IndependentState>>, //only for reading
-    pub reader_thread: bool,
+    //Taken by the reader thread once it starts.
+    pub rx: Option<Receiver<Command>>,
 }
 impl Chaos {
     pub fn new(
@@ -20,11 +22,12 @@ impl Chaos {
         // independent_state: Arc<RwLock<IndependentState>>,
         attachment: ChannelAttachment,
     ) -> Self {
+        let (tx, rx) = attachment;
         Self {
             gui_state,
             // independent_state,
-            attachment: Arc::new(Mutex::new(attachment)),
-            reader_thread: false,
+            tx,
+            rx: Some(rx),
         }
     }
 }
@@ -34,7 +37,9 @@ impl Attach for Chaos {
         channel_attachment: ChannelAttachment,
         _: Option<crate::scheduler::ThreadTypes>,
     ) {
-        self.attachment = Arc::new(Mutex::new(channel_attachment));
+        let (tx, rx) = channel_attachment;
+        self.tx = tx;
+        self.rx = Some(rx);
     }
 }
 /*pub struct Chaos {
@@ -109,11 +114,7 @@ fn menu_bar(ctx: &Context) {
         });
     });
 }
-fn connection_request_popup(
-    ctx: &Context,
-    remote_id: &UserId,
-    attachment: &Arc<Mutex<ChannelAttachment>>,
-) {
+fn connection_request_popup(ctx: &Context, remote_id: &UserId, tx: &Sender<Command>) {
     ctx.show_viewport_immediate(
         egui::ViewportId::from_hash_of("Connection Request"),
         egui::ViewportBuilder::default()
@@ -121,8 +122,6 @@ fn connection_request_popup(
             .with_inner_size([200.0, 100.0]),
         |ctx, class| {
             egui::CentralPanel::default().show(ctx, |ui| {
-                let attachment = attachment.try_lock().unwrap();
-                let (tx, _) = attachment.clone();
                 if ui.button("Accept").clicked() {
                     tx.try_send(Command::GUI(GUICommand::CallAnswer(
                         true,
@@ -145,7 +144,7 @@ fn app_body(
     ctx: &Context,
     gui_state: &mut Arc<Mutex<GUIState>>,
     // independent_state: &mut Arc<RwLock<IndependentState>>,
-    attachment: &mut Arc<Mutex<ChannelAttachment>>,
+    tx: &Sender<Command>,
 ) {
     let gui_state = gui_state.clone();
     let gui_state2 = gui_state.clone();
@@ -153,8 +152,8 @@ fn app_body(
     // let independent_state = independent_state.clone();
     // let independent_state2 = independent_state.clone(); //never make this mut
     //
-    let attachment = attachment.clone();
-    let attachment2 = attachment.clone();
+    let tx2 = tx.clone();
+    let tx = tx.clone();
     egui::SidePanel::left("sidebar")
         .resizable(true)
         .default_width(150.0)
@@ -171,11 +170,9 @@ fn app_body(
             {
                 gui_state.current_sidebar_button = SidebarButton::NewConnection;
             }
-            let attachment = attachment.clone();
-
             for connection in gui_state.display_state.connections.iter() {
                 if connection.1.progress == ConnectionProgress::CallRequestReceived {
-                    connection_request_popup(ctx, connection.0, &attachment);
+                    connection_request_popup(ctx, connection.0, &tx);
                 }
                 let sidebar_selected_changed = ui.selectable_label(
                     &gui_state.current_sidebar_button == &SidebarButton::Chat(connection.0.clone()),
@@ -189,7 +186,6 @@ fn app_body(
     egui::CentralPanel::default().show(ctx, move |ui| {
         let mut gui_state = gui_state2.try_lock().unwrap();
         // let independent_state = independent_state2.try_read().unwrap();
-        let attachment = attachment2.clone();
 
         ui.label(format!(
             "Client ID: {}",
@@ -197,7 +193,7 @@ fn app_body(
         ));
         ui.add(egui::TextEdit::singleline(&mut gui_state.remote_id).hint_text("Enter Remote ID"));
         if ui.button("Connect").clicked() {
-            button_click(attachment, gui_state.remote_id.clone());
+            button_click(&tx2, gui_state.remote_id.clone());
         }
 
         if let Some(connection) = gui_state
@@ -209,8 +205,7 @@ fn app_body(
         }
     });
 }
-fn button_click(attachment: Arc<Mutex<ChannelAttachment>>, remote_id: UserId) {
-    let (tx, _) = attachment.try_lock().unwrap().clone();
+fn button_click(tx: &Sender<Command>, remote_id: UserId) {
     tx.try_send(Command::GUI(GUICommand::CallRequest(remote_id.clone())))
         .unwrap();
 }
@@ -222,27 +217,24 @@ impl eframe::App for Chaos {
         let Self {
             gui_state,
             // independent_state,
-            attachment,
-            reader_thread,
+            tx,
+            rx,
         } = self;
         menu_bar(ctx);
-        app_body(ctx, gui_state, /*independent_state,*/ attachment);
-        if !(*reader_thread) {
-            *reader_thread = true;
+        app_body(ctx, gui_state, /*independent_state,*/ tx);
+        if let Some(mut rx) = rx.take() {
             let ctx = ctx.clone();
-            let attachment = attachment.clone();
             let gui_state = gui_state.clone();
             tokio::spawn(async move {
-                let (_, rx) = attachment.try_lock().unwrap().clone();
                 println!("Starting gui listener");
                 let gui_state = gui_state.clone();
-                while let Ok(command) = rx.recv() {
+                while let Some(command) = rx.recv().await {
                     println!("Received a message on GUI listener.");
                     if let Command::GUI(gui_command) = command {
                         use GUICommand::*;
                         match gui_command {
                             UpdateState(independent_state) => {
-                                let mut gui_state = gui_state.try_lock().unwrap();
+                                let mut gui_state = gui_state.lock().await;
                                 gui_state.display_state = independent_state;
                                 ctx.request_repaint();
                                 println!("Updated gui state.");
@@ -0,0 +1,787 @@
+use std::collections::HashSet;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{watch, RwLock};
+
+use chaos::config::{self, Config};
+use chaos::coupler::Coupler;
+use chaos::database::Database;
+use chaos::peer::Peer;
+use chaos::scheduler::{
+    self, ChannelAttachment, Command, GUICommand, MediaCommand, Scheduler, ThreadTypes,
+};
+use chaos::state::{
+    CategoryId, ChannelId, ChaosMessage, ConnectionProgress, FriendStatus, GroupId,
+    IndependentState, MessageId, MessageStatus, Permission, RoleId, ServerEdit, ServerId,
+    TransferId, TransferStatus, UserId, VoiceStatus,
+};
+use chaos::utils::crypto::Identity;
+use chaos::utils::{storage, Attach};
+
+//At the end of piped input we wait this long for what it sent to go out, `--wait <seconds>`
+//changes it.
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+//Settled and nothing new from the scheduler for this long counts as done.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+const SERVER_EDITS: &str = "Server changes:
+  rename <name>
+  category <name>
+  channel <name> [category id]
+  remove-channel <cid>
+  role <name> [channels] [roles] [members]
+                      a role that can manage the given parts of the server
+  grant <id> <role id>
+  revoke <id> <role id>
+  add <id>
+  remove <id>
+  leave";
+
+const USAGE: &str = "Commands:
+  whoami              print your user id
+  call <id>           send a call request
+  accept [id]         accept a call request, any pending one if no id is given
+  reject [id]         reject a call request, any pending one if no id is given
+  send <id> <text>    send a message over an established connection
+  typing <id>         show id that you are typing, until you send or it times out
+  read <id>           tell id you have read their messages
+  file <id> <path>    offer id a file over an established connection
+  accept-file <tid>   accept a file offer, it is saved to the downloads directory
+  reject-file <tid>   reject a file offer
+  voice <id>          start a voice call over an established connection
+  pick-up [id]        take a voice call, any ringing one if no id is given
+  decline [id]        turn down a voice call, any ringing one if no id is given
+  mute <id>           stop sending audio in a call, `unmute <id>` to send again
+  hang-up <id>        end a voice call
+  group <name> [id..] create a group with the given members, names can't have spaces
+  gsend <gid> <text>  send a message to everyone in a group
+  gadd <gid> <id>     add id to a group
+  gremove <gid> <id>  remove id from a group
+  gleave <gid>        leave a group
+  groups              list groups and their members
+  server <name> [id..]
+                      create a server with a general channel and the given members
+  csend <cid> <text>  send a message to a server channel
+  sedit <sid> <edit>  change a server, `sedit` alone lists the changes
+  servers             list servers with their channels, roles and members
+  contact <id> <name> [notes]
+                      add a contact or change their name and notes, names can't have spaces
+  forget <id>         remove a contact
+  friend <id>         send a friend request, or accept the one id sent
+  accept-friend <id>  accept a friend request, `reject-friend <id>` to turn it down
+  block <id>          turn down calls and friend requests from id, `unblock <id>` to stop
+  contacts            list contacts with their friend status and notes
+  list                list known connections and their progress
+  help                print this help
+  quit                exit, without waiting on what is still on its way";
+
+//Runs a command given on the command line, then keeps reading commands from stdin until quit or
+//EOF, e.g. `chaos-cli call <id>` or `echo "send <id> hi" | chaos-cli`. At EOF it waits for the
+//commands to go through before exiting.
+#[tokio::main]
+async fn main() {
+    let env_args = env::args().skip(1).collect::<Vec<String>>();
+    let args = command_args(&env_args);
+    let wait = config::flag(&env_args, "--wait")
+        .map(|wait| wait.parse().expect("--wait takes a number of seconds."))
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_WAIT);
+    let identity_path = storage::data_dir().join("identity.key");
+    let identity =
+        Arc::new(Identity::load_or_generate(&identity_path).expect("Could not load identity."));
+    if args.first().map(String::as_str) == Some("whoami") {
+        println!("{}", identity.user_id());
+        return;
+    }
+    let config = Config::load().expect("Could not load config.");
+    let database = Database::open(&storage::data_dir().join("history.db"))
+        .expect("Could not open message history.");
+    let mut independent_state = IndependentState::default();
+    independent_state.connection_details.id = identity.user_id();
+    independent_state.connection_details.public_key = identity.public_key();
+    independent_state.connections = database.load().expect("Could not load message history.");
+    independent_state.groups = database
+        .load_groups()
+        .expect("Could not load group history.");
+    independent_state.servers = database
+        .load_servers()
+        .expect("Could not load server history.");
+    independent_state.contacts = database.load_contacts().expect("Could not load contacts.");
+    //Messages still queued from an earlier run can take a while, only wait on this run's.
+    let queued_before = unacknowledged(&independent_state)
+        .map(|message| message.id)
+        .collect::<HashSet<MessageId>>();
+    let (shown_state, shown_state_rx) = watch::channel(independent_state.clone());
+    let (tx, rx) = setup_threads(independent_state, database, identity, &config).await;
+    tokio::spawn(print_updates(rx, shown_state));
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut running = args.is_empty() || run_command(&args.join(" "), &tx, &shown_state_rx).await;
+    while running {
+        running = match lines.next_line().await {
+            Ok(Some(line)) => run_command(&line, &tx, &shown_state_rx).await,
+            _ => {
+                let shown_state = shown_state_rx.clone();
+                wait_until_settled(&tx, shown_state, &queued_before, wait).await;
+                false
+            }
+        };
+    }
+    //The scheduler threads never return on their own, so don't wait for the runtime to drain.
+    process::exit(0);
+}
+
+//The cli takes the place of the gui on the scheduler.
+async fn setup_threads(
+    independent_state: IndependentState,
+    database: Database,
+    identity: Arc<Identity>,
+    config: &Config,
+) -> ChannelAttachment {
+    let mut scheduler = Scheduler::new(
+        Arc::new(RwLock::new(independent_state)),
+        database,
+        identity.clone(),
+    );
+
+    let scheduler_coupler = scheduler::channel();
+    let coupler_scheduler = scheduler::channel();
+    scheduler.attach(
+        (scheduler_coupler.0, coupler_scheduler.1),
+        Some(ThreadTypes::Coupler),
+    );
+    let coupler = Coupler::new(
+        (coupler_scheduler.0, scheduler_coupler.1),
+        identity.clone(),
+        config,
+    );
+
+    let scheduler_peer = scheduler::channel();
+    let peer_scheduler = scheduler::channel();
+    scheduler.attach(
+        (scheduler_peer.0, peer_scheduler.1),
+        Some(ThreadTypes::Peer),
+    );
+    let peer = Peer::new((peer_scheduler.0, scheduler_peer.1), identity, config).await;
+
+    let scheduler_cli = scheduler::channel();
+    let cli_scheduler = scheduler::channel();
+    scheduler.attach((scheduler_cli.0, cli_scheduler.1), Some(ThreadTypes::GUI));
+
+    scheduler.run();
+    coupler.start().await;
+    peer.start().await;
+    (cli_scheduler.0, scheduler_cli.1)
+}
+
+//Same as the gui, only listens so the scheduler never waits on us while we wait on it.
+async fn print_updates(mut rx: Receiver<Command>, shown_state: watch::Sender<IndependentState>) {
+    while let Some(command) = rx.recv().await {
+        match command {
+            Command::GUI(GUICommand::UpdateState(independent_state)) => {
+                print_changes(&shown_state.borrow(), &independent_state);
+                shown_state.send_replace(independent_state);
+            }
+            Command::GUI(GUICommand::Notify(message)) => println!("! {}", message),
+            _ => {}
+        }
+    }
+}
+
+//Returns false once the cli should exit.
+async fn run_command(
+    line: &str,
+    tx: &Sender<Command>,
+    shown_state: &watch::Receiver<IndependentState>,
+) -> bool {
+    //A copy, the printer can't update the shown state while we wait on the scheduler.
+    let state = &shown_state.borrow().clone();
+    let line = line.trim();
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    let gui_command = match command {
+        "" => return true,
+        "quit" | "exit" => return false,
+        "help" => {
+            println!("{}", USAGE);
+            return true;
+        }
+        "whoami" => {
+            println!("{}", state.connection_details.id);
+            return true;
+        }
+        "list" => {
+            let mut connections = state.connections.iter().collect::<Vec<_>>();
+            connections.sort_by(|a, b| a.0.cmp(b.0));
+            for (remote_id, connection) in connections {
+                println!("{} {:?}", remote_id, connection.progress);
+            }
+            return true;
+        }
+        "groups" => {
+            let mut groups = state.groups.values().collect::<Vec<_>>();
+            groups.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+            for group in groups {
+                println!(
+                    "{} {} {}",
+                    group.info.id,
+                    group.info.name,
+                    members(state, &group.info.id)
+                );
+            }
+            return true;
+        }
+        "servers" => {
+            let mut servers = state.servers.values().collect::<Vec<_>>();
+            servers.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+            for server in servers {
+                let info = &server.info;
+                println!("{} {} owned by {}", info.id, info.name, info.owner);
+                for channel in &info.channels {
+                    let category = info
+                        .categories
+                        .iter()
+                        .find(|category| Some(category.id) == channel.category)
+                        .map_or("", |category| category.name.as_str());
+                    println!("  #{} {} {}", channel.name, channel.id, category);
+                }
+                for category in &info.categories {
+                    println!("  category {} {}", category.name, category.id);
+                }
+                for role in &info.roles {
+                    println!("  role {} {} {:?}", role.name, role.id, role.permissions);
+                }
+                for (member, role_ids) in &info.members {
+                    let roles = info
+                        .roles
+                        .iter()
+                        .filter(|role| role_ids.contains(&role.id))
+                        .map(|role| role.name.as_str())
+                        .collect::<Vec<_>>();
+                    println!("  member {} {}", member, roles.join(", "));
+                }
+            }
+            return true;
+        }
+        "contacts" => {
+            let mut contacts = state.contacts.values().collect::<Vec<_>>();
+            contacts.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+            for contact in contacts {
+                let blocked = if contact.blocked { " blocked" } else { "" };
+                println!(
+                    "{} {} {:?}{} {}",
+                    contact.id, contact.display_name, contact.friend_status, blocked, contact.notes
+                );
+            }
+            return true;
+        }
+        "contact" => {
+            let mut args = rest.splitn(3, ' ');
+            let (Some(remote_id), Some(name)) = (args.next(), args.next()) else {
+                println!("Usage: contact <id> <name> [notes]");
+                return true;
+            };
+            let notes = args.next().unwrap_or_default().trim().to_string();
+            GUICommand::SaveContact(remote_id.to_string(), name.to_string(), notes)
+        }
+        "forget" if !rest.is_empty() => GUICommand::RemoveContact(rest.to_string()),
+        "friend" if !rest.is_empty() => GUICommand::SendFriendRequest(rest.to_string()),
+        "accept-friend" | "reject-friend" if !rest.is_empty() => {
+            GUICommand::AnswerFriendRequest(rest.to_string(), command == "accept-friend")
+        }
+        "block" | "unblock" if !rest.is_empty() => {
+            GUICommand::SetBlocked(rest.to_string(), command == "block")
+        }
+        "call" if !rest.is_empty() => GUICommand::CallRequest(rest.to_string()),
+        "accept" | "reject" => {
+            let remote_id = if rest.is_empty() {
+                pending_call_request(state)
+            } else {
+                Some(rest.to_string())
+            };
+            let Some(remote_id) = remote_id else {
+                println!("No pending call request.");
+                return true;
+            };
+            GUICommand::CallAnswer(command == "accept", remote_id)
+        }
+        "typing" if !rest.is_empty() => GUICommand::SetTyping(rest.to_string(), true),
+        "read" if !rest.is_empty() => GUICommand::MarkRead(rest.to_string()),
+        "file" => match rest.split_once(' ') {
+            Some((remote_id, path)) if !path.trim().is_empty() => {
+                GUICommand::SendFile(remote_id.to_string(), PathBuf::from(path.trim()))
+            }
+            _ => {
+                println!("Usage: file <id> <path>");
+                return true;
+            }
+        },
+        "accept-file" | "reject-file" => {
+            let Some((remote_id, transfer_id)) = file_offer(state, rest) else {
+                println!("No file offer {}.", rest);
+                return true;
+            };
+            GUICommand::AnswerFile(remote_id, transfer_id, command == "accept-file")
+        }
+        "voice" if !rest.is_empty() => {
+            return send_media(tx, MediaCommand::StartCall(rest.to_string())).await
+        }
+        "pick-up" | "decline" => {
+            let remote_id = if rest.is_empty() {
+                ringing_call(state)
+            } else {
+                Some(rest.to_string())
+            };
+            let Some(remote_id) = remote_id else {
+                println!("No voice call is ringing.");
+                return true;
+            };
+            let media_command = MediaCommand::AnswerCall(remote_id, command == "pick-up");
+            return send_media(tx, media_command).await;
+        }
+        "mute" | "unmute" if !rest.is_empty() => {
+            let media_command = MediaCommand::SetMuted(rest.to_string(), command == "mute");
+            return send_media(tx, media_command).await;
+        }
+        "hang-up" if !rest.is_empty() => {
+            return send_media(tx, MediaCommand::HangUp(rest.to_string())).await
+        }
+        "group" if !rest.is_empty() => {
+            let mut args = rest.split_whitespace().map(str::to_string);
+            let name = args.next().unwrap_or_default();
+            GUICommand::CreateGroup(name, args.collect())
+        }
+        "gsend" | "gadd" | "gremove" => {
+            let Some((group_id, rest)) = rest.split_once(' ') else {
+                println!(
+                    "Usage: {} <gid> <{}>",
+                    command,
+                    if command == "gsend" { "text" } else { "id" }
+                );
+                return true;
+            };
+            let Ok(group_id) = GroupId::parse_str(group_id) else {
+                println!("No group {}.", group_id);
+                return true;
+            };
+            let rest = rest.trim().to_string();
+            match command {
+                "gsend" => GUICommand::SendGroupMessage(group_id, rest),
+                "gadd" => GUICommand::AddGroupMember(group_id, rest),
+                _ => GUICommand::RemoveGroupMember(group_id, rest),
+            }
+        }
+        "gleave" => {
+            let Ok(group_id) = GroupId::parse_str(rest) else {
+                println!("No group {}.", rest);
+                return true;
+            };
+            GUICommand::RemoveGroupMember(group_id, state.connection_details.id.clone())
+        }
+        "server" if !rest.is_empty() => {
+            let mut args = rest.split_whitespace().map(str::to_string);
+            let name = args.next().unwrap_or_default();
+            GUICommand::CreateServer(name, args.collect())
+        }
+        "csend" => {
+            let channel = rest.split_once(' ').and_then(|(channel_id, message)| {
+                let channel_id = ChannelId::parse_str(channel_id).ok()?;
+                Some((channel_server(state, &channel_id)?, channel_id, message))
+            });
+            let Some((server_id, channel_id, message)) = channel else {
+                println!("Usage: csend <cid> <text>, with the id of a channel from `servers`");
+                return true;
+            };
+            GUICommand::SendChannelMessage(server_id, channel_id, message.trim().to_string())
+        }
+        "sedit" => {
+            let edit = rest.split_once(' ').and_then(|(server_id, edit)| {
+                let server_id = ServerId::parse_str(server_id).ok()?;
+                Some((server_id, server_edit(state, edit.trim())?))
+            });
+            let Some((server_id, edit)) = edit else {
+                println!("Usage: sedit <sid> <edit>\n{}", SERVER_EDITS);
+                return true;
+            };
+            GUICommand::EditServer(server_id, edit)
+        }
+        "send" => match rest.split_once(' ') {
+            Some((remote_id, message)) if !message.trim().is_empty() => {
+                GUICommand::SendMessage(remote_id.to_string(), message.trim().to_string())
+            }
+            _ => {
+                println!("Usage: send <id> <text>");
+                return true;
+            }
+        },
+        _ => {
+            println!("Unknown command: {}\n{}", line, USAGE);
+            return true;
+        }
+    };
+    scheduler::send(tx, Command::GUI(gui_command)).await;
+    true
+}
+
+async fn send_media(tx: &Sender<Command>, media_command: MediaCommand) -> bool {
+    scheduler::send(tx, Command::Media(media_command)).await;
+    true
+}
+
+//Parses the edit part of `sedit`.
+fn server_edit(state: &IndependentState, edit: &str) -> Option<ServerEdit> {
+    let (edit, rest) = edit.split_once(' ').unwrap_or((edit, ""));
+    let mut args = rest.split_whitespace();
+    let edit = match edit {
+        "rename" if !rest.is_empty() => ServerEdit::Rename(rest.to_string()),
+        "category" if !rest.is_empty() => ServerEdit::AddCategory(rest.to_string()),
+        "channel" => {
+            let name = args.next()?.to_string();
+            let category = match args.next() {
+                Some(category_id) => Some(CategoryId::parse_str(category_id).ok()?),
+                None => None,
+            };
+            ServerEdit::AddChannel(name, category)
+        }
+        "remove-channel" => ServerEdit::RemoveChannel(ChannelId::parse_str(args.next()?).ok()?),
+        "role" => {
+            let name = args.next()?.to_string();
+            let permissions = args
+                .map(|permission| match permission {
+                    "channels" => Some(Permission::ManageChannels),
+                    "roles" => Some(Permission::ManageRoles),
+                    "members" => Some(Permission::ManageMembers),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?;
+            ServerEdit::AddRole(name, permissions)
+        }
+        "grant" | "revoke" => {
+            let member = args.next()?.to_string();
+            let role_id = RoleId::parse_str(args.next()?).ok()?;
+            ServerEdit::SetRole(member, role_id, edit == "grant")
+        }
+        "add" => ServerEdit::AddMember(args.next()?.to_string()),
+        "remove" => ServerEdit::RemoveMember(args.next()?.to_string()),
+        "leave" => ServerEdit::RemoveMember(state.connection_details.id.clone()),
+        _ => return None,
+    };
+    Some(edit)
+}
+
+//The server a channel belongs to.
+fn channel_server(state: &IndependentState, channel_id: &ChannelId) -> Option<ServerId> {
+    state
+        .servers
+        .values()
+        .find(|server| server.info.channel(channel_id).is_some())
+        .map(|server| server.info.id)
+}
+
+fn members(state: &IndependentState, group_id: &GroupId) -> String {
+    state.groups.get(group_id).map_or(String::new(), |group| {
+        group
+            .info
+            .members
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+fn ringing_call(state: &IndependentState) -> Option<UserId> {
+    state
+        .connections
+        .iter()
+        .find(|(_, connection)| connection.voice == VoiceStatus::Ringing)
+        .map(|(remote_id, _)| remote_id.clone())
+}
+
+fn pending_call_request(state: &IndependentState) -> Option<UserId> {
+    state
+        .connections
+        .iter()
+        .find(|(_, connection)| connection.progress == ConnectionProgress::CallRequestReceived)
+        .map(|(remote_id, _)| remote_id.clone())
+}
+
+//The remote that offered transfer_id, if it is still waiting for an answer.
+fn file_offer(state: &IndependentState, transfer_id: &str) -> Option<(UserId, TransferId)> {
+    let transfer_id = TransferId::parse_str(transfer_id).ok()?;
+    state
+        .connections
+        .iter()
+        .find_map(|(remote_id, connection)| {
+            connection
+                .transfers()
+                .iter()
+                .any(|transfer| {
+                    transfer.incoming
+                        && transfer.offer.id == transfer_id
+                        && transfer.status == TransferStatus::Offered
+                })
+                .then(|| (remote_id.clone(), transfer_id))
+        })
+}
+
+//Prints progress changes and new messages between two states sent by the scheduler.
+fn print_changes(old: &IndependentState, new: &IndependentState) {
+    if old.signaling_status != new.signaling_status {
+        println!("> signaling {:?}", new.signaling_status);
+    }
+    let mut connections = new.connections.iter().collect::<Vec<_>>();
+    connections.sort_by(|a, b| a.0.cmp(b.0));
+    for (remote_id, connection) in connections {
+        let old_connection = old.connections.get(remote_id);
+        if old_connection.map(|c| c.progress) != Some(connection.progress) {
+            println!("> {} {:?}", remote_id, connection.progress);
+            if connection.progress == ConnectionProgress::CallRequestReceived {
+                println!("> call request from {}, `accept` or `reject` it", remote_id);
+            }
+        }
+        let old_voice = old_connection.map_or((VoiceStatus::Idle, false), |c| (c.voice, c.muted));
+        if old_voice != (connection.voice, connection.muted) {
+            let muted = if connection.muted { " (muted)" } else { "" };
+            println!("> {} voice {:?}{}", remote_id, connection.voice, muted);
+            if connection.voice == VoiceStatus::Ringing {
+                println!("> {} is calling, `pick-up` or `decline` it", remote_id);
+            }
+        }
+        let was_typing = old_connection.is_some_and(|c| c.is_remote_typing());
+        if connection.is_remote_typing() && !was_typing {
+            println!("> {} is typing", remote_id);
+        }
+        let old_messages = old_connection.map_or(&[][..], |c| c.messages());
+        for message in connection.messages() {
+            match old_messages.iter().find(|old| old.id == message.id) {
+                None => println!(
+                    "[{}] {} {}: {}",
+                    remote_id,
+                    message
+                        .timestamp
+                        .with_timezone(&chrono::Local)
+                        .format("%H:%M"),
+                    message.client_id,
+                    message.message_content
+                ),
+                //Received messages only change when we read them ourselves.
+                Some(old)
+                    if old.status != message.status
+                        && message.client_id == new.connection_details.id =>
+                {
+                    println!(
+                        "> {} {:?}: {}",
+                        remote_id, message.status, message.message_content
+                    )
+                }
+                Some(_) => {}
+            }
+        }
+        let old_transfers = old_connection.map_or(&[][..], |c| c.transfers());
+        for transfer in connection.transfers() {
+            let old_status = old_transfers
+                .iter()
+                .find(|old| old.offer.id == transfer.offer.id)
+                .map(|old| old.status);
+            if old_status == Some(transfer.status) {
+                continue;
+            }
+            if transfer.incoming && transfer.status == TransferStatus::Offered {
+                println!(
+                    "> {} offers {} ({} bytes), `accept-file {}` or `reject-file {}` it",
+                    remote_id,
+                    transfer.offer.name,
+                    transfer.offer.size,
+                    transfer.offer.id,
+                    transfer.offer.id
+                );
+                continue;
+            }
+            println!(
+                "> {} {:?}: {}",
+                remote_id, transfer.status, transfer.offer.name
+            );
+        }
+    }
+    for (group_id, group) in &new.groups {
+        let old_group = old.groups.get(group_id);
+        if old_group.map(|g| &g.info) != Some(&group.info) {
+            println!(
+                "> group {} {}: {}",
+                group_id,
+                group.info.name,
+                members(new, group_id)
+            );
+        }
+        let old_messages = old_group.map_or(&[][..], |g| g.history.messages());
+        for message in group.history.messages() {
+            if old_messages.iter().all(|old| old.id != message.id) {
+                println!(
+                    "[{}] {} {}: {}",
+                    group.info.name,
+                    message
+                        .timestamp
+                        .with_timezone(&chrono::Local)
+                        .format("%H:%M"),
+                    message.client_id,
+                    message.message_content
+                );
+            }
+        }
+    }
+    for (server_id, server) in &new.servers {
+        let old_server = old.servers.get(server_id);
+        let info = &server.info;
+        if old_server.map(|s| &s.info) != Some(info) {
+            println!(
+                "> server {} {}: {} members, channels {}",
+                server_id,
+                info.name,
+                info.members.len(),
+                info.channels
+                    .iter()
+                    .map(|channel| format!("#{}", channel.name))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        for channel in &info.channels {
+            let old_messages = old_server
+                .and_then(|s| s.history(&channel.id))
+                .map_or(&[][..], |history| history.messages());
+            let Some(history) = server.history(&channel.id) else {
+                continue;
+            };
+            for message in history.messages() {
+                if old_messages.iter().all(|old| old.id != message.id) {
+                    println!(
+                        "[{}#{}] {} {}: {}",
+                        info.name,
+                        channel.name,
+                        message
+                            .timestamp
+                            .with_timezone(&chrono::Local)
+                            .format("%H:%M"),
+                        message.client_id,
+                        message.message_content
+                    );
+                }
+            }
+        }
+    }
+    for (remote_id, contact) in &new.contacts {
+        let old_contact = old.contacts.get(remote_id);
+        if old_contact == Some(contact) {
+            continue;
+        }
+        let requested = old_contact.map(|c| c.friend_status) != Some(contact.friend_status)
+            && contact.friend_status == FriendStatus::RequestReceived;
+        if requested {
+            println!(
+                "> friend request from {}, `accept-friend` or `reject-friend` it",
+                remote_id
+            );
+            continue;
+        }
+        let blocked = if contact.blocked { " blocked" } else { "" };
+        println!(
+            "> contact {} {}: {:?}{}",
+            remote_id, contact.display_name, contact.friend_status, blocked
+        );
+    }
+}
+
+//Waits until the scheduler took everything we gave it and nothing we sent is still on its way,
+//or until the wait is over.
+async fn wait_until_settled(
+    tx: &Sender<Command>,
+    mut shown_state: watch::Receiver<IndependentState>,
+    queued_before: &HashSet<MessageId>,
+    wait: Duration,
+) {
+    let settled = tokio::time::timeout(wait, async {
+        loop {
+            let settled = tx.capacity() == tx.max_capacity()
+                && is_settled(&shown_state.borrow_and_update(), queued_before);
+            match tokio::time::timeout(SETTLE_TIME, shown_state.changed()).await {
+                Err(_) if settled => return,
+                Ok(Err(_)) => return,
+                _ => {}
+            }
+        }
+    })
+    .await;
+    if settled.is_err() {
+        println!(
+            "Gave up waiting after {}s, what is left goes out next time.",
+            wait.as_secs()
+        );
+    }
+}
+fn is_settled(state: &IndependentState, queued_before: &HashSet<MessageId>) -> bool {
+    use ConnectionProgress::*;
+    let connecting = state.connections.values().any(|connection| {
+        matches!(
+            connection.progress,
+            CallRequestSent
+                | CallRequestAccepted
+                | CallAnswerSent
+                | CallAnswerReceived
+                | CallReplySent
+                | CallReplyReceived
+        )
+    });
+    let transferring = state
+        .connections
+        .values()
+        .flat_map(|connection| connection.transfers())
+        .any(|transfer| transfer.status == TransferStatus::Transferring);
+    let sending = unacknowledged(state).any(|message| !queued_before.contains(&message.id));
+    !connecting && !transferring && !sending
+}
+//Our messages, direct or to groups and servers, that nobody got yet.
+fn unacknowledged(state: &IndependentState) -> impl Iterator<Item = &ChaosMessage> {
+    let direct = state
+        .connections
+        .values()
+        .flat_map(|connection| connection.messages());
+    let group = state
+        .groups
+        .values()
+        .flat_map(|group| group.history.messages());
+    let channel = state.servers.values().flat_map(|server| {
+        server
+            .info
+            .channels
+            .iter()
+            .filter_map(|channel| server.history(&channel.id))
+            .flat_map(|history| history.messages())
+    });
+    let client_id = &state.connection_details.id;
+    direct.chain(group).chain(channel).filter(move |message| {
+        &message.client_id == client_id && message.status < MessageStatus::Delivered
+    })
+}
+
+//Drops config flags and their values so only the command is left.
+fn command_args(args: &[String]) -> Vec<String> {
+    let mut command = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            if !arg.contains('=') {
+                args.next();
+            }
+        } else {
+            command.push(arg.clone());
+        }
+    }
+    command
+}
@@ -0,0 +1,365 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::Mutex;
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+
+use chaos::scheduler::WSCommand;
+use chaos::state::{CallId, UserId};
+use chaos::utils::crypto;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:3030";
+const COUPLE_PATH: &str = "/couple";
+//Calls are set up in seconds, one nobody signaled on for this long is forgotten.
+const CALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+//Only the two sides of a call can signal on it.
+struct Call {
+    caller: UserId,
+    callee: UserId,
+    last_signal: Instant,
+}
+impl Call {
+    fn is_between(&self, caller: &UserId, callee: &UserId) -> bool {
+        &self.caller == caller && &self.callee == callee
+    }
+}
+#[derive(Default)]
+struct Clients {
+    senders: HashMap<UserId, UnboundedSender<Message>>,
+    calls: HashMap<CallId, Call>,
+    //Who wants to hear once a user comes online, forgotten once they are told.
+    watchers: HashMap<UserId, HashSet<UserId>>,
+    //Who each online user lets find out they are online, so strangers can't follow them.
+    allowed_watchers: HashMap<UserId, BTreeSet<UserId>>,
+    //Friend requests to offline users with the requester's key, by the user they are for. Strangers
+    //can't watch them, so the requests wait here for as long as the requester stays connected.
+    friend_requests: HashMap<UserId, HashMap<UserId, String>>,
+}
+type SharedClients = Arc<Mutex<Clients>>;
+
+#[tokio::main]
+async fn main() {
+    let address = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind signaling server address.");
+    println!("chaos-signal listening on ws://{}{}", address, COUPLE_PATH);
+
+    let clients = SharedClients::default();
+    tokio::spawn(expire_calls(clients.clone()));
+    while let Ok((stream, peer_address)) = listener.accept().await {
+        println!("Incoming connection from {}", peer_address);
+        let clients = clients.clone();
+        tokio::spawn(async move {
+            handle_connection(clients, stream).await;
+        });
+    }
+}
+
+async fn handle_connection(clients: SharedClients, stream: TcpStream) {
+    let ws_stream = match accept_hdr_async(stream, check_path).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            println!("Websocket handshake failed: {}", e);
+            return;
+        }
+    };
+    let (mut ws_write, mut ws_read) = ws_stream.split();
+    let (tx, mut rx) = unbounded_channel::<Message>();
+
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if ws_write.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    //Clients prove they own the key their id is derived from before anything else.
+    let challenge = crypto::new_challenge();
+    send_command(&tx, &WSCommand::Challenge(challenge.clone()));
+    let client_id = match ws_read.next().await {
+        Some(Ok(ws_message)) => {
+            match serde_json::from_str::<WSCommand>(ws_message.to_text().unwrap_or_default()) {
+                Ok(WSCommand::Authenticate(public_key, signature)) => {
+                    match crypto::verify_challenge(&challenge, &public_key, &signature) {
+                        Ok(client_id) => client_id,
+                        Err(e) => {
+                            println!("Authentication failed: {}", e);
+                            return;
+                        }
+                    }
+                }
+                _ => {
+                    println!("Client did not authenticate.");
+                    return;
+                }
+            }
+        }
+        _ => return,
+    };
+    register_client(&clients, &client_id, tx.clone()).await;
+    println!("Client connected: {}", client_id);
+    send_command(&tx, &WSCommand::SetClientId(client_id.clone()));
+    let friend_requests = clients.lock().await.friend_requests.remove(&client_id);
+    for (remote_id, public_key) in friend_requests.unwrap_or_default() {
+        send_command(&tx, &WSCommand::FriendRequest(remote_id, public_key));
+    }
+
+    while let Some(Ok(ws_message)) = ws_read.next().await {
+        if ws_message.is_close() {
+            break;
+        }
+        let Ok(text) = ws_message.to_text() else {
+            continue;
+        };
+        match serde_json::from_str::<WSCommand>(text) {
+            Ok(ws_command) => route_command(&clients, &client_id, ws_command).await,
+            Err(e) => println!("Invalid websocket message from {}: {}", client_id, e),
+        }
+    }
+
+    let mut clients = clients.lock().await;
+    //A newer connection with the same identity may have replaced this one already.
+    if clients
+        .senders
+        .get(&client_id)
+        .is_some_and(|sender| sender.same_channel(&tx))
+    {
+        clients.senders.remove(&client_id);
+        clients.allowed_watchers.remove(&client_id);
+        clients
+            .calls
+            .retain(|_, call| call.caller != client_id && call.callee != client_id);
+        for watchers in clients.watchers.values_mut() {
+            watchers.remove(&client_id);
+        }
+        clients.watchers.retain(|_, watchers| !watchers.is_empty());
+        for requests in clients.friend_requests.values_mut() {
+            requests.remove(&client_id);
+        }
+        clients
+            .friend_requests
+            .retain(|_, requests| !requests.is_empty());
+    }
+    println!("Client disconnected: {}", client_id);
+}
+
+//The callback signature is fixed by tungstenite.
+#[allow(clippy::result_large_err)]
+fn check_path(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if request.uri().path() == COUPLE_PATH {
+        return Ok(response);
+    }
+    let mut error_response = ErrorResponse::new(Some("Not found".to_string()));
+    *error_response.status_mut() = StatusCode::NOT_FOUND;
+    Err(error_response)
+}
+
+async fn register_client(
+    clients: &SharedClients,
+    client_id: &UserId,
+    tx: UnboundedSender<Message>,
+) {
+    let mut clients = clients.lock().await;
+    if clients.senders.insert(client_id.clone(), tx).is_some() {
+        println!("{} reconnected, replacing its old connection", client_id);
+    }
+}
+async fn expire_calls(clients: SharedClients) {
+    let mut interval = tokio::time::interval(CALL_TIMEOUT / 2);
+    loop {
+        interval.tick().await;
+        clients
+            .lock()
+            .await
+            .calls
+            .retain(|_, call| call.last_signal.elapsed() < CALL_TIMEOUT);
+    }
+}
+
+async fn route_command(clients: &SharedClients, client_id: &UserId, ws_command: WSCommand) {
+    let mut clients = clients.lock().await;
+    use WSCommand::*;
+    match ws_command {
+        CallRequest(remote_id, call_id) => {
+            let Some(remote_tx) = clients.senders.get(&remote_id).cloned() else {
+                println!("Call request from {} to offline {}", client_id, remote_id);
+                if let Some(tx) = clients.senders.get(client_id) {
+                    send_command(tx, &CallRequestFailure(remote_id, call_id));
+                }
+                return;
+            };
+            if clients.calls.contains_key(&call_id) {
+                println!("Call request from {} reuses call {}", client_id, call_id);
+                return;
+            }
+            //A new call replaces the caller's old one. Calls the other way stay, when two users
+            //call each other at the same time the clients settle which call goes on.
+            clients
+                .calls
+                .retain(|_, call| !call.is_between(client_id, &remote_id));
+            let call = Call {
+                caller: client_id.clone(),
+                callee: remote_id,
+                last_signal: Instant::now(),
+            };
+            clients.calls.insert(call_id.clone(), call);
+            send_command(&remote_tx, &CallRequest(client_id.clone(), call_id));
+        }
+        CallAnswer(remote_id, call_id, accepted, sdp) => {
+            if !signal(&mut clients, &call_id, &remote_id, client_id) {
+                println!(
+                    "Call answer from {} for unknown call {}",
+                    client_id, call_id
+                );
+                return;
+            }
+            if !accepted {
+                clients.calls.remove(&call_id);
+            }
+            forward(
+                &mut clients,
+                client_id,
+                remote_id,
+                CallAnswer(client_id.clone(), call_id, accepted, sdp),
+            );
+        }
+        CallReply(remote_id, call_id, sdp) => {
+            if !signal(&mut clients, &call_id, client_id, &remote_id) {
+                println!("Call reply from {} for unknown call {}", client_id, call_id);
+                return;
+            }
+            forward(
+                &mut clients,
+                client_id,
+                remote_id,
+                CallReply(client_id.clone(), call_id, sdp),
+            );
+        }
+        IceCandidate(remote_id, call_id, candidate) => {
+            let in_call = signal(&mut clients, &call_id, client_id, &remote_id)
+                || signal(&mut clients, &call_id, &remote_id, client_id);
+            if !in_call {
+                println!(
+                    "ICE candidate from {} for unknown call {}",
+                    client_id, call_id
+                );
+                return;
+            }
+            forward(
+                &mut clients,
+                client_id,
+                remote_id,
+                IceCandidate(client_id.clone(), call_id, candidate),
+            );
+        }
+        //Watchers that aren't allowed yet stay, the user may allow them later.
+        WatchOnline(remote_id) => {
+            let allowed = clients
+                .allowed_watchers
+                .get(&remote_id)
+                .is_some_and(|allowed| allowed.contains(client_id));
+            if allowed && clients.senders.contains_key(&remote_id) {
+                if let Some(tx) = clients.senders.get(client_id) {
+                    send_command(tx, &Online(remote_id));
+                }
+                return;
+            }
+            clients
+                .watchers
+                .entry(remote_id)
+                .or_default()
+                .insert(client_id.clone());
+        }
+        AllowWatchers(allowed) => {
+            let mut watchers = clients.watchers.remove(client_id).unwrap_or_default();
+            for watcher in watchers.iter().filter(|watcher| allowed.contains(*watcher)) {
+                if let Some(watcher_tx) = clients.senders.get(watcher) {
+                    send_command(watcher_tx, &Online(client_id.clone()));
+                }
+            }
+            watchers.retain(|watcher| !allowed.contains(watcher));
+            if !watchers.is_empty() {
+                clients.watchers.insert(client_id.clone(), watchers);
+            }
+            clients.allowed_watchers.insert(client_id.clone(), allowed);
+        }
+        FriendRequest(remote_id, public_key) => {
+            if let Some(remote_tx) = clients.senders.get(&remote_id) {
+                send_command(remote_tx, &FriendRequest(client_id.clone(), public_key));
+                return;
+            }
+            if let Some(tx) = clients.senders.get(client_id) {
+                send_command(tx, &FriendRequestFailure(remote_id.clone()));
+            }
+            clients
+                .friend_requests
+                .entry(remote_id)
+                .or_default()
+                .insert(client_id.clone(), public_key);
+        }
+        //The requester sends the request again once they are both online, so a lost answer is
+        //only late.
+        FriendAnswer(remote_id, accepted, public_key) => {
+            if let Some(remote_tx) = clients.senders.get(&remote_id) {
+                send_command(
+                    remote_tx,
+                    &FriendAnswer(client_id.clone(), accepted, public_key),
+                );
+            }
+        }
+        Challenge(_)
+        | Authenticate(_, _)
+        | SetClientId(_)
+        | CallRequestFailure(_, _)
+        | Online(_)
+        | FriendRequestFailure(_) => {
+            println!("Ignoring server-only command from {}", client_id);
+        }
+    }
+}
+
+//Whether call_id is a call from caller to callee, it is kept around for longer if it is.
+fn signal(clients: &mut Clients, call_id: &CallId, caller: &UserId, callee: &UserId) -> bool {
+    match clients.calls.get_mut(call_id) {
+        Some(call) if call.is_between(caller, callee) => {
+            call.last_signal = Instant::now();
+            true
+        }
+        _ => false,
+    }
+}
+//Sends a call command to remote_id, telling the sender if remote_id has gone offline meanwhile.
+fn forward(clients: &mut Clients, client_id: &UserId, remote_id: UserId, ws_command: WSCommand) {
+    if let Some(remote_tx) = clients.senders.get(&remote_id) {
+        send_command(remote_tx, &ws_command);
+        return;
+    }
+    let call_id = match ws_command {
+        WSCommand::CallAnswer(_, call_id, _, _)
+        | WSCommand::CallReply(_, call_id, _)
+        | WSCommand::IceCandidate(_, call_id, _) => call_id,
+        _ => return,
+    };
+    clients.calls.remove(&call_id);
+    if let Some(tx) = clients.senders.get(client_id) {
+        send_command(tx, &WSCommand::CallRequestFailure(remote_id, call_id));
+    }
+}
+
+fn send_command(tx: &UnboundedSender<Message>, ws_command: &WSCommand) {
+    let msg_str = serde_json::to_string(ws_command).unwrap();
+    let _ = tx.send(Message::Text(msg_str));
+}
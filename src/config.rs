@@ -0,0 +1,160 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::utils::storage;
+
+const DEFAULT_SIGNALING_URL: &str = "ws://localhost:3030/couple";
+const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+impl IceServer {
+    fn is_turn(&self) -> bool {
+        self.urls
+            .iter()
+            .any(|url| url.starts_with("turn:") || url.starts_with("turns:"))
+    }
+}
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IceTransportPolicy {
+    #[default]
+    All,
+    Relay,
+}
+impl TryFrom<&str> for IceTransportPolicy {
+    type Error = anyhow::Error;
+    fn try_from(policy: &str) -> Result<Self> {
+        match policy {
+            "all" => Ok(Self::All),
+            "relay" => Ok(Self::Relay),
+            _ => Err(anyhow!("Unknown ICE transport policy {}.", policy)),
+        }
+    }
+}
+
+//Read from config.toml in the data dir, then CHAOS_* env vars, then command line flags.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub signaling_url: String,
+    pub ice_servers: Vec<IceServer>,
+    pub ice_transport_policy: IceTransportPolicy,
+    //WAV files calls play from and record into instead of the audio device, e.g. for testing.
+    pub audio_input: Option<PathBuf>,
+    pub audio_output: Option<PathBuf>,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            signaling_url: DEFAULT_SIGNALING_URL.to_string(),
+            ice_servers: vec![IceServer {
+                urls: vec![DEFAULT_STUN_SERVER.to_string()],
+                ..Default::default()
+            }],
+            ice_transport_policy: IceTransportPolicy::All,
+            audio_input: None,
+            audio_output: None,
+        }
+    }
+}
+impl Config {
+    pub fn load() -> Result<Self> {
+        let args = env::args().skip(1).collect::<Vec<String>>();
+        let path = flag(&args, "--config")
+            .or_else(|| env::var("CHAOS_CONFIG").ok())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| storage::data_dir().join("config.toml"));
+        let mut config = if path.exists() {
+            toml::from_str(&fs::read_to_string(&path)?)
+                .map_err(|e| anyhow!("Invalid config {}: {}", path.display(), e))?
+        } else {
+            Config::default()
+        };
+        let from_env = |var: &str| env::var(var).ok();
+        let from_flags = |var: &str| {
+            let name = format!("--{}", var.trim_start_matches("CHAOS_").to_lowercase());
+            flag(&args, &name.replace('_', "-"))
+        };
+        config.apply_overrides(from_env)?;
+        config.apply_overrides(from_flags)?;
+        //Once the server list is final, whichever way the servers and the credentials were given.
+        let lookup = |var: &str| from_flags(var).or_else(|| from_env(var));
+        config.set_turn_credentials(
+            lookup("CHAOS_TURN_USERNAME"),
+            lookup("CHAOS_TURN_CREDENTIAL"),
+        );
+        config.validate()?;
+        Ok(config)
+    }
+    fn apply_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(signaling_url) = lookup("CHAOS_SIGNALING_URL") {
+            self.signaling_url = signaling_url;
+        }
+        if let Some(ice_servers) = lookup("CHAOS_ICE_SERVERS") {
+            self.ice_servers = ice_servers
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(|url| IceServer {
+                    urls: vec![url.to_string()],
+                    ..Default::default()
+                })
+                .collect();
+        }
+        if let Some(policy) = lookup("CHAOS_ICE_TRANSPORT_POLICY") {
+            self.ice_transport_policy = IceTransportPolicy::try_from(policy.as_str())?;
+        }
+        if let Some(audio_input) = lookup("CHAOS_AUDIO_INPUT") {
+            self.audio_input = Some(PathBuf::from(audio_input));
+        }
+        if let Some(audio_output) = lookup("CHAOS_AUDIO_OUTPUT") {
+            self.audio_output = Some(PathBuf::from(audio_output));
+        }
+        Ok(())
+    }
+    //TURN credentials apply to every TURN server, so they can stay out of the config file. The
+    //file's own ones are kept for what isn't given.
+    fn set_turn_credentials(&mut self, username: Option<String>, credential: Option<String>) {
+        for ice_server in self.ice_servers.iter_mut().filter(|s| s.is_turn()) {
+            if let Some(username) = &username {
+                ice_server.username = username.clone();
+            }
+            if let Some(credential) = &credential {
+                ice_server.credential = credential.clone();
+            }
+        }
+    }
+    fn validate(&self) -> Result<()> {
+        Url::parse(&self.signaling_url)
+            .map_err(|e| anyhow!("Invalid signaling url {}: {}", self.signaling_url, e))?;
+        if self.ice_transport_policy == IceTransportPolicy::Relay
+            && !self.ice_servers.iter().any(|s| s.is_turn())
+        {
+            bail!("The relay ICE transport policy needs at least one TURN server.");
+        }
+        Ok(())
+    }
+}
+
+//Accepts both `--name value` and `--name=value`.
+pub fn flag(args: &[String], name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == name {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix(&prefix).map(|value| value.to_string())
+        }
+    })
+}
@@ -1,161 +1,209 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::tungstenite::Message::Text;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use url::Url;
 
-use crate::scheduler::{Command, StateCommand, WSCommand};
-use crate::state::ConnectionProgress;
-use crate::state::ConnectionProgress::*;
+use crate::config::Config;
+use crate::error::ChaosError;
+use crate::scheduler::{report, send, Command, StateCommand, WSCommand, CHANNEL_CAPACITY};
+use crate::state::SignalingStatus;
+use crate::utils::crypto::Identity;
 use crate::{
     scheduler::{ChannelAttachment, ThreadTypes},
     utils::Attach,
 };
 
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 pub type SplitSocketRead = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
 pub type SplitSocketWrite = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
 pub struct Coupler {
-    attachment: Arc<Mutex<ChannelAttachment>>,
-    socket: (Arc<Mutex<SplitSocketWrite>>, Arc<Mutex<SplitSocketRead>>),
+    attachment: ChannelAttachment,
+    identity: Arc<Identity>,
+    signaling_url: Url,
 }
 
 impl Coupler {
-    pub async fn new(attachment: Arc<Mutex<ChannelAttachment>>) -> Self {
-        let (ws_socket, _response) =
-            connect_async(Url::parse("ws://localhost:3030/couple").unwrap())
-                .await
-                .unwrap();
-        let (ws_write, ws_read) = ws_socket.split();
-        let socket = (
-            Arc::new(Mutex::new(ws_write)),
-            Arc::new(Mutex::new(ws_read)),
-        );
-        Self { attachment, socket }
+    pub fn new(attachment: ChannelAttachment, identity: Arc<Identity>, config: &Config) -> Self {
+        Self {
+            attachment,
+            identity,
+            signaling_url: Url::parse(&config.signaling_url)
+                .expect("The signaling url is checked when the config is loaded."),
+        }
     }
-    pub async fn start(&mut self) {
-        let socket = self.socket.clone();
-        let (socket_write, socket_read) = socket.clone();
-        let attachment = self.attachment.clone();
-        let attachment2 = Arc::clone(&attachment);
+    pub async fn start(self) {
+        //Outbound commands wait here while we are offline.
+        let (outbox_tx, outbox_rx) = mpsc::channel::<WSCommand>(CHANNEL_CAPACITY);
+        let (tx, rx) = self.attachment;
+        let signaling_url = self.signaling_url;
+        let identity = self.identity;
+        let outbox_report_tx = tx.clone();
         tokio::spawn(async move {
-            websocket_thread(attachment, socket_read).await;
+            websocket_thread(tx, signaling_url, identity, outbox_rx).await;
             println!("Websocket Listener thread closed.");
         });
 
         tokio::spawn(async move {
-            coupler_scheduler_thread(attachment2, socket_write).await;
+            coupler_scheduler_thread(rx, outbox_tx, outbox_report_tx).await;
             println!("Coupler-Scheduler Thread closed.");
         });
     }
 }
-impl Attach<Arc<Mutex<ChannelAttachment>>> for Coupler {
-    fn attach(
-        &mut self,
-        channel_attachment: Arc<Mutex<ChannelAttachment>>,
-        _: Option<ThreadTypes>,
-    ) {
+impl Attach for Coupler {
+    fn attach(&mut self, channel_attachment: ChannelAttachment, _: Option<ThreadTypes>) {
         self.attachment = channel_attachment;
     }
 }
 
+//Keeps a signaling connection up for as long as the app runs, reconnecting with exponential
+//backoff and authenticating again every time.
 async fn websocket_thread(
-    attachment: Arc<Mutex<ChannelAttachment>>,
-    socket_read: Arc<Mutex<SplitSocketRead>>,
+    tx: Sender<Command>,
+    signaling_url: Url,
+    identity: Arc<Identity>,
+    mut outbox: Receiver<WSCommand>,
 ) {
-    let attachment = attachment.clone();
-    while let Ok(ws_message) = socket_read.try_lock().unwrap().next().await.unwrap() {
-        let ws_command = serde_json::from_str::<WSCommand>(ws_message.to_text().unwrap())
-            .expect("Invalid websocket message");
-        println!("Websocket Message: {}", ws_message);
-        let attachment = attachment.clone();
-        use WSCommand::*;
-        match ws_command {
-            SetClientId(client_id) => {
-                let (tx, _) = attachment.try_lock().unwrap().clone();
-                tx.try_send(Command::State(StateCommand::SetClientId(client_id)))
-                    .unwrap();
-            }
-            CallRequest(remote_id) => {
-                //ask user permission
-
-                let (tx, _) = attachment.try_lock().unwrap().clone();
-                tx.try_send(Command::State(StateCommand::SetProgress(
-                    remote_id.clone(),
-                    ConnectionProgress::CallRequestReceived,
-                )))
-                .unwrap();
-            }
-            CallAnswer(accepted, Some(remote_sdp)) => {
-                let (tx, _) = attachment.try_lock().unwrap().clone();
-                tx.try_send(Command::WS(WSCommand::CallAnswer(
-                    accepted,
-                    Some(remote_sdp),
-                )))
-                .unwrap();
-            }
-            CallReply(remote_sdp) => {
-                let (tx, _) = attachment.try_lock().unwrap().clone();
-                tx.try_send(Command::WS(WSCommand::CallReply(remote_sdp)))
-                    .unwrap();
-            }
-            _ => {
-                println!("Not implemented yet.");
+    let mut unsent: Option<WSCommand> = None;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        set_signaling_status(&tx, SignalingStatus::Connecting).await;
+        match connect_async(signaling_url.clone()).await {
+            Ok((ws_socket, _response)) => {
+                let (mut ws_write, mut ws_read) = ws_socket.split();
+                let mut online = false;
+                loop {
+                    tokio::select! {
+                        ws_message = ws_read.next() => {
+                            let Some(Ok(ws_message)) = ws_message else {
+                                break;
+                            };
+                            if ws_message.is_close() {
+                                break;
+                            }
+                            println!("Websocket Message: {}", ws_message);
+                            let ws_command = match serde_json::from_str::<WSCommand>(
+                                ws_message.to_text().unwrap_or_default(),
+                            ) {
+                                Ok(ws_command) => ws_command,
+                                Err(e) => {
+                                    report(&tx, ChaosError::InvalidMessage(e.to_string())).await;
+                                    continue;
+                                }
+                            };
+                            use WSCommand::*;
+                            match ws_command {
+                                Challenge(challenge) => {
+                                    let msg = WSCommand::Authenticate(
+                                        identity.public_key(),
+                                        identity.sign_challenge(&challenge),
+                                    );
+                                    if websocket_send(&mut ws_write, &msg).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                SetClientId(client_id) => {
+                                    //Like a failed authentication, we hang up and try again
+                                    //after the backoff.
+                                    if client_id != identity.user_id() {
+                                        let e = format!("Registered us as {}.", client_id);
+                                        report(&tx, ChaosError::Signaling(e)).await;
+                                        let _ = ws_write.close().await;
+                                        break;
+                                    }
+                                    online = true;
+                                    backoff = INITIAL_BACKOFF;
+                                    set_signaling_status(&tx, SignalingStatus::Online).await;
+                                    let set_client_id = StateCommand::SetClientId(client_id);
+                                    send(&tx, Command::State(set_client_id)).await;
+                                    if let Some(ws_command) = unsent.take() {
+                                        if websocket_send(&mut ws_write, &ws_command).await.is_err() {
+                                            unsent = Some(ws_command);
+                                            break;
+                                        }
+                                    }
+                                }
+                                ws_command => handle_ws_command(&tx, ws_command).await,
+                            }
+                        }
+                        Some(ws_command) = outbox.recv(), if online => {
+                            if websocket_send(&mut ws_write, &ws_command).await.is_err() {
+                                unsent = Some(ws_command);
+                                break;
+                            }
+                        }
+                    }
+                }
+                println!("Lost connection to the signaling server.");
             }
-        };
+            Err(e) => println!("Could not reach the signaling server: {}", e),
+        }
+        set_signaling_status(&tx, SignalingStatus::Offline).await;
+        let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 4);
+        tokio::time::sleep(backoff + Duration::from_millis(jitter)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
     }
 }
+async fn handle_ws_command(tx: &Sender<Command>, ws_command: WSCommand) {
+    use WSCommand::*;
+    match ws_command {
+        CallRequest(..)
+        | CallRequestFailure(..)
+        | CallAnswer(..)
+        | CallReply(..)
+        | IceCandidate(..)
+        | Online(..)
+        | FriendRequest(..)
+        | FriendAnswer(..)
+        | FriendRequestFailure(..) => {
+            send(tx, Command::WS(ws_command)).await;
+        }
+        _ => {
+            println!("Not implemented yet.");
+        }
+    };
+}
+async fn set_signaling_status(tx: &Sender<Command>, status: SignalingStatus) {
+    send(tx, Command::State(StateCommand::SetSignalingStatus(status))).await;
+}
+//Never waits on the outbox, the scheduler can be waiting on us while the websocket thread waits
+//on it. A full outbox means we have been offline for a while and what is in it is stale anyway.
 async fn coupler_scheduler_thread(
-    attachment: Arc<Mutex<ChannelAttachment>>,
-    socket_write: Arc<Mutex<SplitSocketWrite>>,
+    mut rx: Receiver<Command>,
+    outbox: Sender<WSCommand>,
+    tx: Sender<Command>,
 ) {
-    let (_, rx) = attachment.try_lock().unwrap().clone();
-    while let Ok(command) = rx.recv() {
+    while let Some(command) = rx.recv().await {
         if let Command::WS(ws_command) = command {
-            use WSCommand::*;
-            match ws_command {
-                CallRequest(remote_id) => {
-                    let msg = WSCommand::CallRequest(remote_id.clone());
-                    let msg_str = serde_json::to_string(&msg).unwrap();
-                    let (tx, _) = attachment.try_lock().unwrap().clone();
-                    tx.try_send(Command::State(StateCommand::SetProgress(
-                        remote_id.clone(),
-                        CallRequestSent,
-                    )))
-                    .unwrap();
-                    websocket_send(socket_write.clone(), msg_str).await;
-                }
-                CallAnswer(accepted, sdp) => {
-                    let msg = WSCommand::CallAnswer(accepted, sdp);
-                    let msg_str = serde_json::to_string(&msg).unwrap();
-                    let (tx, _) = attachment.try_lock().unwrap().clone();
-                    // tx.try_send(Command::State(StateCommand::SetProgress(
-                    //     remote_id.clone(),
-                    //     CallAnswerSent,
-                    // )))
-                    // .unwrap();
-                    websocket_send(socket_write.clone(), msg_str).await;
+            match outbox.try_send(ws_command) {
+                Ok(()) => {}
+                Err(TrySendError::Full(ws_command)) => {
+                    let e = format!("Offline, dropped {:?}.", ws_command);
+                    report(&tx, ChaosError::Signaling(e)).await;
                 }
-                CallReply(local_sdp) => {
-                    let msg = WSCommand::CallReply(local_sdp);
-                    let msg_str = serde_json::to_string(&msg).unwrap();
-                    websocket_send(socket_write.clone(), msg_str).await;
-                }
-
-                _ => {}
+                Err(TrySendError::Closed(_)) => break,
             }
         }
     }
 }
-async fn websocket_send(socket_write: Arc<Mutex<SplitSocketWrite>>, msg_str: String) {
+async fn websocket_send(
+    socket_write: &mut SplitSocketWrite,
+    ws_command: &WSCommand,
+) -> Result<(), ChaosError> {
+    let msg_str =
+        serde_json::to_string(ws_command).map_err(|e| ChaosError::InvalidMessage(e.to_string()))?;
     socket_write
-        .try_lock()
-        .unwrap()
         .send(Text(msg_str))
         .await
-        .unwrap();
+        .map_err(|e| ChaosError::Signaling(e.to_string()))
 }
@@ -0,0 +1,712 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection as SqliteConnection, Row};
+
+use crate::state::{
+    ChannelId, ChaosMessage, Connection, Contact, FriendStatus, Group, GroupId, GroupInfo,
+    MessageId, MessageStatus, RevisionSignature, Server, ServerId, ServerInfo, UserId,
+};
+
+//How many messages are loaded per conversation at startup and per page after that.
+pub const HISTORY_PAGE_SIZE: usize = 50;
+const MESSAGE_COLUMNS: &str = "message_id, client_id, message_content, timestamp, status";
+
+pub struct Database {
+    connection: SqliteConnection,
+}
+impl Database {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = SqliteConnection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS connections (
+                remote_id TEXT PRIMARY KEY,
+                public_key TEXT NOT NULL DEFAULT ''
+            );
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                remote_id TEXT NOT NULL REFERENCES connections(remote_id),
+                message_id TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                UNIQUE(remote_id, message_id)
+            );
+            CREATE INDEX IF NOT EXISTS messages_by_remote ON messages(remote_id, id);
+            CREATE TABLE IF NOT EXISTS groups (
+                group_id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                members TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                changed_by TEXT NOT NULL,
+                public_key TEXT NOT NULL DEFAULT '',
+                signature TEXT NOT NULL DEFAULT ''
+            );
+            CREATE TABLE IF NOT EXISTS group_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id TEXT NOT NULL REFERENCES groups(group_id),
+                message_id TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                UNIQUE(group_id, message_id)
+            );
+            CREATE INDEX IF NOT EXISTS group_messages_by_group ON group_messages(group_id, id);
+            CREATE TABLE IF NOT EXISTS servers (
+                server_id TEXT PRIMARY KEY,
+                info TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS channel_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                server_id TEXT NOT NULL REFERENCES servers(server_id),
+                channel_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                UNIQUE(channel_id, message_id)
+            );
+            CREATE INDEX IF NOT EXISTS channel_messages_by_channel
+            ON channel_messages(channel_id, id);
+            CREATE TABLE IF NOT EXISTS contacts (
+                user_id TEXT PRIMARY KEY,
+                display_name TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                notes TEXT NOT NULL,
+                friend_status INTEGER NOT NULL,
+                blocked INTEGER NOT NULL
+            );",
+        )?;
+        //Files from older versions catch up with the tables above.
+        add_message_receipts(&connection)?;
+        add_group_signatures(&connection)?;
+        scope_message_ids(&connection)?;
+        Ok(Self { connection })
+    }
+    //Every known contact with the latest page of its conversation.
+    pub fn load(&self) -> Result<HashMap<UserId, Connection>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT remote_id, public_key FROM connections")?;
+        let contacts = statement
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(UserId, String)>>>()?;
+        let mut connections = HashMap::new();
+        for (remote_id, public_key) in contacts {
+            let mut connection = Connection::new(remote_id.clone());
+            connection.set_remote_key(public_key);
+            let (messages, history_cursor) = self.messages_before(&remote_id, None)?;
+            connection.prepend_messages(messages, history_cursor);
+            connections.insert(remote_id, connection);
+        }
+        Ok(connections)
+    }
+    //Every group we know of with the latest page of its history.
+    pub fn load_groups(&self) -> Result<HashMap<GroupId, Group>> {
+        let mut statement = self.connection.prepare(
+            "SELECT group_id, name, members, version, changed_by, public_key, signature
+                FROM groups",
+        )?;
+        let infos = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, String>(4)?,
+                    RevisionSignature {
+                        public_key: row.get(5)?,
+                        signature: row.get(6)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut groups = HashMap::new();
+        for (group_id, name, members, version, changed_by, signed) in infos {
+            let info = GroupInfo {
+                id: GroupId::parse_str(&group_id)?,
+                name,
+                members: serde_json::from_str(&members)?,
+                version: version as u64,
+                changed_by,
+                signed,
+            };
+            let mut group = Group::new(info);
+            let (messages, history_cursor) = self.group_messages_before(&group.info.id, None)?;
+            group.history.prepend_messages(messages, history_cursor);
+            groups.insert(group.info.id, group);
+        }
+        Ok(groups)
+    }
+    pub fn save_group(&self, info: &GroupInfo) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO groups
+            (group_id, name, members, version, changed_by, public_key, signature)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT(group_id) DO UPDATE SET name = excluded.name,
+            members = excluded.members, version = excluded.version,
+            changed_by = excluded.changed_by, public_key = excluded.public_key,
+            signature = excluded.signature",
+            params![
+                info.id.to_string(),
+                info.name,
+                serde_json::to_string(&info.members)?,
+                info.version as i64,
+                info.changed_by,
+                info.signed.public_key,
+                info.signed.signature
+            ],
+        )?;
+        Ok(())
+    }
+    //Every server we know of with the latest page of each channel.
+    pub fn load_servers(&self) -> Result<HashMap<ServerId, Server>> {
+        let mut statement = self.connection.prepare("SELECT info FROM servers")?;
+        let infos = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut servers = HashMap::new();
+        for info in infos {
+            let mut server = Server::new(serde_json::from_str::<ServerInfo>(&info)?);
+            for channel_id in server
+                .info
+                .channels
+                .iter()
+                .map(|channel| channel.id)
+                .collect::<Vec<_>>()
+            {
+                let (messages, history_cursor) = self.channel_messages_before(&channel_id, None)?;
+                server
+                    .history_mut(channel_id)
+                    .prepend_messages(messages, history_cursor);
+            }
+            servers.insert(server.info.id, server);
+        }
+        Ok(servers)
+    }
+    //Servers nest too deep for columns of their own, so they are kept whole.
+    pub fn save_server(&self, info: &ServerInfo) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO servers (server_id, info) VALUES (?1, ?2)
+            ON CONFLICT(server_id) DO UPDATE SET info = excluded.info",
+            params![info.id.to_string(), serde_json::to_string(info)?],
+        )?;
+        Ok(())
+    }
+    pub fn load_contacts(&self) -> Result<HashMap<UserId, Contact>> {
+        let mut statement = self.connection.prepare(
+            "SELECT user_id, display_name, public_key, notes, friend_status, blocked
+            FROM contacts",
+        )?;
+        let contacts = statement
+            .query_map([], |row| {
+                Ok(Contact {
+                    id: row.get(0)?,
+                    display_name: row.get(1)?,
+                    public_key: row.get(2)?,
+                    notes: row.get(3)?,
+                    friend_status: row.get(4)?,
+                    blocked: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(contacts
+            .into_iter()
+            .map(|contact| (contact.id.clone(), contact))
+            .collect())
+    }
+    pub fn save_contact(&self, contact: &Contact) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO contacts
+            (user_id, display_name, public_key, notes, friend_status, blocked)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT(user_id) DO UPDATE SET display_name = excluded.display_name,
+            public_key = excluded.public_key, notes = excluded.notes,
+            friend_status = excluded.friend_status, blocked = excluded.blocked",
+            params![
+                contact.id,
+                contact.display_name,
+                contact.public_key,
+                contact.notes,
+                contact.friend_status,
+                contact.blocked
+            ],
+        )?;
+        Ok(())
+    }
+    pub fn remove_contact(&self, user_id: &UserId) -> Result<()> {
+        self.connection
+            .execute("DELETE FROM contacts WHERE user_id = ?1", params![user_id])?;
+        Ok(())
+    }
+    pub fn save_connection(&self, remote_id: &UserId, public_key: &str) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO connections (remote_id, public_key) VALUES (?1, ?2)
+            ON CONFLICT(remote_id) DO UPDATE SET public_key = excluded.public_key",
+            params![remote_id, public_key],
+        )?;
+        Ok(())
+    }
+    //Returns false for a message we already have, remotes resend what we haven't acked.
+    pub fn add_message(&self, remote_id: &UserId, message: &ChaosMessage) -> Result<bool> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO connections (remote_id) VALUES (?1)",
+            params![remote_id],
+        )?;
+        let added = self.connection.execute(
+            "INSERT INTO messages
+            (remote_id, message_id, client_id, message_content, timestamp, status)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT(remote_id, message_id) DO NOTHING",
+            params![
+                remote_id,
+                message.id.to_string(),
+                message.client_id,
+                message.message_content,
+                message.timestamp.timestamp_millis(),
+                message.status
+            ],
+        )?;
+        Ok(added > 0)
+    }
+    //Like Connection::set_message_status, a status never moves back.
+    pub fn set_message_status(
+        &self,
+        remote_id: &UserId,
+        message_id: &MessageId,
+        status: MessageStatus,
+    ) -> Result<()> {
+        self.connection.execute(
+            "UPDATE messages SET status = ?3
+            WHERE remote_id = ?1 AND message_id = ?2 AND status < ?3",
+            params![remote_id, message_id.to_string(), status],
+        )?;
+        Ok(())
+    }
+    //Like add_message, false for a message we already have.
+    pub fn add_group_message(&self, group_id: &GroupId, message: &ChaosMessage) -> Result<bool> {
+        let added = self.connection.execute(
+            "INSERT INTO group_messages
+            (group_id, message_id, client_id, message_content, timestamp, status)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT(group_id, message_id) DO NOTHING",
+            params![
+                group_id.to_string(),
+                message.id.to_string(),
+                message.client_id,
+                message.message_content,
+                message.timestamp.timestamp_millis(),
+                message.status
+            ],
+        )?;
+        Ok(added > 0)
+    }
+    //Like add_message, false for a message we already have.
+    pub fn add_channel_message(
+        &self,
+        server_id: &ServerId,
+        channel_id: &ChannelId,
+        message: &ChaosMessage,
+    ) -> Result<bool> {
+        let added = self.connection.execute(
+            "INSERT INTO channel_messages
+            (server_id, channel_id, message_id, client_id, message_content, timestamp, status)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT(channel_id, message_id) DO NOTHING",
+            params![
+                server_id.to_string(),
+                channel_id.to_string(),
+                message.id.to_string(),
+                message.client_id,
+                message.message_content,
+                message.timestamp.timestamp_millis(),
+                message.status
+            ],
+        )?;
+        Ok(added > 0)
+    }
+    pub fn set_channel_message_status(
+        &self,
+        channel_id: &ChannelId,
+        message_id: &MessageId,
+        status: MessageStatus,
+    ) -> Result<()> {
+        self.connection.execute(
+            "UPDATE channel_messages SET status = ?3
+            WHERE channel_id = ?1 AND message_id = ?2 AND status < ?3",
+            params![channel_id.to_string(), message_id.to_string(), status],
+        )?;
+        Ok(())
+    }
+    pub fn set_group_message_status(
+        &self,
+        group_id: &GroupId,
+        message_id: &MessageId,
+        status: MessageStatus,
+    ) -> Result<()> {
+        self.connection.execute(
+            "UPDATE group_messages SET status = ?3
+            WHERE group_id = ?1 AND message_id = ?2 AND status < ?3",
+            params![group_id.to_string(), message_id.to_string(), status],
+        )?;
+        Ok(())
+    }
+    //Our messages to remote_id that it hasn't confirmed yet, oldest first.
+    pub fn queued(&self, remote_id: &UserId) -> Result<Vec<ChaosMessage>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {} FROM messages
+            WHERE remote_id = ?1 AND client_id != remote_id AND status < ?2 ORDER BY id",
+            MESSAGE_COLUMNS
+        ))?;
+        let messages = statement
+            .query_map(params![remote_id, MessageStatus::Delivered], |row| {
+                message_from_row(row, 0)
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(messages)
+    }
+    //Everyone we have queued messages for.
+    pub fn queued_remotes(&self) -> Result<Vec<UserId>> {
+        let mut statement = self.connection.prepare(
+            "SELECT DISTINCT remote_id FROM messages WHERE client_id != remote_id AND status < ?1",
+        )?;
+        let remote_ids = statement
+            .query_map(params![MessageStatus::Delivered], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(remote_ids)
+    }
+    //A page of messages older than the cursor, oldest first, and the cursor for the page before
+    //it if there is one.
+    pub fn messages_before(
+        &self,
+        remote_id: &UserId,
+        cursor: Option<i64>,
+    ) -> Result<(Vec<ChaosMessage>, Option<i64>)> {
+        self.page_before("messages", "remote_id", remote_id, cursor)
+    }
+    pub fn group_messages_before(
+        &self,
+        group_id: &GroupId,
+        cursor: Option<i64>,
+    ) -> Result<(Vec<ChaosMessage>, Option<i64>)> {
+        self.page_before("group_messages", "group_id", &group_id.to_string(), cursor)
+    }
+    pub fn channel_messages_before(
+        &self,
+        channel_id: &ChannelId,
+        cursor: Option<i64>,
+    ) -> Result<(Vec<ChaosMessage>, Option<i64>)> {
+        self.page_before(
+            "channel_messages",
+            "channel_id",
+            &channel_id.to_string(),
+            cursor,
+        )
+    }
+    //Pages through the messages in table whose owner column is owner.
+    fn page_before(
+        &self,
+        table: &str,
+        owner_column: &str,
+        owner: &str,
+        cursor: Option<i64>,
+    ) -> Result<(Vec<ChaosMessage>, Option<i64>)> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT id, {} FROM {}
+            WHERE {} = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+            MESSAGE_COLUMNS, table, owner_column
+        ))?;
+        let mut rows = statement
+            .query_map(
+                params![
+                    owner,
+                    cursor.unwrap_or(i64::MAX),
+                    HISTORY_PAGE_SIZE as i64 + 1
+                ],
+                |row| Ok((row.get::<_, i64>(0)?, message_from_row(row, 1)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let has_older = rows.len() > HISTORY_PAGE_SIZE;
+        rows.truncate(HISTORY_PAGE_SIZE);
+        let history_cursor = if has_older {
+            rows.last().map(|(id, _)| *id)
+        } else {
+            None
+        };
+        let messages = rows.into_iter().rev().map(|(_, message)| message).collect();
+        Ok((messages, history_cursor))
+    }
+}
+
+//Reads MESSAGE_COLUMNS starting at column first.
+fn message_from_row(row: &Row, first: usize) -> rusqlite::Result<ChaosMessage> {
+    let message_id = row.get::<_, String>(first)?;
+    Ok(ChaosMessage {
+        id: MessageId::parse_str(&message_id).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                first,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })?,
+        client_id: row.get(first + 1)?,
+        message_content: row.get(first + 2)?,
+        timestamp: Utc
+            .timestamp_millis_opt(row.get(first + 3)?)
+            .single()
+            .unwrap_or_default(),
+        status: row.get(first + 4)?,
+    })
+}
+//Histories from before receipts get an id for every message and count as read, we can't know any
+//better for them.
+fn add_message_receipts(connection: &SqliteConnection) -> Result<()> {
+    let has_receipts = connection
+        .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'message_id'")?
+        .exists([])?;
+    if !has_receipts {
+        connection.execute_batch(
+            "ALTER TABLE messages ADD COLUMN message_id TEXT NOT NULL DEFAULT '';
+            ALTER TABLE messages ADD COLUMN timestamp INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE messages ADD COLUMN status INTEGER NOT NULL DEFAULT 3;
+            UPDATE messages SET message_id = lower(hex(randomblob(16)));
+            CREATE UNIQUE INDEX messages_by_message_id ON messages(message_id);",
+        )?;
+    }
+    Ok(())
+}
+//Groups from before revisions were signed keep an empty signature, others won't take them.
+fn add_group_signatures(connection: &SqliteConnection) -> Result<()> {
+    let has_signatures = connection
+        .prepare("SELECT 1 FROM pragma_table_info('groups') WHERE name = 'signature'")?
+        .exists([])?;
+    if !has_signatures {
+        connection.execute_batch(
+            "ALTER TABLE groups ADD COLUMN public_key TEXT NOT NULL DEFAULT '';
+            ALTER TABLE groups ADD COLUMN signature TEXT NOT NULL DEFAULT '';",
+        )?;
+    }
+    Ok(())
+}
+//Message ids used to be unique across all conversations, so one could keep the same id from
+//being stored in another. The tables are copied into ones that only keep them unique within a
+//conversation.
+fn scope_message_ids(connection: &SqliteConnection) -> Result<()> {
+    if has_global_message_ids(connection, "messages")? {
+        connection.execute_batch(
+            "BEGIN;
+            CREATE TABLE scoped_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                remote_id TEXT NOT NULL REFERENCES connections(remote_id),
+                message_id TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                UNIQUE(remote_id, message_id)
+            );
+            INSERT INTO scoped_messages
+            (id, remote_id, message_id, client_id, message_content, timestamp, status)
+            SELECT id, remote_id, message_id, client_id, message_content, timestamp, status
+            FROM messages;
+            DROP TABLE messages;
+            ALTER TABLE scoped_messages RENAME TO messages;
+            CREATE INDEX messages_by_remote ON messages(remote_id, id);
+            COMMIT;",
+        )?;
+    }
+    if has_global_message_ids(connection, "group_messages")? {
+        connection.execute_batch(
+            "BEGIN;
+            CREATE TABLE scoped_group_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id TEXT NOT NULL REFERENCES groups(group_id),
+                message_id TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                UNIQUE(group_id, message_id)
+            );
+            INSERT INTO scoped_group_messages SELECT * FROM group_messages;
+            DROP TABLE group_messages;
+            ALTER TABLE scoped_group_messages RENAME TO group_messages;
+            CREATE INDEX group_messages_by_group ON group_messages(group_id, id);
+            COMMIT;",
+        )?;
+    }
+    if has_global_message_ids(connection, "channel_messages")? {
+        connection.execute_batch(
+            "BEGIN;
+            CREATE TABLE scoped_channel_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                server_id TEXT NOT NULL REFERENCES servers(server_id),
+                channel_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status INTEGER NOT NULL,
+                UNIQUE(channel_id, message_id)
+            );
+            INSERT INTO scoped_channel_messages SELECT * FROM channel_messages;
+            DROP TABLE channel_messages;
+            ALTER TABLE scoped_channel_messages RENAME TO channel_messages;
+            CREATE INDEX channel_messages_by_channel ON channel_messages(channel_id, id);
+            COMMIT;",
+        )?;
+    }
+    Ok(())
+}
+//Whether table has a unique index on message_id alone.
+fn has_global_message_ids(connection: &SqliteConnection, table: &str) -> Result<bool> {
+    let exists = connection
+        .prepare(
+            "SELECT 1 FROM pragma_index_list(?1) AS list
+            WHERE list.\"unique\"
+            AND (SELECT group_concat(name) FROM pragma_index_info(list.name)) = 'message_id'",
+        )?
+        .exists(params![table])?;
+    Ok(exists)
+}
+//Stored as its position, so SQL can tell which status comes later.
+impl ToSql for MessageStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+impl FromSql for MessageStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        use MessageStatus::*;
+        match value.as_i64()? {
+            0 => Ok(Pending),
+            1 => Ok(Sent),
+            2 => Ok(Delivered),
+            3 => Ok(Read),
+            status => Err(FromSqlError::OutOfRange(status)),
+        }
+    }
+}
+impl ToSql for FriendStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+impl FromSql for FriendStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        use FriendStatus::*;
+        match value.as_i64()? {
+            0 => Ok(Stranger),
+            1 => Ok(RequestSent),
+            2 => Ok(RequestReceived),
+            3 => Ok(Friends),
+            status => Err(FromSqlError::OutOfRange(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn message(content: &str) -> ChaosMessage {
+        ChaosMessage::new("sender".to_string(), content.to_string())
+    }
+
+    #[test]
+    fn message_ids_are_unique_per_conversation() {
+        let database = Database::open(Path::new(":memory:")).unwrap();
+        let message = message("hello");
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        assert!(database.add_message(&alice, &message).unwrap());
+        assert!(!database.add_message(&alice, &message).unwrap());
+        //Someone else using the same id doesn't hide it.
+        assert!(database.add_message(&bob, &message).unwrap());
+        database
+            .set_message_status(&alice, &message.id, MessageStatus::Read)
+            .unwrap();
+        let (bobs, _) = database.messages_before(&bob, None).unwrap();
+        assert_eq!(bobs[0].status, MessageStatus::Pending);
+
+        let groups = [
+            GroupInfo::new("a".to_string(), alice, BTreeSet::new()),
+            GroupInfo::new("b".to_string(), bob, BTreeSet::new()),
+        ];
+        for info in &groups {
+            database.save_group(info).unwrap();
+            assert!(database.add_group_message(&info.id, &message).unwrap());
+            assert!(!database.add_group_message(&info.id, &message).unwrap());
+        }
+    }
+
+    #[test]
+    fn old_files_are_upgraded() {
+        let dir = std::env::temp_dir().join(format!("chaos-db-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("history.db");
+        std::fs::create_dir_all(&dir).unwrap();
+        let old = SqliteConnection::open(&path).unwrap();
+        old.execute_batch(
+            "CREATE TABLE connections (
+                remote_id TEXT PRIMARY KEY,
+                public_key TEXT NOT NULL DEFAULT ''
+            );
+            CREATE TABLE messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                remote_id TEXT NOT NULL REFERENCES connections(remote_id),
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL
+            );
+            CREATE TABLE groups (
+                group_id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                members TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                changed_by TEXT NOT NULL
+            );
+            CREATE TABLE group_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id TEXT NOT NULL REFERENCES groups(group_id),
+                message_id TEXT NOT NULL UNIQUE,
+                client_id TEXT NOT NULL,
+                message_content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                status INTEGER NOT NULL
+            );
+            INSERT INTO connections (remote_id) VALUES ('alice');
+            INSERT INTO messages (remote_id, client_id, message_content)
+            VALUES ('alice', 'alice', 'from before');",
+        )
+        .unwrap();
+        drop(old);
+
+        let database = Database::open(&path).unwrap();
+        let (messages, _) = database
+            .messages_before(&"alice".to_string(), None)
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message_content, "from before");
+        let message = message("hello");
+        assert!(database
+            .add_message(&"alice".to_string(), &message)
+            .unwrap());
+        assert!(database.add_message(&"bob".to_string(), &message).unwrap());
+        assert!(database.load_groups().unwrap().is_empty());
+        for table in ["messages", "group_messages", "channel_messages"] {
+            assert!(!has_global_message_ids(&database.connection, table).unwrap());
+        }
+        drop(database);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::UserId;
+
+//Failures worth telling the user about. They travel to the scheduler as Command::Error, so they
+//only carry what is needed to describe them.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub enum ChaosError {
+    Signaling(String),
+    InvalidMessage(String),
+    Peer(UserId, String),
+    Handshake(UserId, String),
+    NotConnected(UserId),
+    Database(String),
+    //A file we send or receive, and what went wrong with it.
+    File(String, String),
+    Audio(String),
+    //A server change our roles don't allow, with the server's name.
+    NotAllowed(String),
+    //One of our own threads stopped listening.
+    ThreadGone(String),
+    //One of our threads fell so far behind that we dropped a command of the topic for it.
+    ThreadBusy(String, String),
+}
+impl fmt::Display for ChaosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ChaosError::*;
+        match self {
+            Signaling(e) => write!(f, "Signaling server: {}", e),
+            InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+            Peer(remote_id, e) => write!(f, "Connection with {} failed: {}", remote_id, e),
+            Handshake(remote_id, e) => write!(f, "Could not verify {}: {}", remote_id, e),
+            NotConnected(remote_id) => write!(f, "Not connected to {}.", remote_id),
+            Database(e) => write!(f, "Message history: {}", e),
+            File(name, e) => write!(f, "File {}: {}", name, e),
+            Audio(e) => write!(f, "Audio: {}", e),
+            NotAllowed(server) => write!(f, "Your roles on {} don't allow that.", server),
+            ThreadGone(thread) => write!(f, "The {} thread is not running.", thread),
+            ThreadBusy(thread, topic) => {
+                write!(
+                    f,
+                    "The {} thread is too busy, dropped a {} command.",
+                    thread, topic
+                )
+            }
+        }
+    }
+}
+impl std::error::Error for ChaosError {}
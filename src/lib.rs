@@ -0,0 +1,9 @@
+pub mod app;
+pub mod config;
+pub mod coupler;
+pub mod database;
+pub mod error;
+pub mod peer;
+pub mod scheduler;
+pub mod state;
+pub mod utils;
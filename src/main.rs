@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
 
@@ -6,25 +7,28 @@ use dioxus::desktop::muda::Menu;
 use dioxus::desktop::tao::dpi::{PhysicalSize, Size};
 use dioxus::desktop::{window, WindowBuilder};
 use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc::Receiver;
 use tokio::sync::{Mutex, RwLock};
 
-use coupler::Coupler;
-use scheduler::{ChannelAttachment, Command, Scheduler, ThreadTypes};
-use state::{GUIState, IndependentState};
-use utils::Attach;
+use chaos::config::Config;
+use chaos::coupler::Coupler;
+use chaos::database::Database;
+use chaos::scheduler::{
+    self, ChannelAttachment, Command, GUICommand, MediaCommand, Scheduler, ThreadTypes,
+};
+use chaos::state::{
+    ChaosMessage, ConnectionProgress, Contact, FileTransfer, FriendStatus, GUIState, GroupId,
+    IndependentState, Permission, ServerEdit, ServerId, SidebarButton, TextChannel,
+    TransferStatus, UserId, VoiceStatus,
+};
+use chaos::utils::crypto::Identity;
+use chaos::utils::{storage, Attach};
 
 use dioxus::prelude::*;
 use dioxus_logger::tracing::{info, Level};
 
-use crate::app::Chaos;
-use crate::peer::Peer;
-
-pub mod app;
-pub mod coupler;
-pub mod peer;
-pub mod scheduler;
-pub mod state;
-pub mod utils;
+use chaos::app::Chaos;
+use chaos::peer::Peer;
 
 const _: &str = manganis::mg!(file("./public/tailwind.css"));
 fn main() {
@@ -54,8 +58,24 @@ async fn setup_threads() -> ChannelAttachment {
             .with_min_inner_size([300.0, 220.0]),
         ..Default::default()
     };
+    let config = Config::load().expect("Could not load config.");
+    let identity_path = storage::data_dir().join("identity.key");
+    let identity =
+        Arc::new(Identity::load_or_generate(&identity_path).expect("Could not load identity."));
     //setup independent state;
-    let independent_state = IndependentState::default();
+    let database = Database::open(&storage::data_dir().join("history.db"))
+        .expect("Could not open message history.");
+    let mut independent_state = IndependentState::default();
+    independent_state.connection_details.id = identity.user_id();
+    independent_state.connection_details.public_key = identity.public_key();
+    independent_state.connections = database.load().expect("Could not load message history.");
+    independent_state.groups = database
+        .load_groups()
+        .expect("Could not load group history.");
+    independent_state.servers = database
+        .load_servers()
+        .expect("Could not load server history.");
+    independent_state.contacts = database.load_contacts().expect("Could not load contacts.");
     let independent_state = Arc::new(RwLock::new(independent_state));
 
     let mut gui_state = GUIState::default();
@@ -63,33 +83,37 @@ async fn setup_threads() -> ChannelAttachment {
 
     let independent_state_scheduler = independent_state.clone();
     //setup scheduler
-    let mut scheduler = Scheduler::new(independent_state_scheduler); //now scheduler owns independent_state do
-                                                                     //not use independent_state directly after
-                                                                     //this.
+    //now scheduler owns independent_state do not use independent_state directly after this.
+    let mut scheduler = Scheduler::new(independent_state_scheduler, database, identity.clone());
 
-    let scheduler_coupler = crossbeam_channel::unbounded::<Command>();
-    let coupler_scheduler = crossbeam_channel::unbounded::<Command>();
+    let scheduler_coupler = scheduler::channel();
+    let coupler_scheduler = scheduler::channel();
     scheduler.attach(
         (scheduler_coupler.0, coupler_scheduler.1),
         Some(ThreadTypes::Coupler),
     );
-    let mut coupler = Coupler::new(Arc::new(Mutex::new((
-        coupler_scheduler.0,
-        scheduler_coupler.1,
-    ))))
-    .await;
-    let scheduler_peer = crossbeam_channel::unbounded::<Command>();
-    let peer_scheduler = crossbeam_channel::unbounded::<Command>();
+    let coupler = Coupler::new(
+        (coupler_scheduler.0, scheduler_coupler.1),
+        identity.clone(),
+        &config,
+    );
+    let scheduler_peer = scheduler::channel();
+    let peer_scheduler = scheduler::channel();
 
     scheduler.attach(
         (scheduler_peer.0, peer_scheduler.1),
         Some(ThreadTypes::Peer),
     );
 
-    let mut peer = Peer::new(Arc::new(Mutex::new((peer_scheduler.0, scheduler_peer.1)))).await;
+    let peer = Peer::new(
+        (peer_scheduler.0, scheduler_peer.1),
+        identity,
+        &config,
+    )
+    .await;
 
-    let scheduler_chaos = crossbeam_channel::unbounded::<Command>();
-    let chaos_scheduler = crossbeam_channel::unbounded::<Command>();
+    let scheduler_chaos = scheduler::channel();
+    let chaos_scheduler = scheduler::channel();
     scheduler.attach(
         (scheduler_chaos.0, chaos_scheduler.1),
         Some(ThreadTypes::GUI),
@@ -132,17 +156,1151 @@ fn popup(props: PopupProps) -> Element {
 
     }
 }
+#[derive(PartialEq, Props, Clone)]
+struct CreatePopupProps {
+    tx: Coroutine<Command>,
+    server: bool,
+}
+//Groups and servers are both created from a name and the ids of their first members.
+#[component]
+fn create_popup(props: CreatePopupProps) -> Element {
+    let mut name = use_signal(|| "".to_string());
+    let mut members = use_signal(|| "".to_string());
+    let name_placeholder = if props.server {
+        "Enter server name"
+    } else {
+        "Enter group name"
+    };
+
+    rsx! {
+        div {
+            class: "bg-[#454545] flex flex-col p-4 h-screen w-full gap-4",
+            input {
+                class:"bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                r#type:"text",
+                placeholder: name_placeholder,
+                value: "{name}",
+                oninput: move |event| name.set(event.value())
+            }
+            input {
+                class:"bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                r#type:"text",
+                placeholder: "Enter member ids, separated by spaces",
+                value: "{members}",
+                oninput: move |event| members.set(event.value())
+            }
+            button {
+                class:"py-2 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                onclick: move |_| {
+                    let members = members.read().split_whitespace().map(str::to_string).collect();
+                    if props.server {
+                        props.tx.send(Command::GUI(GUICommand::CreateServer(name.to_string(), members)));
+                    } else {
+                        props.tx.send(Command::GUI(GUICommand::CreateGroup(name.to_string(), members)));
+                    }
+                    dioxus::desktop::window().close();
+                },
+                "Create"
+            }
+        }
+    }
+}
+#[derive(PartialEq, Props, Clone)]
+struct CallRequestPopupProps {
+    tx: Coroutine<Command>,
+    remote_id: UserId,
+}
+#[component]
+fn call_request_popup(props: CallRequestPopupProps) -> Element {
+    let accepted_id = props.remote_id.clone();
+    let declined_id = props.remote_id.clone();
+
+    rsx! {
+        div {
+            class: "bg-[#454545] flex flex-col p-4 h-screen w-full gap-4",
+            span {
+                class: "text-white",
+                "{props.remote_id} wants to chat with you."
+            }
+            div {
+                class: "flex flex-row gap-4",
+                button {
+                    class:"flex-1 py-2 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                    onclick: move |_| {
+                        props.tx.send(Command::GUI(GUICommand::CallAnswer(true, accepted_id.clone())));
+                        dioxus::desktop::window().close();
+                    },
+                    "Accept"
+                }
+                button {
+                    class:"flex-1 py-2 bg-[#353535] text-white rounded-[4px]",
+                    onclick: move |_| {
+                        props.tx.send(Command::GUI(GUICommand::CallAnswer(false, declined_id.clone())));
+                        dioxus::desktop::window().close();
+                    },
+                    "Decline"
+                }
+            }
+        }
+    }
+}
+fn open_call_request_popup(tx: Coroutine<Command>, remote_id: UserId) {
+    let dom =
+        VirtualDom::new_with_props(call_request_popup, CallRequestPopupProps { tx, remote_id });
+    let window = dioxus::desktop::WindowBuilder::new()
+        .with_title("call request")
+        .with_max_inner_size(Size::Physical(PhysicalSize {
+            width: 400,
+            height: 200,
+        }));
+    dioxus::desktop::window().new_window(
+        dom,
+        dioxus::desktop::Config::new()
+            .with_menu(Menu::new())
+            .with_window(window),
+    );
+}
+
+//Only listens, sending to the scheduler from here could leave us both waiting on each other.
+async fn gui_listener(mut rx: Receiver<Command>, mut gui_state: Signal<GUIState>) {
+    println!("Starting gui listener");
+    while let Some(command) = rx.recv().await {
+        if let Command::GUI(gui_command) = command {
+            use GUICommand::*;
+            match gui_command {
+                UpdateState(independent_state) => {
+                    let call_requests: Vec<UserId> = independent_state
+                        .connections
+                        .iter()
+                        .filter(|(remote_id, connection)| {
+                            connection.progress == ConnectionProgress::CallRequestReceived
+                                && gui_state
+                                    .read()
+                                    .display_state
+                                    .connections
+                                    .get(*remote_id)
+                                    .map(|connection| connection.progress)
+                                    != Some(ConnectionProgress::CallRequestReceived)
+                        })
+                        .map(|(remote_id, _)| remote_id.clone())
+                        .collect();
+                    gui_state.write().display_state = independent_state;
+                    for remote_id in call_requests {
+                        open_call_request_popup(consume_context::<Coroutine<Command>>(), remote_id);
+                    }
+                }
+                Notify(message) => gui_state.write().notifications.push(message),
+                _ => {
+                    println!("Not implemented yet");
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct ConnectionButtonProps {
+    remote_id: UserId,
+    progress: ConnectionProgress,
+    gui_state: Signal<GUIState>,
+}
+#[component]
+fn ConnectionButton(props: ConnectionButtonProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let sidebar_button = SidebarButton::Chat(props.remote_id.clone());
+    let class = if gui_state.read().current_sidebar_button == sidebar_button {
+        "flex flex-col py-2 px-6 rounded-[4px] text-left bg-[#566051] text-[#6FC86D]"
+    } else {
+        "flex flex-col py-2 px-6 rounded-[4px] text-left bg-[#353535] text-white"
+    };
+
+    rsx! {
+        button {
+            class: class,
+            onclick: move |_| gui_state.write().current_sidebar_button = sidebar_button.clone(),
+            "{props.remote_id}"
+            span {
+                class: "text-sm text-[#929292]",
+                "{props.progress:?}"
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct GroupButtonProps {
+    group_id: GroupId,
+    name: String,
+    member_count: usize,
+    gui_state: Signal<GUIState>,
+}
+#[component]
+fn GroupButton(props: GroupButtonProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let sidebar_button = SidebarButton::Group(props.group_id);
+    let class = if gui_state.read().current_sidebar_button == sidebar_button {
+        "flex flex-col py-2 px-6 rounded-[4px] text-left bg-[#566051] text-[#6FC86D]"
+    } else {
+        "flex flex-col py-2 px-6 rounded-[4px] text-left bg-[#353535] text-white"
+    };
+
+    rsx! {
+        button {
+            class: class,
+            onclick: move |_| gui_state.write().current_sidebar_button = sidebar_button.clone(),
+            "{props.name}"
+            span {
+                class: "text-sm text-[#929292]",
+                "{props.member_count} members"
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct ChannelButtonProps {
+    server_id: ServerId,
+    channel: TextChannel,
+    gui_state: Signal<GUIState>,
+}
+#[component]
+fn ChannelButton(props: ChannelButtonProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let sidebar_button = SidebarButton::Channel(props.server_id, props.channel.id);
+    let class = if gui_state.read().current_sidebar_button == sidebar_button {
+        "py-1 px-6 rounded-[4px] text-left bg-[#566051] text-[#6FC86D]"
+    } else {
+        "py-1 px-6 rounded-[4px] text-left bg-[#353535] text-white"
+    };
+
+    rsx! {
+        button {
+            class: class,
+            onclick: move |_| gui_state.write().current_sidebar_button = sidebar_button.clone(),
+            "#{props.channel.name}"
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct ServerListProps {
+    server_id: ServerId,
+    gui_state: Signal<GUIState>,
+}
+//A server with its channels listed under their categories, uncategorised ones first.
+#[component]
+fn ServerList(props: ServerListProps) -> Element {
+    let gui_state = props.gui_state;
+    let Some(info) = gui_state
+        .read()
+        .display_state
+        .servers
+        .get(&props.server_id)
+        .map(|server| server.info.clone())
+    else {
+        return rsx! {};
+    };
+    let channels_in = |category| {
+        info.channels
+            .iter()
+            .filter(|channel| channel.category == category)
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let uncategorised = channels_in(None);
+    let categories = info
+        .categories
+        .iter()
+        .map(|category| (category.id, category.name.clone(), channels_in(Some(category.id))))
+        .collect::<Vec<_>>();
+
+    rsx! {
+        div {
+            class: "flex flex-col gap-1",
+            span {
+                class: "text-white",
+                "{info.name}"
+            }
+            for channel in uncategorised {
+                ChannelButton {
+                    key: "{channel.id}",
+                    server_id: props.server_id,
+                    channel: channel,
+                    gui_state: gui_state,
+                }
+            }
+            for (category_id, name, channels) in categories {
+                span {
+                    key: "{category_id}",
+                    class: "text-sm text-[#929292]",
+                    "{name}"
+                }
+                for channel in channels {
+                    ChannelButton {
+                        key: "{channel.id}",
+                        server_id: props.server_id,
+                        channel: channel,
+                        gui_state: gui_state,
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct ContactsButtonProps {
+    requests: usize,
+    gui_state: Signal<GUIState>,
+}
+#[component]
+fn ContactsButton(props: ContactsButtonProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let class = if gui_state.read().current_sidebar_button == SidebarButton::Contacts {
+        "flex flex-col py-2 px-6 rounded-[4px] text-left bg-[#566051] text-[#6FC86D]"
+    } else {
+        "flex flex-col py-2 px-6 rounded-[4px] text-left bg-[#353535] text-white"
+    };
+
+    rsx! {
+        button {
+            class: class,
+            onclick: move |_| gui_state.write().current_sidebar_button = SidebarButton::Contacts,
+            "Contacts"
+            if props.requests > 0 {
+                span {
+                    class: "text-sm text-[#929292]",
+                    "{props.requests} friend requests"
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct ChatMessageProps {
+    message: ChaosMessage,
+    is_own: bool,
+}
+#[component]
+fn ChatMessage(props: ChatMessageProps) -> Element {
+    let sender = if props.is_own {
+        "You".to_string()
+    } else {
+        props.message.client_id.clone()
+    };
+    let time = props
+        .message
+        .timestamp
+        .with_timezone(&chrono::Local)
+        .format("%H:%M");
+    //Only our own messages have a status worth showing.
+    let status = if props.is_own {
+        format!(" · {:?}", props.message.status)
+    } else {
+        String::new()
+    };
+
+    rsx! {
+        div {
+            class: "flex flex-col",
+            span {
+                class: "text-sm text-[#929292]",
+                "{sender} {time}{status}"
+            }
+            span {
+                class: "text-white",
+                "{props.message.message_content}"
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct FileTransferRowProps {
+    tx: Coroutine<Command>,
+    remote_id: UserId,
+    transfer: FileTransfer,
+}
+#[component]
+fn FileTransferRow(props: FileTransferRowProps) -> Element {
+    let transfer = &props.transfer;
+    let percent = if transfer.offer.size == 0 {
+        100
+    } else {
+        transfer.transferred * 100 / transfer.offer.size
+    };
+    let direction = if transfer.incoming { "From" } else { "To" };
+    let can_answer = transfer.incoming && transfer.status == TransferStatus::Offered;
+    let transfer_id = transfer.offer.id;
+    let accept_remote_id = props.remote_id.clone();
+    let reject_remote_id = props.remote_id.clone();
+
+    rsx! {
+        div {
+            class: "flex flex-col gap-1 p-2 bg-[#353535] rounded-[4px]",
+            span {
+                class: "text-sm text-[#929292]",
+                "{direction} {props.remote_id} · {transfer.status:?}"
+            }
+            span {
+                class: "text-white",
+                "{transfer.offer.name}"
+            }
+            div {
+                class: "h-1 bg-[#454545] rounded-[4px]",
+                div {
+                    class: "h-1 bg-[#6FC86D] rounded-[4px]",
+                    style: "width: {percent}%",
+                }
+            }
+            if can_answer {
+                div {
+                    class: "flex flex-row gap-2",
+                    button {
+                        class: "py-1 px-4 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                        onclick: move |_| props.tx.send(Command::GUI(GUICommand::AnswerFile(accept_remote_id.clone(), transfer_id, true))),
+                        "Accept"
+                    }
+                    button {
+                        class: "py-1 px-4 bg-[#7A3E3E] text-white rounded-[4px]",
+                        onclick: move |_| props.tx.send(Command::GUI(GUICommand::AnswerFile(reject_remote_id.clone(), transfer_id, false))),
+                        "Reject"
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct NotificationsProps {
+    gui_state: Signal<GUIState>,
+}
+#[component]
+fn Notifications(props: NotificationsProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let notifications = gui_state.read().notifications.clone();
+    rsx! {
+        div {
+            class: "flex flex-col gap-2 mt-auto",
+            for (index, message) in notifications.into_iter().enumerate() {
+                div {
+                    class: "flex flex-row gap-2 p-2 text-sm text-white bg-[#7A3E3E] rounded-[4px]",
+                    span {
+                        class: "flex-1",
+                        "{message}"
+                    }
+                    button {
+                        class: "text-[#929292]",
+                        onclick: move |_| {
+                            gui_state.write().notifications.remove(index);
+                        },
+                        "Dismiss"
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct ChatPaneProps {
+    tx: Coroutine<Command>,
+    gui_state: Signal<GUIState>,
+}
+#[component]
+fn ChatPane(props: ChatPaneProps) -> Element {
+    let mut gui_state = props.gui_state;
+    //Whatever is open in the chat pane has been read.
+    use_effect(move || {
+        let gui_state = gui_state.read();
+        let SidebarButton::Chat(remote_id) = &gui_state.current_sidebar_button else {
+            return;
+        };
+        let unread = gui_state
+            .display_state
+            .connections
+            .get(remote_id)
+            .is_some_and(|connection| {
+                connection.progress == ConnectionProgress::Established && connection.has_unread()
+            });
+        if unread {
+            props.tx.send(Command::GUI(GUICommand::MarkRead(remote_id.clone())));
+        }
+    });
+    let SidebarButton::Chat(remote_id) = gui_state.read().current_sidebar_button.clone() else {
+        return rsx! {};
+    };
+    let Some(connection) = gui_state
+        .read()
+        .display_state
+        .connections
+        .get(&remote_id)
+        .cloned()
+    else {
+        return rsx! {};
+    };
+    let client_id = gui_state.read().display_state.connection_details.id.clone();
+    let title = remote_id.clone();
+    let send_message = move || {
+        let message = gui_state.read().current_message.clone();
+        if message.is_empty() {
+            return;
+        }
+        props.tx.send(Command::GUI(GUICommand::SendMessage(
+            remote_id.clone(),
+            message,
+        )));
+        gui_state.write().current_message.clear();
+    };
+    let mut send_on_enter = send_message.clone();
+    let mut send_on_click = send_message.clone();
+    let history_remote_id = title.clone();
+    let reconnect_remote_id = title.clone();
+    let typing_remote_id = title.clone();
+    let file_remote_id = title.clone();
+    let call_remote_id = title.clone();
+    let pick_up_remote_id = title.clone();
+    let decline_remote_id = title.clone();
+    let mute_remote_id = title.clone();
+    let hang_up_remote_id = title.clone();
+    let can_call = connection.progress == ConnectionProgress::Established
+        && connection.voice == VoiceStatus::Idle;
+    let muted = connection.muted;
+    let can_reconnect = matches!(
+        connection.progress,
+        ConnectionProgress::Failed | ConnectionProgress::Closed
+    );
+
+    rsx! {
+        div {
+            class: "flex flex-row p-4 gap-4 text-white",
+            "{title}"
+            span {
+                class: "text-[#929292]",
+                "{connection.progress:?}"
+            }
+            if can_reconnect {
+                button {
+                    class: "py-1 px-4 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::CallRequest(reconnect_remote_id.clone()))),
+                    "Reconnect"
+                }
+            }
+            if can_call {
+                button {
+                    class: "py-1 px-4 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::Media(MediaCommand::StartCall(call_remote_id.clone()))),
+                    "Call"
+                }
+            }
+            if connection.voice == VoiceStatus::Ringing {
+                button {
+                    class: "py-1 px-4 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::Media(MediaCommand::AnswerCall(pick_up_remote_id.clone(), true))),
+                    "Pick up"
+                }
+                button {
+                    class: "py-1 px-4 bg-[#353535] text-white rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::Media(MediaCommand::AnswerCall(decline_remote_id.clone(), false))),
+                    "Decline"
+                }
+            }
+            if connection.voice == VoiceStatus::Calling {
+                span {
+                    class: "text-[#929292]",
+                    "Calling..."
+                }
+            }
+            if connection.voice == VoiceStatus::Active {
+                button {
+                    class: "py-1 px-4 bg-[#353535] text-white rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::Media(MediaCommand::SetMuted(mute_remote_id.clone(), !muted))),
+                    if muted { "Unmute" } else { "Mute" }
+                }
+            }
+            if matches!(connection.voice, VoiceStatus::Calling | VoiceStatus::Active) {
+                button {
+                    class: "py-1 px-4 bg-[#353535] text-white rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::Media(MediaCommand::HangUp(hang_up_remote_id.clone()))),
+                    "Hang up"
+                }
+            }
+        }
+        div {
+            class: "flex flex-col flex-1 gap-2 p-4 overflow-y-auto",
+            if connection.history_cursor().is_some() {
+                button {
+                    class: "self-center py-1 px-4 text-[#929292] rounded-[4px] bg-[#353535]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::LoadHistory(history_remote_id.clone()))),
+                    "Load older messages"
+                }
+            }
+            for message in connection.messages().iter().cloned() {
+                ChatMessage {
+                    is_own: message.client_id == client_id,
+                    message: message,
+                }
+            }
+            for transfer in connection.transfers().iter().cloned() {
+                FileTransferRow {
+                    tx: props.tx,
+                    remote_id: title.clone(),
+                    transfer: transfer,
+                }
+            }
+        }
+        if connection.is_remote_typing() {
+            span {
+                class: "px-4 text-sm text-[#929292]",
+                "{title} is typing..."
+            }
+        }
+        div {
+            class: "flex flex-row gap-4 p-4",
+            input {
+                class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                r#type:"text",
+                placeholder: "Enter message",
+                value: "{gui_state.read().current_message}",
+                oninput: move |event| {
+                    let typing = !event.value().is_empty();
+                    gui_state.write().current_message = event.value();
+                    props.tx.send(Command::GUI(GUICommand::SetTyping(typing_remote_id.clone(), typing)));
+                },
+                onkeydown: move |event| {
+                    if event.key() == Key::Enter {
+                        send_on_enter();
+                    }
+                }
+            }
+            label {
+                class:"py-2 px-6 bg-[#353535] text-[#929292] rounded-[4px] cursor-pointer",
+                "File"
+                input {
+                    class: "hidden",
+                    r#type: "file",
+                    onchange: move |event| {
+                        let Some(files) = event.files() else {
+                            return;
+                        };
+                        for path in files.files() {
+                            props.tx.send(Command::GUI(GUICommand::SendFile(file_remote_id.clone(), PathBuf::from(path))));
+                        }
+                    }
+                }
+            }
+            button {
+                class:"py-2 px-6 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                onclick: move |_| send_on_click(),
+                "Send"
+            }
+        }
+    }
+}
+
+#[component]
+fn GroupPane(props: ChatPaneProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let mut new_member = use_signal(|| "".to_string());
+    let SidebarButton::Group(group_id) = gui_state.read().current_sidebar_button.clone() else {
+        return rsx! {};
+    };
+    let Some(group) = gui_state.read().display_state.groups.get(&group_id).cloned() else {
+        return rsx! {};
+    };
+    let client_id = gui_state.read().display_state.connection_details.id.clone();
+    let is_member = group.is_member(&client_id);
+    let members = group
+        .info
+        .members
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(", ");
+    let send_message = move || {
+        let message = gui_state.read().current_message.clone();
+        if message.is_empty() {
+            return;
+        }
+        props.tx.send(Command::GUI(GUICommand::SendGroupMessage(group_id, message)));
+        gui_state.write().current_message.clear();
+    };
+    let mut send_on_enter = send_message.clone();
+    let mut send_on_click = send_message.clone();
+    let leave_client_id = client_id.clone();
+
+    rsx! {
+        div {
+            class: "flex flex-row p-4 gap-4 text-white",
+            "{group.info.name}"
+            span {
+                class: "text-[#929292]",
+                "{members}"
+            }
+            if is_member {
+                button {
+                    class: "py-1 px-4 bg-[#7A3E3E] text-white rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::RemoveGroupMember(group_id, leave_client_id.clone()))),
+                    "Leave"
+                }
+            }
+        }
+        div {
+            class: "flex flex-col flex-1 gap-2 p-4 overflow-y-auto",
+            if group.history.history_cursor().is_some() {
+                button {
+                    class: "self-center py-1 px-4 text-[#929292] rounded-[4px] bg-[#353535]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::LoadGroupHistory(group_id))),
+                    "Load older messages"
+                }
+            }
+            for message in group.history.messages().iter().cloned() {
+                ChatMessage {
+                    is_own: message.client_id == client_id,
+                    message: message,
+                }
+            }
+        }
+        if is_member {
+            div {
+                class: "flex flex-row gap-4 px-4",
+                input {
+                    class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                    r#type:"text",
+                    placeholder: "Enter member id",
+                    value: "{new_member}",
+                    oninput: move |event| new_member.set(event.value())
+                }
+                button {
+                    class:"py-2 px-6 bg-[#353535] text-[#929292] rounded-[4px]",
+                    onclick: move |_| {
+                        props.tx.send(Command::GUI(GUICommand::AddGroupMember(group_id, new_member.to_string())));
+                        new_member.set(String::new());
+                    },
+                    "Add member"
+                }
+            }
+            div {
+                class: "flex flex-row gap-4 p-4",
+                input {
+                    class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                    r#type:"text",
+                    placeholder: "Enter message",
+                    value: "{gui_state.read().current_message}",
+                    oninput: move |event| gui_state.write().current_message = event.value(),
+                    onkeydown: move |event| {
+                        if event.key() == Key::Enter {
+                            send_on_enter();
+                        }
+                    }
+                }
+                button {
+                    class:"py-2 px-6 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                    onclick: move |_| send_on_click(),
+                    "Send"
+                }
+            }
+        } else {
+            span {
+                class: "p-4 text-sm text-[#929292]",
+                "You are no longer in this group."
+            }
+        }
+    }
+}
+
+#[component]
+fn ChannelPane(props: ChatPaneProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let mut new_channel = use_signal(|| "".to_string());
+    let mut new_member = use_signal(|| "".to_string());
+    let SidebarButton::Channel(server_id, channel_id) =
+        gui_state.read().current_sidebar_button.clone()
+    else {
+        return rsx! {};
+    };
+    let Some(server) = gui_state.read().display_state.servers.get(&server_id).cloned() else {
+        return rsx! {};
+    };
+    let Some(channel) = server.info.channel(&channel_id).cloned() else {
+        return rsx! {};
+    };
+    let client_id = gui_state.read().display_state.connection_details.id.clone();
+    let is_member = server.is_member(&client_id);
+    let can_manage_channels = server.info.can(&client_id, Permission::ManageChannels);
+    let can_manage_members = server.info.can(&client_id, Permission::ManageMembers);
+    let messages = server
+        .history(&channel_id)
+        .map(|history| history.messages().to_vec())
+        .unwrap_or_default();
+    let has_older = server
+        .history(&channel_id)
+        .is_some_and(|history| history.history_cursor().is_some());
+    let send_message = move || {
+        let message = gui_state.read().current_message.clone();
+        if message.is_empty() {
+            return;
+        }
+        props.tx.send(Command::GUI(GUICommand::SendChannelMessage(
+            server_id, channel_id, message,
+        )));
+        gui_state.write().current_message.clear();
+    };
+    let mut send_on_enter = send_message.clone();
+    let mut send_on_click = send_message.clone();
+    let leave_client_id = client_id.clone();
+
+    rsx! {
+        div {
+            class: "flex flex-row p-4 gap-4 text-white",
+            "#{channel.name}"
+            span {
+                class: "text-[#929292]",
+                "{server.info.name}"
+            }
+            if is_member && server.info.owner != client_id {
+                button {
+                    class: "py-1 px-4 bg-[#7A3E3E] text-white rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::EditServer(server_id, ServerEdit::RemoveMember(leave_client_id.clone())))),
+                    "Leave"
+                }
+            }
+        }
+        div {
+            class: "flex flex-col flex-1 gap-2 p-4 overflow-y-auto",
+            if has_older {
+                button {
+                    class: "self-center py-1 px-4 text-[#929292] rounded-[4px] bg-[#353535]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::LoadChannelHistory(server_id, channel_id))),
+                    "Load older messages"
+                }
+            }
+            for message in messages {
+                ChatMessage {
+                    is_own: message.client_id == client_id,
+                    message: message,
+                }
+            }
+        }
+        if is_member {
+            if can_manage_channels {
+                div {
+                    class: "flex flex-row gap-4 px-4",
+                    input {
+                        class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                        r#type:"text",
+                        placeholder: "Enter channel name",
+                        value: "{new_channel}",
+                        oninput: move |event| new_channel.set(event.value())
+                    }
+                    button {
+                        class:"py-2 px-6 bg-[#353535] text-[#929292] rounded-[4px]",
+                        onclick: move |_| {
+                            //New channels go next to the one we are looking at.
+                            props.tx.send(Command::GUI(GUICommand::EditServer(server_id, ServerEdit::AddChannel(new_channel.to_string(), channel.category))));
+                            new_channel.set(String::new());
+                        },
+                        "Add channel"
+                    }
+                }
+            }
+            if can_manage_members {
+                div {
+                    class: "flex flex-row gap-4 px-4 pt-4",
+                    input {
+                        class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                        r#type:"text",
+                        placeholder: "Enter member id",
+                        value: "{new_member}",
+                        oninput: move |event| new_member.set(event.value())
+                    }
+                    button {
+                        class:"py-2 px-6 bg-[#353535] text-[#929292] rounded-[4px]",
+                        onclick: move |_| {
+                            props.tx.send(Command::GUI(GUICommand::EditServer(server_id, ServerEdit::AddMember(new_member.to_string()))));
+                            new_member.set(String::new());
+                        },
+                        "Add member"
+                    }
+                }
+            }
+            div {
+                class: "flex flex-row gap-4 p-4",
+                input {
+                    class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                    r#type:"text",
+                    placeholder: "Message #{channel.name}",
+                    value: "{gui_state.read().current_message}",
+                    oninput: move |event| gui_state.write().current_message = event.value(),
+                    onkeydown: move |event| {
+                        if event.key() == Key::Enter {
+                            send_on_enter();
+                        }
+                    }
+                }
+                button {
+                    class:"py-2 px-6 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                    onclick: move |_| send_on_click(),
+                    "Send"
+                }
+            }
+        } else {
+            span {
+                class: "p-4 text-sm text-[#929292]",
+                "You are no longer on this server."
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Props, Clone)]
+struct ContactRowProps {
+    tx: Coroutine<Command>,
+    contact: Contact,
+    gui_state: Signal<GUIState>,
+}
+#[component]
+fn ContactRow(props: ContactRowProps) -> Element {
+    let mut gui_state = props.gui_state;
+    let contact = &props.contact;
+    let blocked = contact.blocked;
+    let chat_remote_id = contact.id.clone();
+    let friend_remote_id = contact.id.clone();
+    let accept_remote_id = contact.id.clone();
+    let decline_remote_id = contact.id.clone();
+    let block_remote_id = contact.id.clone();
+    let remove_remote_id = contact.id.clone();
+    //Chatting with someone we are already connected to only opens the chat.
+    let idle = gui_state
+        .read()
+        .display_state
+        .connections
+        .get(&contact.id)
+        .is_none_or(|connection| connection.is_idle());
+    let status = match (blocked, contact.friend_status) {
+        (true, _) => "Blocked",
+        (false, FriendStatus::Stranger) => "Contact",
+        (false, FriendStatus::RequestSent) => "Friend request sent",
+        (false, FriendStatus::RequestReceived) => "Wants to be friends",
+        (false, FriendStatus::Friends) => "Friend",
+    };
+
+    rsx! {
+        div {
+            class: "flex flex-col gap-1 p-2 bg-[#353535] rounded-[4px]",
+            span {
+                class: "text-white",
+                "{contact.display_name}"
+            }
+            span {
+                class: "text-sm text-[#929292]",
+                "{contact.id} · {status}"
+            }
+            if !contact.notes.is_empty() {
+                span {
+                    class: "text-sm text-white",
+                    "{contact.notes}"
+                }
+            }
+            div {
+                class: "flex flex-row gap-2",
+                if !blocked {
+                    button {
+                        class: "py-1 px-4 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                        onclick: move |_| {
+                            if idle {
+                                props.tx.send(Command::GUI(GUICommand::CallRequest(chat_remote_id.clone())));
+                            }
+                            gui_state.write().current_sidebar_button = SidebarButton::Chat(chat_remote_id.clone());
+                        },
+                        "Chat"
+                    }
+                }
+                if !blocked && contact.friend_status == FriendStatus::Stranger {
+                    button {
+                        class: "py-1 px-4 bg-[#454545] text-white rounded-[4px]",
+                        onclick: move |_| props.tx.send(Command::GUI(GUICommand::SendFriendRequest(friend_remote_id.clone()))),
+                        "Add friend"
+                    }
+                }
+                if !blocked && contact.friend_status == FriendStatus::RequestReceived {
+                    button {
+                        class: "py-1 px-4 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                        onclick: move |_| props.tx.send(Command::GUI(GUICommand::AnswerFriendRequest(accept_remote_id.clone(), true))),
+                        "Accept"
+                    }
+                    button {
+                        class: "py-1 px-4 bg-[#454545] text-white rounded-[4px]",
+                        onclick: move |_| props.tx.send(Command::GUI(GUICommand::AnswerFriendRequest(decline_remote_id.clone(), false))),
+                        "Decline"
+                    }
+                }
+                button {
+                    class: "py-1 px-4 bg-[#7A3E3E] text-white rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::SetBlocked(block_remote_id.clone(), !blocked))),
+                    if blocked { "Unblock" } else { "Block" }
+                }
+                button {
+                    class: "py-1 px-4 bg-[#454545] text-[#929292] rounded-[4px]",
+                    onclick: move |_| props.tx.send(Command::GUI(GUICommand::RemoveContact(remove_remote_id.clone()))),
+                    "Remove"
+                }
+            }
+        }
+    }
+}
+
+#[component]
+fn ContactsPane(props: ChatPaneProps) -> Element {
+    let gui_state = props.gui_state;
+    let mut remote_id = use_signal(|| "".to_string());
+    let mut display_name = use_signal(|| "".to_string());
+    let mut notes = use_signal(|| "".to_string());
+    if gui_state.read().current_sidebar_button != SidebarButton::Contacts {
+        return rsx! {};
+    }
+    let mut contacts: Vec<Contact> = gui_state
+        .read()
+        .display_state
+        .contacts
+        .values()
+        .cloned()
+        .collect();
+    //Requests waiting on us come first.
+    contacts.sort_by_key(|contact| {
+        (
+            contact.friend_status != FriendStatus::RequestReceived,
+            contact.display_name.clone(),
+        )
+    });
+
+    rsx! {
+        div {
+            class: "flex flex-row p-4 gap-4 text-white",
+            "Contacts"
+        }
+        div {
+            class: "flex flex-col gap-4 px-4",
+            input {
+                class:"bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                r#type:"text",
+                placeholder: "Enter remote id",
+                value: "{remote_id}",
+                oninput: move |event| remote_id.set(event.value())
+            }
+            div {
+                class: "flex flex-row gap-4",
+                input {
+                    class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                    r#type:"text",
+                    placeholder: "Enter display name",
+                    value: "{display_name}",
+                    oninput: move |event| display_name.set(event.value())
+                }
+                input {
+                    class:"flex-1 bg-[#353535] py-2 px-6 placeholder-[#929292]  rounded-[4px] form-input text-white",
+                    r#type:"text",
+                    placeholder: "Enter notes",
+                    value: "{notes}",
+                    oninput: move |event| notes.set(event.value())
+                }
+            }
+            div {
+                class: "flex flex-row gap-4",
+                button {
+                    class:"flex-1 py-2 bg-[#353535] text-white rounded-[4px]",
+                    onclick: move |_| {
+                        if remote_id.read().is_empty() {
+                            return;
+                        }
+                        //Contacts without a name of their own go by their id.
+                        let name = if display_name.read().is_empty() {
+                            remote_id.to_string()
+                        } else {
+                            display_name.to_string()
+                        };
+                        props.tx.send(Command::GUI(GUICommand::SaveContact(remote_id.to_string(), name, notes.to_string())));
+                        remote_id.set(String::new());
+                        display_name.set(String::new());
+                        notes.set(String::new());
+                    },
+                    "Save contact"
+                }
+                button {
+                    class:"flex-1 py-2 bg-[#566051] text-[#6FC86D] rounded-[4px]",
+                    onclick: move |_| {
+                        if remote_id.read().is_empty() {
+                            return;
+                        }
+                        props.tx.send(Command::GUI(GUICommand::SendFriendRequest(remote_id.to_string())));
+                        remote_id.set(String::new());
+                    },
+                    "Send friend request"
+                }
+            }
+        }
+        div {
+            class: "flex flex-col flex-1 gap-2 p-4 overflow-y-auto",
+            for contact in contacts {
+                ContactRow {
+                    key: "{contact.id}",
+                    tx: props.tx,
+                    contact: contact,
+                    gui_state: gui_state,
+                }
+            }
+        }
+    }
+}
+
 #[component]
 fn App() -> Element {
-    let tx = use_coroutine(|mut rx: UnboundedReceiver<Command>| async move {
-        let attachment = Arc::new(Mutex::new(setup_threads().await));
+    let gui_state = use_signal(GUIState::default);
+    let tx = use_coroutine(move |mut rx: UnboundedReceiver<Command>| async move {
+        let (tx_scheduler, rx_scheduler) = setup_threads().await;
+        spawn(gui_listener(rx_scheduler, gui_state));
         while let Some(command) = rx.next().await {
-            let attachment = attachment.clone();
-            let (tx, _) = attachment.try_lock().unwrap().clone();
-            tx.send(command).unwrap();
+            scheduler::send(&tx_scheduler, command).await;
         }
     });
     let tx_clone = tx.clone();
+    let mut connections: Vec<(UserId, ConnectionProgress)> = gui_state
+        .read()
+        .display_state
+        .connections
+        .iter()
+        .map(|(remote_id, connection)| (remote_id.clone(), connection.progress))
+        .collect();
+    connections.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut groups: Vec<(GroupId, String, usize)> = gui_state
+        .read()
+        .display_state
+        .groups
+        .values()
+        .map(|group| (group.info.id, group.info.name.clone(), group.info.members.len()))
+        .collect();
+    groups.sort_by(|a, b| a.1.cmp(&b.1));
+    let group_tx = tx.clone();
+    let server_tx = tx.clone();
+    let friend_requests = gui_state
+        .read()
+        .display_state
+        .contacts
+        .values()
+        .filter(|contact| contact.friend_status == FriendStatus::RequestReceived)
+        .count();
+    let mut servers: Vec<ServerId> = gui_state
+        .read()
+        .display_state
+        .servers
+        .keys()
+        .cloned()
+        .collect();
+    servers.sort_by_key(|server_id| gui_state.read().display_state.servers[server_id].info.name.clone());
+    let client_id = gui_state.read().display_state.connection_details.id.clone();
+    let signaling_status = gui_state.read().display_state.signaling_status;
     rsx! {
         head::Link {
             rel:"stylesheet",
@@ -151,7 +1309,15 @@ fn App() -> Element {
         div {
             class: "flex flex-row h-screen w-full",
             div {
-                class: "flex flex-col w-2/6  bg-[#454545] p-4",
+                class: "flex flex-col w-2/6  bg-[#454545] p-4 gap-4",
+                span {
+                    class: "text-sm text-[#929292]",
+                    "Your ID: {client_id}"
+                }
+                span {
+                    class: "text-sm text-[#929292]",
+                    "Signaling: {signaling_status:?}"
+                }
                 button {
                     onclick:  move |_| {
                         let dom = VirtualDom::new_with_props(
@@ -168,10 +1334,91 @@ fn App() -> Element {
                     class: "bg-[#566051] px-6 py-2 text-[#6FC86D] rounded-[4px] border-[1px] border-dashed border-[#6FC86D] hover:bg-[#6FC86D] hover:text-[#566051]",
                     "New Chat"
                 }
+                button {
+                    onclick:  move |_| {
+                        let dom = VirtualDom::new_with_props(
+                            create_popup, CreatePopupProps { tx: group_tx.clone(), server: false }
+                        );
+                        let window = dioxus::desktop::WindowBuilder::new()
+                        .with_title("new group")
+                        .with_max_inner_size(Size::Physical(PhysicalSize {
+                            width: 400,
+                            height: 250,
+                        }));
+                        dioxus::desktop::window().new_window(dom, dioxus::desktop::Config::new().with_menu(Menu::new()).with_window(window));
+                    },
+                    class: "bg-[#566051] px-6 py-2 text-[#6FC86D] rounded-[4px] border-[1px] border-dashed border-[#6FC86D] hover:bg-[#6FC86D] hover:text-[#566051]",
+                    "New Group"
+                }
+                button {
+                    onclick:  move |_| {
+                        let dom = VirtualDom::new_with_props(
+                            create_popup, CreatePopupProps { tx: server_tx.clone(), server: true }
+                        );
+                        let window = dioxus::desktop::WindowBuilder::new()
+                        .with_title("new server")
+                        .with_max_inner_size(Size::Physical(PhysicalSize {
+                            width: 400,
+                            height: 250,
+                        }));
+                        dioxus::desktop::window().new_window(dom, dioxus::desktop::Config::new().with_menu(Menu::new()).with_window(window));
+                    },
+                    class: "bg-[#566051] px-6 py-2 text-[#6FC86D] rounded-[4px] border-[1px] border-dashed border-[#6FC86D] hover:bg-[#6FC86D] hover:text-[#566051]",
+                    "New Server"
+                }
+                div {
+                    class: "flex flex-col gap-2",
+                    ContactsButton {
+                        requests: friend_requests,
+                        gui_state: gui_state,
+                    }
+                    for (remote_id, progress) in connections {
+                        ConnectionButton {
+                            key: "{remote_id}",
+                            remote_id: remote_id.clone(),
+                            progress: progress,
+                            gui_state: gui_state,
+                        }
+                    }
+                    for (group_id, name, member_count) in groups {
+                        GroupButton {
+                            key: "{group_id}",
+                            group_id: group_id,
+                            name: name,
+                            member_count: member_count,
+                            gui_state: gui_state,
+                        }
+                    }
+                    for server_id in servers {
+                        ServerList {
+                            key: "{server_id}",
+                            server_id: server_id,
+                            gui_state: gui_state,
+                        }
+                    }
+                }
+                Notifications {
+                    gui_state: gui_state,
+                }
             }
             div {
                 class: "flex flex-col bg-[#363636] w-full",
-
+                ChatPane {
+                    tx: tx.clone(),
+                    gui_state: gui_state,
+                }
+                GroupPane {
+                    tx: tx.clone(),
+                    gui_state: gui_state,
+                }
+                ChannelPane {
+                    tx: tx.clone(),
+                    gui_state: gui_state,
+                }
+                ContactsPane {
+                    tx: tx.clone(),
+                    gui_state: gui_state,
+                }
             }
 
         }
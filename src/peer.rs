@@ -1,161 +1,844 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::{APIBuilder, API};
-use webrtc::data::data_channel;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
 use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
-use webrtc::peer_connection;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 
-use crate::scheduler::{ChannelAttachment, Command, PeerCommand};
-use crate::utils::crypto;
+use crate::config::{Config, IceTransportPolicy};
+use crate::error::ChaosError;
+use crate::scheduler::{report, send, ChannelAttachment, Command, PeerCommand, StateCommand};
+use crate::state::{
+    ChannelId, ChaosMessage, ConnectionProgress, ControlMessage, FileTransfer, GroupId,
+    MessageStatus, ServerId, TransferStatus, UserId, VoiceStatus, SDP,
+};
+use crate::utils::audio::{self, AudioDevice};
+use crate::utils::crypto::{self, Handshake, Identity, SealedFrame, Session};
+use media::AudioCalls;
+use transfer::IncomingFiles;
+
+mod media;
+mod transfer;
+
+const DATA_CHANNEL_ID: u16 = 0;
+//We tell the remote we are typing at most this often, and they stop showing it if they don't hear
+//about it again in time.
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+//Everything sent over a data channel, chat messages only ever travel sealed.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub enum DataFrame {
+    Handshake(Handshake),
+    Sealed(SealedFrame),
+}
+//What a sealed frame carries.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub enum SealedPayload {
+    Message(ChaosMessage),
+    Control(ControlMessage),
+    //Our copy of a message to one of our groups, every member gets their own.
+    GroupMessage(GroupId, ChaosMessage),
+    ChannelMessage(ServerId, ChannelId, ChaosMessage),
+}
+
+pub struct PeerConnection {
+    pub peer_connection: Arc<RTCPeerConnection>,
+    pub data_channel: Arc<RTCDataChannel>,
+    pub session: Arc<Mutex<Session>>,
+    pub audio_track: Arc<TrackLocalStaticSample>,
+    pub typing_sent_at: Option<Instant>,
+    pub typing_received_at: Option<Instant>,
+}
+pub type PeerConnections = Arc<Mutex<HashMap<UserId, PeerConnection>>>;
 
 pub struct Peer {
-    pub attachment: Arc<Mutex<ChannelAttachment>>,
+    pub attachment: ChannelAttachment,
     pub rtc_config: RTCConfiguration,
-    pub rtc_api: API,
+    pub rtc_api: Arc<API>,
+    pub connections: PeerConnections,
+    pub identity: Arc<Identity>,
+    //Where calls get their audio from and play it to, swapped out to test without hardware.
+    pub audio_device: Arc<dyn AudioDevice>,
+}
+#[derive(Clone)]
+struct PeerContext {
+    rtc_api: Arc<API>,
+    rtc_config: RTCConfiguration,
+    connections: PeerConnections,
+    identity: Arc<Identity>,
+    //Remote candidates that arrived before their connection had a remote description.
+    pending_candidates: Arc<Mutex<HashMap<UserId, Vec<RTCIceCandidateInit>>>>,
+    incoming_files: IncomingFiles,
+    audio_device: Arc<dyn AudioDevice>,
+    audio_calls: AudioCalls,
+    tx: Sender<Command>,
 }
 impl Peer {
-    pub async fn new(attachment: Arc<Mutex<ChannelAttachment>>) -> Self {
+    pub async fn new(
+        attachment: ChannelAttachment,
+        identity: Arc<Identity>,
+        config: &Config,
+    ) -> Self {
         let mut media_engine = MediaEngine::default();
         media_engine
             .register_default_codecs()
             .expect("Could not register default codecs.");
         let mut registry = Registry::new();
-        registry = register_default_interceptors(registry, &mut media_engine).unwrap();
+        registry = register_default_interceptors(registry, &mut media_engine)
+            .expect("Could not register default interceptors.");
 
         let rtc_api = APIBuilder::new()
             .with_media_engine(media_engine)
             .with_interceptor_registry(registry)
             .build();
-        let rtc_config = RTCConfiguration {
-            ice_servers: vec![
-                RTCIceServer {
-                    urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                    ..Default::default()
-                },
-                RTCIceServer {
-                    urls: vec!["turn:global.relay.metered.ca".to_owned()],
-                    username: "46c967b98ec9994d702767e8".to_owned(),
-                    credential: "lcz6Ykhd14hYyKfP".to_owned(),
-                    credential_type: RTCIceCredentialType::Password,
-                },
-            ],
-
-            ..Default::default()
-        };
+        let rtc_config = rtc_configuration(config);
         Self {
             attachment,
             rtc_config,
-            rtc_api,
+            rtc_api: Arc::new(rtc_api),
+            connections: Default::default(),
+            identity,
+            audio_device: audio::from_config(config),
         }
     }
-    pub async fn start(&mut self) {
-        let attachment = self.attachment.clone();
+    pub async fn start(self) {
+        let (tx, mut rx) = self.attachment;
+        let context = PeerContext {
+            rtc_api: self.rtc_api,
+            rtc_config: self.rtc_config,
+            connections: self.connections,
+            identity: self.identity,
+            pending_candidates: Default::default(),
+            incoming_files: Default::default(),
+            audio_device: self.audio_device,
+            audio_calls: Default::default(),
+            tx: tx.clone(),
+        };
 
-        let peer_connection = Arc::new(
-            self.rtc_api
-                .new_peer_connection(self.rtc_config.clone())
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                if let Command::Peer(command) = command {
+                    if let Err(e) = handle_peer_command(&context, command).await {
+                        report(&tx, e).await;
+                    }
+                }
+            }
+        });
+    }
+}
+async fn handle_peer_command(
+    context: &PeerContext,
+    command: PeerCommand,
+) -> Result<(), ChaosError> {
+    match command {
+        PeerCommand::NewPeerConnection(remote_id) => {
+            let peer_connection = new_peer_connection(context, remote_id.clone()).await?;
+            //Candidates left over from an earlier call don't apply to this one.
+            context.pending_candidates.lock().await.remove(&remote_id);
+            let peer_error = peer_error(remote_id.clone());
+            let offer = peer_connection
+                .create_offer(None)
                 .await
-                .unwrap(),
-        );
-        let data_channel = peer_connection
-            .create_data_channel("data/userid", None)
+                .map_err(&peer_error)?;
+            peer_connection
+                .set_local_description(offer)
+                .await
+                .map_err(&peer_error)?;
+            if let Some(local_description) = peer_connection.local_description().await {
+                send(
+                    &context.tx,
+                    Command::Peer(PeerCommand::CallAnswer(
+                        remote_id,
+                        encode_description(&local_description),
+                    )),
+                )
+                .await;
+            }
+        }
+        PeerCommand::EstablishConnection(remote_id, remote_sdp, is_reply) => {
+            let peer_connection = if is_reply {
+                let connections = context.connections.lock().await;
+                let Some(connection) = connections.get(&remote_id) else {
+                    return Err(ChaosError::NotConnected(remote_id));
+                };
+                connection.peer_connection.clone()
+            } else {
+                new_peer_connection(context, remote_id.clone()).await?
+            };
+            let remote_offer = crypto::decode_b64(&remote_sdp)
+                .map_err(|e| e.to_string())
+                .and_then(|remote_description| {
+                    serde_json::from_str::<RTCSessionDescription>(&remote_description)
+                        .map_err(|e| e.to_string())
+                })
+                .map_err(|e| {
+                    ChaosError::InvalidMessage(format!(
+                        "Session description from {}: {}",
+                        remote_id, e
+                    ))
+                })?;
+            let peer_error = peer_error(remote_id.clone());
+            peer_connection
+                .set_remote_description(remote_offer)
+                .await
+                .map_err(&peer_error)?;
+            let pending_candidates = context
+                .pending_candidates
+                .lock()
+                .await
+                .remove(&remote_id)
+                .unwrap_or_default();
+            for candidate in pending_candidates {
+                add_ice_candidate(&peer_connection, &remote_id, candidate).await;
+            }
+            if !is_reply {
+                let answer = peer_connection
+                    .create_answer(None)
+                    .await
+                    .map_err(&peer_error)?;
+                peer_connection
+                    .set_local_description(answer)
+                    .await
+                    .map_err(&peer_error)?;
+
+                if let Some(local_description) = peer_connection.local_description().await {
+                    send(
+                        &context.tx,
+                        Command::Peer(PeerCommand::CallReply(
+                            remote_id,
+                            encode_description(&local_description),
+                        )),
+                    )
+                    .await;
+                }
+            }
+        }
+        PeerCommand::AddIceCandidate(remote_id, candidate) => {
+            let candidate = match serde_json::from_str::<RTCIceCandidateInit>(&candidate) {
+                Ok(candidate) => candidate,
+                Err(e) => {
+                    println!("Invalid ICE candidate from {}: {}", remote_id, e);
+                    return Ok(());
+                }
+            };
+            let peer_connection = context
+                .connections
+                .lock()
+                .await
+                .get(&remote_id)
+                .map(|connection| connection.peer_connection.clone());
+            //Candidates can overtake the description they belong to.
+            match peer_connection {
+                Some(peer_connection) if peer_connection.remote_description().await.is_some() => {
+                    add_ice_candidate(&peer_connection, &remote_id, candidate).await
+                }
+                _ => context
+                    .pending_candidates
+                    .lock()
+                    .await
+                    .entry(remote_id)
+                    .or_default()
+                    .push(candidate),
+            }
+        }
+        PeerCommand::SendMessage(remote_id, message) => {
+            //The remote stops showing us as typing once the message arrives.
+            if let Some(connection) = context.connections.lock().await.get_mut(&remote_id) {
+                connection.typing_sent_at = None;
+            }
+            let message_id = message.id;
+            send_sealed(context, remote_id.clone(), &SealedPayload::Message(message)).await?;
+            let sent = StateCommand::SetMessageStatus(remote_id, message_id, MessageStatus::Sent);
+            send(&context.tx, Command::State(sent)).await;
+        }
+        PeerCommand::SendGroupMessage(remote_id, group_id, message) => {
+            let message_id = message.id;
+            let payload = SealedPayload::GroupMessage(group_id, message);
+            send_sealed(context, remote_id, &payload).await?;
+            let sent =
+                StateCommand::SetGroupMessageStatus(group_id, message_id, MessageStatus::Sent);
+            send(&context.tx, Command::State(sent)).await;
+        }
+        PeerCommand::SendChannelMessage(remote_id, server_id, channel_id, message) => {
+            let message_id = message.id;
+            let payload = SealedPayload::ChannelMessage(server_id, channel_id, message);
+            send_sealed(context, remote_id, &payload).await?;
+            let sent = StateCommand::SetChannelMessageStatus(
+                server_id,
+                channel_id,
+                message_id,
+                MessageStatus::Sent,
+            );
+            send(&context.tx, Command::State(sent)).await;
+        }
+        PeerCommand::SendControl(remote_id, control) => {
+            let mut connections = context.connections.lock().await;
+            let Some(connection) = connections.get_mut(&remote_id) else {
+                return Ok(());
+            };
+            let typing_sent_at = &mut connection.typing_sent_at;
+            let skip = match control {
+                ControlMessage::TypingStarted => {
+                    let skip = typing_sent_at.is_some_and(|at| at.elapsed() < TYPING_THROTTLE);
+                    if !skip {
+                        *typing_sent_at = Some(Instant::now());
+                    }
+                    skip
+                }
+                ControlMessage::TypingStopped => typing_sent_at.take().is_none(),
+                _ => false,
+            };
+            drop(connections);
+            if !skip {
+                send_sealed(context, remote_id, &SealedPayload::Control(control)).await?;
+            }
+        }
+        //Files take a while, so they don't hold up the other commands.
+        PeerCommand::OfferFile(remote_id, path) => {
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = transfer::offer_file(&context, remote_id, path).await {
+                    report(&context.tx, e).await;
+                }
+            });
+        }
+        PeerCommand::SendFile(remote_id, transfer, offset) => {
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = transfer::send_file(&context, remote_id, transfer, offset).await {
+                    report(&context.tx, e).await;
+                }
+            });
+        }
+        PeerCommand::ReceiveFile(remote_id, transfer) => {
+            transfer::receive_file(context, remote_id, transfer).await?;
+        }
+        PeerCommand::StartAudio(remote_id) => {
+            media::start_audio(context, remote_id).await?;
+        }
+        PeerCommand::StopAudio(remote_id) => {
+            media::stop_audio(context, &remote_id).await;
+        }
+        PeerCommand::MuteAudio(remote_id, muted) => {
+            media::set_muted(context, &remote_id, muted).await;
+        }
+        PeerCommand::CloseConnection(remote_id) => {
+            let peer_connection = context
+                .connections
+                .lock()
+                .await
+                .get(&remote_id)
+                .map(|connection| connection.peer_connection.clone());
+            if let Some(peer_connection) = peer_connection {
+                let progress = ConnectionProgress::Closed;
+                report_progress(
+                    &context.connections,
+                    &remote_id,
+                    &peer_connection,
+                    progress,
+                    &context.tx,
+                )
+                .await;
+            }
+            context.pending_candidates.lock().await.remove(&remote_id);
+            media::stop_audio(context, &remote_id).await;
+        }
+        _ => {
+            println!("Not implemented yet.");
+        }
+    }
+    Ok(())
+}
+async fn send_sealed(
+    context: &PeerContext,
+    remote_id: UserId,
+    payload: &SealedPayload,
+) -> Result<(), ChaosError> {
+    let (data_channel, session) = match context.connections.lock().await.get(&remote_id) {
+        Some(connection) => (connection.data_channel.clone(), connection.session.clone()),
+        None => return Err(ChaosError::NotConnected(remote_id)),
+    };
+    let payload_string =
+        serde_json::to_string(payload).map_err(|e| ChaosError::InvalidMessage(e.to_string()))?;
+    let sealed = session
+        .lock()
+        .await
+        .seal(payload_string.as_bytes())
+        .map_err(|e| ChaosError::Peer(remote_id.clone(), e.to_string()))?;
+    send_frame(&data_channel, &DataFrame::Sealed(sealed))
+        .await
+        .map_err(peer_error(remote_id))
+}
+fn peer_error(remote_id: UserId) -> impl Fn(webrtc::Error) -> ChaosError {
+    move |e| ChaosError::Peer(remote_id.clone(), e.to_string())
+}
+//Session descriptions travel through the signaling server base64 encoded.
+fn encode_description(description: &RTCSessionDescription) -> SDP {
+    crypto::encode_b64(&serde_json::to_string(description).unwrap_or_default())
+}
+fn rtc_configuration(config: &Config) -> RTCConfiguration {
+    RTCConfiguration {
+        ice_servers: config
+            .ice_servers
+            .iter()
+            .map(|ice_server| RTCIceServer {
+                urls: ice_server.urls.clone(),
+                username: ice_server.username.clone(),
+                credential: ice_server.credential.clone(),
+                credential_type: RTCIceCredentialType::Password,
+            })
+            .collect(),
+        ice_transport_policy: match config.ice_transport_policy {
+            IceTransportPolicy::All => RTCIceTransportPolicy::All,
+            IceTransportPolicy::Relay => RTCIceTransportPolicy::Relay,
+        },
+        ..Default::default()
+    }
+}
+//Creates the connection to remote_id, replacing any earlier one.
+async fn new_peer_connection(
+    context: &PeerContext,
+    remote_id: UserId,
+) -> Result<Arc<RTCPeerConnection>, ChaosError> {
+    let peer_error = peer_error(remote_id.clone());
+    let peer_connection = Arc::new(
+        context
+            .rtc_api
+            .new_peer_connection(context.rtc_config.clone())
             .await
-            .unwrap();
-        peer_connection.on_peer_connection_state_change(Box::new(
-            move |s: RTCPeerConnectionState| {
-                println!("Peer Connection state has changed: {s}");
-                if s == RTCPeerConnectionState::Failed {
-                    println!("Peer Connection has changed state to failed. exiting..");
+            .map_err(&peer_error)?,
+    );
+    //Both sides open the same pre-negotiated channel, so there is exactly one per connection.
+    let data_channel = peer_connection
+        .create_data_channel(
+            "data",
+            Some(RTCDataChannelInit {
+                negotiated: Some(DATA_CHANNEL_ID),
+                ..Default::default()
+            }),
+        )
+        .await
+        .map_err(&peer_error)?;
+    let audio_track = media::add_audio_track(&peer_connection)
+        .await
+        .map_err(&peer_error)?;
+    media::on_remote_audio(
+        &peer_connection,
+        remote_id.clone(),
+        context.audio_calls.clone(),
+    );
+    let session = Arc::new(Mutex::new(Session::default()));
+    let state_remote_id = remote_id.clone();
+    let state_peer_connection = Arc::downgrade(&peer_connection);
+    let state_session = session.clone();
+    let state_connections = context.connections.clone();
+    let state_tx = context.tx.clone();
+    peer_connection.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+        println!("Peer Connection state with {state_remote_id} has changed: {s}");
+        let remote_id = state_remote_id.clone();
+        let peer_connection = state_peer_connection.clone();
+        let session = state_session.clone();
+        let connections = state_connections.clone();
+        let tx = state_tx.clone();
+        Box::pin(async move {
+            let Some(peer_connection) = peer_connection.upgrade() else {
+                return;
+            };
+            let progress = match s {
+                //Only a connection that comes back after a drop, the first time round the
+                //handshake decides when we are established.
+                RTCPeerConnectionState::Connected if session.lock().await.is_established() => {
+                    ConnectionProgress::Established
                 }
-                Box::pin(async {})
-            },
-        ));
-        data_channel.on_open(Box::new(move || {
-            println!("Data channel is now open.");
-            Box::pin(async {})
-        }));
-        let peer_connection = Arc::new(Mutex::new(peer_connection));
-        let data_channel = Arc::clone(&data_channel);
-
-        tokio::spawn(async move {
-            let attachment = attachment.clone();
-            let (_, rx) = attachment.try_lock().unwrap().clone();
-            while let Ok(command) = rx.recv() {
-                if let Command::Peer(command) = command {
-                    match command {
-                        PeerCommand::NewPeerConnection(remote_id) => {
-                            let peer_connection = peer_connection.try_lock().unwrap();
-                            let offer = peer_connection.create_offer(None).await.unwrap();
-                            let mut gather_complete =
-                                peer_connection.gathering_complete_promise().await;
-                            let _ = peer_connection.set_local_description(offer).await;
-                            let _ = gather_complete.recv().await;
-                            if let Some(local_description) =
-                                peer_connection.local_description().await
-                            {
-                                let local_description_string =
-                                    serde_json::to_string(&local_description).unwrap();
-                                let encrypted_local_description_string =
-                                    crypto::encode_b64(&local_description_string);
+                RTCPeerConnectionState::Disconnected => ConnectionProgress::Disconnected,
+                RTCPeerConnectionState::Failed => ConnectionProgress::Failed,
+                RTCPeerConnectionState::Closed => ConnectionProgress::Closed,
+                _ => return,
+            };
+            report_progress(&connections, &remote_id, &peer_connection, progress, &tx).await;
+        })
+    }));
+    let candidate_remote_id = remote_id.clone();
+    let candidate_tx = context.tx.clone();
+    peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+        let remote_id = candidate_remote_id.clone();
+        let tx = candidate_tx.clone();
+        Box::pin(async move {
+            let Some(candidate) = candidate else {
+                return;
+            };
+            match candidate
+                .to_json()
+                .map(|candidate| serde_json::to_string(&candidate))
+            {
+                Ok(Ok(candidate)) => {
+                    let candidate = PeerCommand::LocalIceCandidate(remote_id, candidate);
+                    send(&tx, Command::Peer(candidate)).await
+                }
+                Ok(Err(e)) => println!("Could not encode ICE candidate: {}", e),
+                Err(e) => println!("Could not encode ICE candidate: {}", e),
+            }
+        })
+    }));
+    //The remote opens a channel of its own for every file it sends us.
+    let channel_remote_id = remote_id.clone();
+    let channel_context = context.clone();
+    peer_connection.on_data_channel(Box::new(move |data_channel: Arc<RTCDataChannel>| {
+        let remote_id = channel_remote_id.clone();
+        let context = channel_context.clone();
+        Box::pin(async move {
+            transfer::on_file_channel(&context, remote_id, data_channel).await;
+        })
+    }));
+    //Handlers only hold weak references, the connection owns them.
+    let open_remote_id = remote_id.clone();
+    let open_data_channel = Arc::downgrade(&data_channel);
+    let open_peer_connection = Arc::downgrade(&peer_connection);
+    let open_session = session.clone();
+    let identity = context.identity.clone();
+    data_channel.on_open(Box::new(move || {
+        println!("Data channel with {open_remote_id} is now open.");
+        Box::pin(async move {
+            let (Some(data_channel), Some(peer_connection)) =
+                (open_data_channel.upgrade(), open_peer_connection.upgrade())
+            else {
+                return;
+            };
+            let Some((local_fingerprint, remote_fingerprint)) =
+                fingerprints(&peer_connection).await
+            else {
+                println!("No DTLS fingerprints to bind the handshake with {open_remote_id} to.");
+                return;
+            };
+            let handshake = open_session.lock().await.handshake(
+                &identity,
+                &local_fingerprint,
+                &remote_fingerprint,
+            );
+            if let Err(e) = send_frame(&data_channel, &DataFrame::Handshake(handshake)).await {
+                println!("Could not send the handshake to {}: {}", open_remote_id, e);
+            }
+        })
+    }));
+    let close_remote_id = remote_id.clone();
+    let close_peer_connection = Arc::downgrade(&peer_connection);
+    let close_connections = context.connections.clone();
+    let close_tx = context.tx.clone();
+    data_channel.on_close(Box::new(move || {
+        println!("Data channel with {close_remote_id} has closed.");
+        let remote_id = close_remote_id.clone();
+        let peer_connection = close_peer_connection.clone();
+        let connections = close_connections.clone();
+        let tx = close_tx.clone();
+        Box::pin(async move {
+            if let Some(peer_connection) = peer_connection.upgrade() {
+                let progress = ConnectionProgress::Closed;
+                report_progress(&connections, &remote_id, &peer_connection, progress, &tx).await;
+            }
+        })
+    }));
+    on_data_frame(
+        &data_channel,
+        &peer_connection,
+        session.clone(),
+        remote_id.clone(),
+        context,
+    );
 
-                                let (tx, _) = attachment.try_lock().unwrap().clone();
-                                tx.try_send(Command::Peer(PeerCommand::CallAnswer(
-                                    encrypted_local_description_string,
-                                )))
-                                .unwrap();
-                            }
+    let previous = context.connections.lock().await.insert(
+        remote_id.clone(),
+        PeerConnection {
+            peer_connection: peer_connection.clone(),
+            data_channel,
+            session,
+            audio_track,
+            typing_sent_at: None,
+            typing_received_at: None,
+        },
+    );
+    if let Some(previous) = previous {
+        let _ = previous.peer_connection.close().await;
+    }
+    //A call on the old connection doesn't carry over.
+    media::stop_audio(context, &remote_id).await;
+    Ok(peer_connection)
+}
+fn on_data_frame(
+    data_channel: &Arc<RTCDataChannel>,
+    peer_connection: &Arc<RTCPeerConnection>,
+    session: Arc<Mutex<Session>>,
+    remote_id: UserId,
+    context: &PeerContext,
+) {
+    let peer_connection = Arc::downgrade(peer_connection);
+    let connections = context.connections.clone();
+    let tx = context.tx.clone();
+    data_channel.on_message(Box::new(move |msg: DataChannelMessage| {
+        let connections = connections.clone();
+        let tx = tx.clone();
+        let remote_id = remote_id.clone();
+        let session = session.clone();
+        let peer_connection = peer_connection.clone();
+        Box::pin(async move {
+            let Some(peer_connection) = peer_connection.upgrade() else {
+                return;
+            };
+            match serde_json::from_slice::<DataFrame>(&msg.data) {
+                Ok(DataFrame::Handshake(handshake)) => {
+                    let accepted = match fingerprints(&peer_connection).await {
+                        Some((local_fingerprint, remote_fingerprint)) => session
+                            .lock()
+                            .await
+                            .accept(&handshake, &local_fingerprint, &remote_fingerprint),
+                        None => Err(anyhow!("No DTLS fingerprints to verify against.")),
+                    }
+                    .and_then(|remote_key| {
+                        //The remote has to own the key its user id was derived from.
+                        if crypto::user_id(&remote_key)? != remote_id {
+                            bail!("Identity key does not belong to {}.", remote_id);
                         }
-                        PeerCommand::EstablishConnection(remote_sdp, is_reply) => {
-                            let peer_connection = peer_connection.try_lock().unwrap();
-                            let remote_description = crypto::decode_b64(&remote_sdp).unwrap();
-                            let remote_offer =
-                                serde_json::from_str::<RTCSessionDescription>(&remote_description)
-                                    .unwrap();
-                            peer_connection
-                                .set_remote_description(remote_offer)
-                                .await
-                                .unwrap();
-                            if is_reply {
-                                break;
-                            }
-                            if !is_reply {
-                                let answer = peer_connection.create_answer(None).await.unwrap();
-                                let mut gather_complete =
-                                    peer_connection.gathering_complete_promise().await;
-                                peer_connection.set_local_description(answer).await.unwrap();
-                                let _ = gather_complete.recv().await;
-
-                                if let Some(local_description) =
-                                    peer_connection.local_description().await
-                                {
-                                    let local_description_string =
-                                        serde_json::to_string(&local_description).unwrap();
-                                    let encrypted_local_description_string =
-                                        crypto::encode_b64(&local_description_string);
-
-                                    let (tx, _) = attachment.try_lock().unwrap().clone();
-                                    tx.try_send(Command::Peer(PeerCommand::CallReply(
-                                        encrypted_local_description_string,
-                                    )))
-                                    .unwrap();
-                                }
-                            }
+                        Ok(remote_key)
+                    });
+                    match accepted {
+                        Ok(remote_key) => {
+                            println!("Session with {} established.", remote_id);
+                            send(
+                                &tx,
+                                Command::State(StateCommand::SetRemoteKey(
+                                    remote_id.clone(),
+                                    remote_key,
+                                )),
+                            )
+                            .await;
+                            let progress = ConnectionProgress::Established;
+                            report_progress(
+                                &connections,
+                                &remote_id,
+                                &peer_connection,
+                                progress,
+                                &tx,
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            println!("Handshake with {} failed: {}", remote_id, e);
+                            let e = ChaosError::Handshake(remote_id.clone(), e.to_string());
+                            report(&tx, e).await;
+                            let progress = ConnectionProgress::Failed;
+                            report_progress(
+                                &connections,
+                                &remote_id,
+                                &peer_connection,
+                                progress,
+                                &tx,
+                            )
+                            .await;
+                        }
+                    }
+                }
+                Ok(DataFrame::Sealed(sealed)) => {
+                    let opened = session.lock().await.open(&sealed);
+                    match opened.and_then(|plaintext| {
+                        Ok(serde_json::from_slice::<SealedPayload>(&plaintext)?)
+                    }) {
+                        Ok(SealedPayload::Message(message)) => {
+                            let message = StateCommand::AddMessage(remote_id, message);
+                            send(&tx, Command::State(message)).await
+                        }
+                        Ok(SealedPayload::GroupMessage(group_id, message)) => {
+                            let message =
+                                StateCommand::AddGroupMessage(remote_id, group_id, message);
+                            send(&tx, Command::State(message)).await
                         }
-                        _ => {
-                            println!("Not implemented yet.");
+                        Ok(SealedPayload::ChannelMessage(server_id, channel_id, message)) => {
+                            let message = StateCommand::AddChannelMessage(
+                                remote_id, server_id, channel_id, message,
+                            );
+                            send(&tx, Command::State(message)).await
                         }
+                        Ok(SealedPayload::Control(control)) => {
+                            on_control(&connections, remote_id, &peer_connection, control, &tx)
+                                .await
+                        }
+                        Err(e) => println!("Dropping frame from {}: {}", remote_id, e),
                     }
                 }
+                Err(e) => println!("Invalid data channel message: {}", e),
             }
+        })
+    }));
+}
+//Tells the scheduler how the connection to remote_id is doing and tears it down once it is over.
+//Connections we already replaced or tore down are ignored, so they can't touch the current one.
+async fn report_progress(
+    connections: &PeerConnections,
+    remote_id: &UserId,
+    peer_connection: &Arc<RTCPeerConnection>,
+    progress: ConnectionProgress,
+    tx: &Sender<Command>,
+) {
+    let mut connections = connections.lock().await;
+    let is_current = connections
+        .get(remote_id)
+        .is_some_and(|connection| Arc::ptr_eq(&connection.peer_connection, peer_connection));
+    if !is_current {
+        return;
+    }
+    if matches!(
+        progress,
+        ConnectionProgress::Failed | ConnectionProgress::Closed
+    ) {
+        connections.remove(remote_id);
+        let peer_connection = peer_connection.clone();
+        tokio::spawn(async move {
+            let _ = peer_connection.close().await;
         });
     }
+    drop(connections);
+    send(
+        tx,
+        Command::State(StateCommand::SetProgress(remote_id.clone(), progress)),
+    )
+    .await;
+}
+//Passes acks, file transfers, calls, group and server changes on to the scheduler. Tells it
+//whether the remote is typing, and that they stopped once we haven't heard from them for a while.
+async fn on_control(
+    connections: &PeerConnections,
+    remote_id: UserId,
+    peer_connection: &Arc<RTCPeerConnection>,
+    control: ControlMessage,
+    tx: &Sender<Command>,
+) {
+    let typing = control == ControlMessage::TypingStarted;
+    let state_command = match control {
+        ControlMessage::TypingStarted | ControlMessage::TypingStopped => None,
+        ControlMessage::Ack(message_id, status) => Some(StateCommand::SetMessageStatus(
+            remote_id.clone(),
+            message_id,
+            status,
+        )),
+        ControlMessage::FileOffer(offer) => Some(StateCommand::AddTransfer(
+            remote_id.clone(),
+            FileTransfer::new(offer, true, None),
+        )),
+        ControlMessage::FileAccept(transfer_id, offset) => Some(StateCommand::FileAccepted(
+            remote_id.clone(),
+            transfer_id,
+            offset,
+        )),
+        ControlMessage::FileCancel(transfer_id) => Some(StateCommand::SetTransferStatus(
+            remote_id.clone(),
+            transfer_id,
+            TransferStatus::Cancelled,
+        )),
+        ControlMessage::FileDone(transfer_id) => Some(StateCommand::SetTransferStatus(
+            remote_id.clone(),
+            transfer_id,
+            TransferStatus::Completed,
+        )),
+        ControlMessage::VoiceRing => Some(StateCommand::SetRemoteVoice(
+            remote_id.clone(),
+            VoiceStatus::Ringing,
+        )),
+        ControlMessage::VoiceAccept => Some(StateCommand::SetRemoteVoice(
+            remote_id.clone(),
+            VoiceStatus::Active,
+        )),
+        ControlMessage::VoiceHangUp => Some(StateCommand::SetRemoteVoice(
+            remote_id.clone(),
+            VoiceStatus::Idle,
+        )),
+        ControlMessage::GroupUpdate(info) => {
+            Some(StateCommand::UpdateGroup(remote_id.clone(), info))
+        }
+        ControlMessage::ServerUpdate(info) => {
+            Some(StateCommand::UpdateServer(remote_id.clone(), info))
+        }
+    };
+    if let Some(state_command) = state_command {
+        send(tx, Command::State(state_command)).await;
+        return;
+    }
+    {
+        let mut connections = connections.lock().await;
+        let Some(connection) = connections
+            .get_mut(&remote_id)
+            .filter(|connection| Arc::ptr_eq(&connection.peer_connection, peer_connection))
+        else {
+            return;
+        };
+        connection.typing_received_at = typing.then(Instant::now);
+    }
+    send(
+        tx,
+        Command::State(StateCommand::SetRemoteTyping(remote_id.clone(), typing)),
+    )
+    .await;
+    if !typing {
+        return;
+    }
+    let connections = connections.clone();
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(TYPING_TIMEOUT).await;
+        let mut connections = connections.lock().await;
+        let Some(connection) = connections.get_mut(&remote_id) else {
+            return;
+        };
+        //Anything newer has a timer of its own.
+        let expired = connection
+            .typing_received_at
+            .is_some_and(|at| at.elapsed() >= TYPING_TIMEOUT);
+        if !expired {
+            return;
+        }
+        connection.typing_received_at = None;
+        drop(connections);
+        send(
+            &tx,
+            Command::State(StateCommand::SetRemoteTyping(remote_id, false)),
+        )
+        .await;
+    });
+}
+async fn add_ice_candidate(
+    peer_connection: &RTCPeerConnection,
+    remote_id: &UserId,
+    candidate: RTCIceCandidateInit,
+) {
+    if let Err(e) = peer_connection.add_ice_candidate(candidate).await {
+        println!("Could not add ICE candidate from {}: {}", remote_id, e);
+    }
+}
+async fn fingerprints(peer_connection: &RTCPeerConnection) -> Option<(String, String)> {
+    let local_description = peer_connection.local_description().await?;
+    let remote_description = peer_connection.remote_description().await?;
+    Some((
+        crypto::sdp_fingerprint(&local_description.sdp)?,
+        crypto::sdp_fingerprint(&remote_description.sdp)?,
+    ))
+}
+async fn send_frame(data_channel: &RTCDataChannel, frame: &DataFrame) -> Result<(), webrtc::Error> {
+    let frame_string = serde_json::to_string(frame).unwrap_or_default();
+    data_channel.send_text(frame_string).await.map(|_| ())
 }
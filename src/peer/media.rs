@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex, PoisonError};
+use std::time::Duration;
+
+use bytes::Bytes;
+use opus::{Application, Channels, Decoder, Encoder};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use webrtc::api::media_engine::MIME_TYPE_OPUS;
+use webrtc::media::Sample;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+use webrtc::track::track_remote::TrackRemote;
+
+use super::PeerContext;
+use crate::error::ChaosError;
+use crate::state::UserId;
+use crate::utils::audio::{AudioSink, AudioSource, FRAME_SAMPLES, SAMPLE_RATE};
+
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+//The most one Opus packet can hold, and the most samples it can decode to.
+const MAX_PACKET: usize = 1275;
+const MAX_DECODED: usize = SAMPLE_RATE as usize * 120 / 1000;
+const RTCP_BUFFER: usize = 1500;
+
+//A call with a remote, sending stops once it is dropped. The receiver holds on to the sink
+//while it plays a frame, so the calls stay free meanwhile.
+pub struct AudioCall {
+    muted: Arc<AtomicBool>,
+    sink: Arc<SyncMutex<Box<dyn AudioSink>>>,
+    sender: JoinHandle<()>,
+}
+impl Drop for AudioCall {
+    fn drop(&mut self) {
+        self.sender.abort();
+    }
+}
+pub type AudioCalls = Arc<Mutex<HashMap<UserId, AudioCall>>>;
+
+//Reads frames from a source and encodes them for the track.
+struct FrameEncoder {
+    encoder: Encoder,
+    frame: Vec<i16>,
+    packet: Vec<u8>,
+}
+impl FrameEncoder {
+    fn new() -> Result<Self, opus::Error> {
+        Ok(Self {
+            encoder: Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Voip)?,
+            frame: vec![0; FRAME_SAMPLES],
+            packet: vec![0; MAX_PACKET],
+        })
+    }
+    //The next frame from source as one packet, silence if muted.
+    fn encode(&mut self, source: &mut dyn AudioSource, muted: bool) -> Result<&[u8], opus::Error> {
+        source.read_frame(&mut self.frame);
+        if muted {
+            self.frame.fill(0);
+        }
+        let length = self.encoder.encode(&self.frame, &mut self.packet)?;
+        Ok(&self.packet[..length])
+    }
+}
+//Decodes packets from the track and plays them.
+struct FrameDecoder {
+    decoder: Decoder,
+    frame: Vec<i16>,
+}
+impl FrameDecoder {
+    fn new() -> Result<Self, opus::Error> {
+        Ok(Self {
+            decoder: Decoder::new(SAMPLE_RATE, Channels::Mono)?,
+            frame: vec![0; MAX_DECODED],
+        })
+    }
+    fn decode(
+        &mut self,
+        packet: &[u8],
+        sink: &SyncMutex<Box<dyn AudioSink>>,
+    ) -> Result<(), opus::Error> {
+        let samples = self.decoder.decode(packet, &mut self.frame, false)?;
+        let mut sink = sink.lock().unwrap_or_else(PoisonError::into_inner);
+        sink.write_frame(&self.frame[..samples]);
+        Ok(())
+    }
+}
+
+//Every connection has its audio track from the start, so calls don't need a new offer. Nothing
+//goes out on it outside of a call.
+pub async fn add_audio_track(
+    peer_connection: &RTCPeerConnection,
+) -> Result<Arc<TrackLocalStaticSample>, webrtc::Error> {
+    let track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_string(),
+            ..Default::default()
+        },
+        "audio".to_string(),
+        "chaos".to_string(),
+    ));
+    let rtp_sender = peer_connection
+        .add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+        .await?;
+    //The interceptors only see the remote's reports if someone reads them.
+    tokio::spawn(async move {
+        let mut rtcp = vec![0; RTCP_BUFFER];
+        while rtp_sender.read(&mut rtcp).await.is_ok() {}
+    });
+    Ok(track)
+}
+//Plays the remote's track into our call with them, as long as there is one.
+pub fn on_remote_audio(peer_connection: &RTCPeerConnection, remote_id: UserId, calls: AudioCalls) {
+    peer_connection.on_track(Box::new(move |track: Arc<TrackRemote>, _, _| {
+        let remote_id = remote_id.clone();
+        let calls = calls.clone();
+        tokio::spawn(receive_audio(track, remote_id, calls));
+        Box::pin(async {})
+    }));
+}
+pub async fn start_audio(context: &PeerContext, remote_id: UserId) -> Result<(), ChaosError> {
+    let track = match context.connections.lock().await.get(&remote_id) {
+        Some(connection) => connection.audio_track.clone(),
+        None => return Err(ChaosError::NotConnected(remote_id)),
+    };
+    let audio_error = |e: anyhow::Error| ChaosError::Audio(e.to_string());
+    let source = context.audio_device.source().map_err(audio_error)?;
+    let sink = context.audio_device.sink().map_err(audio_error)?;
+    let muted = Arc::new(AtomicBool::new(false));
+    let sender = tokio::spawn(send_audio(track, source, muted.clone()));
+    let call = AudioCall {
+        muted,
+        sink: Arc::new(SyncMutex::new(sink)),
+        sender,
+    };
+    context.audio_calls.lock().await.insert(remote_id, call);
+    Ok(())
+}
+pub async fn stop_audio(context: &PeerContext, remote_id: &UserId) {
+    context.audio_calls.lock().await.remove(remote_id);
+}
+pub async fn set_muted(context: &PeerContext, remote_id: &UserId, muted: bool) {
+    if let Some(call) = context.audio_calls.lock().await.get(remote_id) {
+        call.muted.store(muted, Ordering::Relaxed);
+    }
+}
+//Muted calls go on sending silence, so the remote's playback keeps its pace.
+async fn send_audio(
+    track: Arc<TrackLocalStaticSample>,
+    mut source: Box<dyn AudioSource>,
+    muted: Arc<AtomicBool>,
+) {
+    let mut encoder = match FrameEncoder::new() {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            println!("Could not start the audio encoder: {}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(FRAME_DURATION);
+    loop {
+        interval.tick().await;
+        let packet = match encoder.encode(source.as_mut(), muted.load(Ordering::Relaxed)) {
+            Ok(packet) => packet,
+            Err(e) => {
+                println!("Could not encode audio: {}", e);
+                continue;
+            }
+        };
+        let sample = Sample {
+            data: Bytes::copy_from_slice(packet),
+            duration: FRAME_DURATION,
+            ..Default::default()
+        };
+        if let Err(e) = track.write_sample(&sample).await {
+            println!("Stopped sending audio: {}", e);
+            return;
+        }
+    }
+}
+async fn receive_audio(track: Arc<TrackRemote>, remote_id: UserId, calls: AudioCalls) {
+    let mut decoder = match FrameDecoder::new() {
+        Ok(decoder) => decoder,
+        Err(e) => {
+            println!("Could not start the audio decoder: {}", e);
+            return;
+        }
+    };
+    while let Ok((packet, _)) = track.read_rtp().await {
+        let sink = match calls.lock().await.get(&remote_id) {
+            Some(call) => call.sink.clone(),
+            None => continue,
+        };
+        if let Err(e) = decoder.decode(&packet.payload, &sink) {
+            println!("Dropping audio from {}: {}", remote_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+    use super::*;
+    use crate::utils::audio::{AudioDevice, WavFiles};
+
+    const FRAMES: usize = 50;
+
+    //Plays a WAV tone through the encoder and decoder a call uses and checks what the sink records.
+    #[test]
+    fn wav_through_opus_reaches_the_sink() {
+        let dir = std::env::temp_dir().join(format!("chaos-audio-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.wav");
+        let output = dir.join("output.wav");
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&input, spec).unwrap();
+        for n in 0..FRAMES * FRAME_SAMPLES {
+            let phase = 2.0 * PI * 440.0 * n as f32 / SAMPLE_RATE as f32;
+            writer.write_sample((phase.sin() * 8000.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let device = WavFiles {
+            input: Some(input),
+            output: Some(output.clone()),
+        };
+        let mut source = device.source().unwrap();
+        let sink = SyncMutex::new(device.sink().unwrap());
+        let mut encoder = FrameEncoder::new().unwrap();
+        let mut decoder = FrameDecoder::new().unwrap();
+        for _ in 0..FRAMES {
+            let packet = encoder.encode(source.as_mut(), false).unwrap();
+            decoder.decode(packet, &sink).unwrap();
+        }
+        drop(sink);
+
+        let captured = WavReader::open(&output)
+            .unwrap()
+            .into_samples::<i16>()
+            .collect::<Result<Vec<i16>, _>>()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(captured.len(), FRAMES * FRAME_SAMPLES);
+        //The codec's lookahead delays the tone a little, so only the second half is compared.
+        let second_half = &captured[captured.len() / 2..];
+        let rms = (second_half
+            .iter()
+            .map(|sample| (*sample as f64).powi(2))
+            .sum::<f64>()
+            / second_half.len() as f64)
+            .sqrt();
+        assert!(rms > 8000.0 / 2f64.sqrt() / 2.0, "rms {}", rms);
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Mutex, Notify};
+use uuid::Uuid;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+use super::{peer_error, send_sealed, PeerContext, SealedPayload};
+use crate::error::ChaosError;
+use crate::scheduler::{report, send, Command, StateCommand};
+use crate::state::{ControlMessage, FileOffer, FileTransfer, TransferId, TransferStatus, UserId};
+
+const FILE_CHANNEL_PREFIX: &str = "file-";
+const CHUNK_SIZE: usize = 16 * 1024;
+//The sender waits once this much is queued on the channel, until it drains below the threshold.
+const MAX_BUFFERED: usize = 1024 * 1024;
+const BUFFERED_LOW_THRESHOLD: usize = 256 * 1024;
+//The channel can close while we wait for it to drain, so look at it every now and then.
+const DRAIN_CHECK: Duration = Duration::from_secs(1);
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+//Both sides tell the scheduler how far along they are every time this much more got through.
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+//A file we accepted, until the sender's channel delivered all of it.
+pub struct IncomingFile {
+    remote_id: UserId,
+    offer: FileOffer,
+    path: PathBuf,
+    received: u64,
+    //Only one channel at a time writes to the .part file.
+    receiving: Option<Receiving>,
+}
+struct Receiving {
+    channel: Weak<RTCDataChannel>,
+    part: File,
+}
+pub type IncomingFiles = Arc<Mutex<HashMap<TransferId, IncomingFile>>>;
+
+//Hashes the file and offers it to remote_id.
+pub async fn offer_file(
+    context: &PeerContext,
+    remote_id: UserId,
+    path: PathBuf,
+) -> Result<(), ChaosError> {
+    let file_error = file_error(&path);
+    let size = fs::metadata(&path).await.map_err(&file_error)?.len();
+    let sha256 = file_sha256(&path).await.map_err(&file_error)?;
+    let offer = FileOffer {
+        id: Uuid::new_v4(),
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size,
+        sha256,
+    };
+    let transfer = FileTransfer::new(offer.clone(), false, Some(path));
+    send(
+        &context.tx,
+        Command::State(StateCommand::AddTransfer(remote_id.clone(), transfer)),
+    )
+    .await;
+    let control = ControlMessage::FileOffer(offer);
+    send_sealed(context, remote_id, &SealedPayload::Control(control)).await
+}
+//Streams the file from offset on over a channel of its own, so it doesn't hold up the chat.
+//Chunks only have DTLS around them, the hash they are checked against came sealed.
+pub async fn send_file(
+    context: &PeerContext,
+    remote_id: UserId,
+    transfer: FileTransfer,
+    offset: u64,
+) -> Result<(), ChaosError> {
+    let Some(path) = transfer.path else {
+        return Ok(());
+    };
+    let transfer_id = transfer.offer.id;
+    let peer_connection = match context.connections.lock().await.get(&remote_id) {
+        Some(connection) => connection.peer_connection.clone(),
+        None => return Err(ChaosError::NotConnected(remote_id)),
+    };
+    let label = format!("{}{}", FILE_CHANNEL_PREFIX, transfer_id);
+    let data_channel = peer_connection
+        .create_data_channel(&label, None)
+        .await
+        .map_err(peer_error(remote_id.clone()))?;
+    let opened = Arc::new(Notify::new());
+    let on_open = opened.clone();
+    data_channel.on_open(Box::new(move || {
+        on_open.notify_one();
+        Box::pin(async {})
+    }));
+    let drained = Arc::new(Notify::new());
+    let on_drained = drained.clone();
+    data_channel
+        .set_buffered_amount_low_threshold(BUFFERED_LOW_THRESHOLD)
+        .await;
+    data_channel
+        .on_buffered_amount_low(Box::new(move || {
+            on_drained.notify_one();
+            Box::pin(async {})
+        }))
+        .await;
+    if tokio::time::timeout(OPEN_TIMEOUT, opened.notified())
+        .await
+        .is_err()
+    {
+        let _ = data_channel.close().await;
+        let e = format!("The channel for {} did not open.", transfer.offer.name);
+        return Err(ChaosError::Peer(remote_id, e));
+    }
+
+    let file_error = file_error(&path);
+    let mut file = File::open(&path).await.map_err(&file_error)?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(&file_error)?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut sent = offset;
+    while sent < transfer.offer.size {
+        let read = file.read(&mut chunk).await.map_err(&file_error)?;
+        //The file got shorter since we offered it, the receiver's hash check will tell.
+        if read == 0 {
+            break;
+        }
+        while data_channel.buffered_amount().await > MAX_BUFFERED
+            && data_channel.ready_state() == RTCDataChannelState::Open
+        {
+            let _ = tokio::time::timeout(DRAIN_CHECK, drained.notified()).await;
+        }
+        //A dropped connection interrupts the transfer, the receiver asks for the rest later.
+        if let Err(e) = data_channel
+            .send(&Bytes::copy_from_slice(&chunk[..read]))
+            .await
+        {
+            println!("Stopped sending {}: {}", transfer.offer.name, e);
+            return Ok(());
+        }
+        let before = sent;
+        sent += read as u64;
+        if sent / PROGRESS_STEP != before / PROGRESS_STEP || sent == transfer.offer.size {
+            set_progress(&context.tx, &remote_id, transfer_id, sent).await;
+        }
+    }
+    Ok(())
+}
+//Gets ready for the sender's channel and asks for whatever part of the file we don't have yet.
+pub async fn receive_file(
+    context: &PeerContext,
+    remote_id: UserId,
+    transfer: FileTransfer,
+) -> Result<(), ChaosError> {
+    let Some(path) = transfer.path else {
+        return Ok(());
+    };
+    let file_error = file_error(&path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.map_err(&file_error)?;
+    }
+    let part = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(part_path(&path))
+        .await
+        .map_err(&file_error)?;
+    let transfer_id = transfer.offer.id;
+    let size = transfer.offer.size;
+    //Whatever channel was still writing is done, the rest comes on a new one.
+    let old_channel = context
+        .incoming_files
+        .lock()
+        .await
+        .remove(&transfer_id)
+        .and_then(|incoming| incoming.receiving)
+        .and_then(|receiving| receiving.channel.upgrade());
+    if let Some(old_channel) = old_channel {
+        let _ = old_channel.close().await;
+    }
+    let offset = part.metadata().await.map_err(&file_error)?.len();
+    context.incoming_files.lock().await.insert(
+        transfer_id,
+        IncomingFile {
+            remote_id: remote_id.clone(),
+            offer: transfer.offer,
+            path,
+            received: offset,
+            receiving: None,
+        },
+    );
+    if offset >= size {
+        finish_file(context, transfer_id).await;
+        return Ok(());
+    }
+    let control = ControlMessage::FileAccept(transfer_id, offset);
+    send_sealed(context, remote_id, &SealedPayload::Control(control)).await
+}
+//Takes a channel remote_id opened for one of the files we accepted, anything else is closed. So
+//is a second channel for a file that is still coming in on another one.
+pub async fn on_file_channel(
+    context: &PeerContext,
+    remote_id: UserId,
+    data_channel: Arc<RTCDataChannel>,
+) {
+    let transfer_id = data_channel
+        .label()
+        .strip_prefix(FILE_CHANNEL_PREFIX)
+        .and_then(|transfer_id| TransferId::parse_str(transfer_id).ok());
+    let mut incoming_files = context.incoming_files.lock().await;
+    let incoming = transfer_id
+        .and_then(|transfer_id| incoming_files.get_mut(&transfer_id))
+        .filter(|incoming| incoming.remote_id == remote_id)
+        .filter(|incoming| {
+            incoming
+                .receiving
+                .as_ref()
+                .and_then(|receiving| receiving.channel.upgrade())
+                .is_none_or(|channel| channel.ready_state() == RTCDataChannelState::Closed)
+        });
+    let (Some(transfer_id), Some(incoming)) = (transfer_id, incoming) else {
+        drop(incoming_files);
+        println!(
+            "Closing unexpected channel {} from {}.",
+            data_channel.label(),
+            remote_id
+        );
+        let _ = data_channel.close().await;
+        return;
+    };
+    let part = match OpenOptions::new()
+        .append(true)
+        .open(part_path(&incoming.path))
+        .await
+    {
+        Ok(part) => part,
+        Err(e) => {
+            let e = file_error(&incoming.path)(e);
+            drop(incoming_files);
+            report(&context.tx, e).await;
+            let _ = data_channel.close().await;
+            return;
+        }
+    };
+    let channel = Arc::downgrade(&data_channel);
+    incoming.receiving = Some(Receiving {
+        channel: channel.clone(),
+        part,
+    });
+    drop(incoming_files);
+
+    let closed_context = context.clone();
+    let closed_channel = channel.clone();
+    data_channel.on_close(Box::new(move || {
+        let context = closed_context.clone();
+        let channel = closed_channel.clone();
+        Box::pin(async move {
+            if let Some(incoming) = context.incoming_files.lock().await.get_mut(&transfer_id) {
+                if incoming
+                    .receiving
+                    .as_ref()
+                    .is_some_and(|receiving| receiving.channel.ptr_eq(&channel))
+                {
+                    incoming.receiving = None;
+                }
+            }
+        })
+    }));
+    let context = context.clone();
+    data_channel.on_message(Box::new(move |msg: DataChannelMessage| {
+        let context = context.clone();
+        let channel = channel.clone();
+        Box::pin(async move {
+            if !on_chunk(&context, transfer_id, &channel, &msg.data).await {
+                return;
+            }
+            if let Some(data_channel) = channel.upgrade() {
+                tokio::spawn(async move {
+                    let _ = data_channel.close().await;
+                });
+            }
+        })
+    }));
+}
+//Returns true once the file is complete. Chunks from a channel that lost the file are dropped.
+async fn on_chunk(
+    context: &PeerContext,
+    transfer_id: TransferId,
+    channel: &Weak<RTCDataChannel>,
+    chunk: &[u8],
+) -> bool {
+    let mut incoming_files = context.incoming_files.lock().await;
+    let Some(incoming) = incoming_files.get_mut(&transfer_id) else {
+        return false;
+    };
+    let Some(receiving) = incoming
+        .receiving
+        .as_mut()
+        .filter(|receiving| receiving.channel.ptr_eq(channel))
+    else {
+        return false;
+    };
+    //Flushed right away, a resume picks up from the length of the file.
+    let written = match receiving.part.write_all(chunk).await {
+        Ok(()) => receiving.part.flush().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        let e = file_error(&incoming.path)(e);
+        drop(incoming_files);
+        report(&context.tx, e).await;
+        return false;
+    }
+    let before = incoming.received;
+    incoming.received += chunk.len() as u64;
+    let (remote_id, received, size) = (
+        incoming.remote_id.clone(),
+        incoming.received,
+        incoming.offer.size,
+    );
+    drop(incoming_files);
+    if received / PROGRESS_STEP != before / PROGRESS_STEP || received >= size {
+        set_progress(&context.tx, &remote_id, transfer_id, received.min(size)).await;
+    }
+    if received < size {
+        return false;
+    }
+    finish_file(context, transfer_id).await;
+    true
+}
+//Checks the file against the offer and moves it into place, or throws it away.
+async fn finish_file(context: &PeerContext, transfer_id: TransferId) {
+    let Some(mut incoming) = context.incoming_files.lock().await.remove(&transfer_id) else {
+        return;
+    };
+    drop(incoming.receiving.take());
+    let part = part_path(&incoming.path);
+    let matches = file_sha256(&part)
+        .await
+        .is_ok_and(|sha256| sha256 == incoming.offer.sha256);
+    let (status, control) = if matches && fs::rename(&part, &incoming.path).await.is_ok() {
+        (
+            TransferStatus::Completed,
+            ControlMessage::FileDone(transfer_id),
+        )
+    } else {
+        println!("{} did not match its offer.", incoming.offer.name);
+        let _ = fs::remove_file(&part).await;
+        (
+            TransferStatus::Failed,
+            ControlMessage::FileCancel(transfer_id),
+        )
+    };
+    let set_status =
+        StateCommand::SetTransferStatus(incoming.remote_id.clone(), transfer_id, status);
+    send(&context.tx, Command::State(set_status)).await;
+    let control = SealedPayload::Control(control);
+    if let Err(e) = send_sealed(context, incoming.remote_id, &control).await {
+        report(&context.tx, e).await;
+    }
+}
+async fn set_progress(
+    tx: &Sender<Command>,
+    remote_id: &UserId,
+    transfer_id: TransferId,
+    transferred: u64,
+) {
+    let progress = StateCommand::SetTransferProgress(remote_id.clone(), transfer_id, transferred);
+    send(tx, Command::State(progress)).await;
+}
+async fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+//Where a file is written until it is complete and checked.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+fn file_error(path: &Path) -> impl Fn(std::io::Error) -> ChaosError {
+    let name = path.display().to_string();
+    move |e| ChaosError::File(name.clone(), e.to_string())
+}
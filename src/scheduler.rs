@@ -1,50 +1,174 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Mutex as SyncMutex, MutexGuard, PoisonError};
+use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
 
-use crossbeam_channel::{Receiver, Sender};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::mpsc::error::SendTimeoutError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::RwLock;
 
+use crate::database::Database;
+use crate::error::ChaosError;
 use crate::peer;
-use crate::state::{Connection, ConnectionProgress, UserId, SDP};
+use crate::state::{
+    CallId, ChannelId, ChaosMessage, Connection, ConnectionProgress, Contact, ControlMessage,
+    FileTransfer, FriendStatus, Group, GroupId, GroupInfo, MessageId, MessageStatus, Server,
+    ServerEdit, ServerId, ServerInfo, SignalingStatus, TransferId, TransferStatus, UserId,
+    VoiceStatus, SDP,
+};
+use crate::utils::crypto::{self, Identity};
+use crate::utils::storage;
 use crate::{state::IndependentState, utils::Attach};
 
-#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub enum GUICommand {
     CallRequest(UserId),
     CallAnswer(bool, UserId),
+    SendMessage(UserId, String),
+    LoadHistory(UserId),
+    //Whether the user has something typed for remote_id.
+    SetTyping(UserId, bool),
+    //The user has seen everything remote_id sent so far.
+    MarkRead(UserId),
+    SendFile(UserId, PathBuf),
+    AnswerFile(UserId, TransferId, bool),
+    //A group with the given name and members, and us.
+    CreateGroup(String, Vec<UserId>),
+    SendGroupMessage(GroupId, String),
+    LoadGroupHistory(GroupId),
+    AddGroupMember(GroupId, UserId),
+    //Removing ourselves leaves the group.
+    RemoveGroupMember(GroupId, UserId),
+    //A server with the given name and members, owned by us.
+    CreateServer(String, Vec<UserId>),
+    EditServer(ServerId, ServerEdit),
+    SendChannelMessage(ServerId, ChannelId, String),
+    LoadChannelHistory(ServerId, ChannelId),
+    //Adds a contact or changes their display name and notes.
+    SaveContact(UserId, String, String),
+    RemoveContact(UserId),
+    SendFriendRequest(UserId),
+    AnswerFriendRequest(UserId, bool),
+    SetBlocked(UserId, bool),
     UpdateState(IndependentState),
+    Notify(String),
 }
 #[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub enum WSCommand {
+    Challenge(String),
+    Authenticate(String, String),
     SetClientId(UserId),
-    CallRequest(UserId),
-    CallRequestFailure,
-    CallAnswer(bool, Option<SDP>),
-    CallReply(SDP),
+    //The user id names the target on the way to the server and the sender on the way back.
+    CallRequest(UserId, CallId),
+    CallRequestFailure(UserId, CallId),
+    CallAnswer(UserId, CallId, bool, Option<SDP>),
+    CallReply(UserId, CallId, SDP),
+    IceCandidate(UserId, CallId, String),
+    //Asks to be told once the user is online, right away if they already are. Only users that
+    //allow us to watch them are told about.
+    WatchOnline(UserId),
+    Online(UserId),
+    //Who may find out we are online, replaces the ones allowed before.
+    AllowWatchers(BTreeSet<UserId>),
+    //Friend requests and answers carry the sender's public key, empty when declining.
+    FriendRequest(UserId, String),
+    FriendAnswer(UserId, bool, String),
+    FriendRequestFailure(UserId),
 }
-#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub enum PeerCommand {
     NewPeerConnection(UserId),
-    CallAnswer(SDP),
+    CallAnswer(UserId, SDP),
 
-    EstablishConnection(SDP, bool),
-    CallReply(SDP),
+    EstablishConnection(UserId, SDP, bool),
+    CallReply(UserId, SDP),
+    LocalIceCandidate(UserId, String),
+    AddIceCandidate(UserId, String),
+    SendMessage(UserId, ChaosMessage),
+    SendControl(UserId, ControlMessage),
+    SendGroupMessage(UserId, GroupId, ChaosMessage),
+    SendChannelMessage(UserId, ServerId, ChannelId, ChaosMessage),
+    OfferFile(UserId, PathBuf),
+    //Sends the file from the offset on.
+    SendFile(UserId, FileTransfer, u64),
+    ReceiveFile(UserId, FileTransfer),
+    StartAudio(UserId),
+    StopAudio(UserId),
+    MuteAudio(UserId, bool),
+    CloseConnection(UserId),
 }
-#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
 pub enum StateCommand {
     SetClientId(UserId),
     SetProgress(UserId, ConnectionProgress),
+    AddMessage(UserId, ChaosMessage),
+    SetRemoteKey(UserId, String),
+    SetSignalingStatus(SignalingStatus),
+    SetRemoteTyping(UserId, bool),
+    SetMessageStatus(UserId, MessageId, MessageStatus),
+    AddTransfer(UserId, FileTransfer),
+    //The remote wants the file from the offset on.
+    FileAccepted(UserId, TransferId, u64),
+    SetTransferProgress(UserId, TransferId, u64),
+    SetTransferStatus(UserId, TransferId, TransferStatus),
+    //Where the remote took the voice call, as seen from our side.
+    SetRemoteVoice(UserId, VoiceStatus),
+    //A group message from the remote.
+    AddGroupMessage(UserId, GroupId, ChaosMessage),
+    SetGroupMessageStatus(GroupId, MessageId, MessageStatus),
+    //How the remote sees a group.
+    UpdateGroup(UserId, GroupInfo),
+    AddChannelMessage(UserId, ServerId, ChannelId, ChaosMessage),
+    SetChannelMessageStatus(ServerId, ChannelId, MessageId, MessageStatus),
+    UpdateServer(UserId, ServerInfo),
+}
+//Voice calls over an established connection, from the gui.
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub enum MediaCommand {
+    StartCall(UserId),
+    AnswerCall(UserId, bool),
+    SetMuted(UserId, bool),
+    HangUp(UserId),
 }
 
-#[derive(PartialEq, Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize, Clone)]
 pub enum Command {
     GUI(GUICommand),
     WS(WSCommand),
     State(StateCommand),
     Peer(PeerCommand),
+    Media(MediaCommand),
+    Error(ChaosError),
+}
+impl Command {
+    pub fn topic(&self) -> Topic {
+        use Command::*;
+        match self {
+            GUI(_) => Topic::GUI,
+            WS(_) => Topic::WS,
+            State(_) => Topic::State,
+            Peer(_) => Topic::Peer,
+            Media(_) => Topic::Media,
+            Error(_) => Topic::Error,
+        }
+    }
+}
+//One per command family.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Topic {
+    GUI,
+    WS,
+    State,
+    Peer,
+    Media,
+    Error,
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum ThreadTypes {
     Coupler,
     Datachannel,
@@ -52,178 +176,1694 @@ pub enum ThreadTypes {
     Scheduler,
     Peer,
 }
+//Sending half to the other side and receiving half from it.
 pub type ChannelAttachment = (Sender<Command>, Receiver<Command>);
+//How many commands can wait on either side of an attachment before senders have to wait.
+pub const CHANNEL_CAPACITY: usize = 256;
+pub fn channel() -> (Sender<Command>, Receiver<Command>) {
+    mpsc::channel(CHANNEL_CAPACITY)
+}
+//How long we wait on a full queue before dropping the command.
+const ROUTE_TIMEOUT: Duration = Duration::from_secs(5);
+type Senders = HashMap<ThreadTypes, Sender<Command>>;
+//Commands the scheduler sends once it let go of the state.
+type Outgoing = Vec<Command>;
+//Database writes the scheduler makes once it let go of the state, in order.
+type Writes = Vec<Box<dyn FnOnce(&Database) -> anyhow::Result<()> + Send>>;
+//Which threads subscribe to the commands the scheduler sends out. State changes and errors are
+//never sent on, only the scheduler touches the state. Media commands become peer commands.
+const ROUTES: &[(Topic, ThreadTypes)] = &[
+    (Topic::GUI, ThreadTypes::GUI),
+    (Topic::WS, ThreadTypes::Coupler),
+    (Topic::Peer, ThreadTypes::Peer),
+];
 
 pub struct Scheduler {
-    attachments: Arc<Mutex<HashMap<ThreadTypes, ChannelAttachment>>>,
+    senders: Senders,
+    receivers: Vec<(ThreadTypes, Receiver<Command>)>,
     independent_state: Arc<RwLock<IndependentState>>,
+    database: Arc<SyncMutex<Database>>,
+    //Signs the group and server changes we make.
+    identity: Arc<Identity>,
+    //The watchers the signaling server last heard we allow.
+    allowed_watchers: Arc<SyncMutex<BTreeSet<UserId>>>,
 }
 impl Scheduler {
-    pub fn new(state: Arc<RwLock<IndependentState>>) -> Self {
+    pub fn new(
+        state: Arc<RwLock<IndependentState>>,
+        database: Database,
+        identity: Arc<Identity>,
+    ) -> Self {
         Self {
-            attachments: Arc::new(Mutex::new(HashMap::new())),
+            senders: HashMap::new(),
+            receivers: Vec::new(),
 
             independent_state: state,
+            database: Arc::new(SyncMutex::new(database)),
+            identity,
+            allowed_watchers: Default::default(),
         }
     }
+    //Every thread gets a task that handles its commands. Queues are bounded, so we wait on a thread
+    //that falls behind. The peer thread waits on us while it works through its own queue though,
+    //so when both queues are full we give up on the command after a while instead of waiting on
+    //each other for good.
     pub fn run(&mut self) {
-        let attachments = &self.attachments.clone();
-        let independent_state = &self.independent_state.clone();
-        for (thread_type, attachment) in attachments.try_lock().unwrap().iter() {
-            let attachments = attachments.clone();
-            let attachment = attachment.clone();
-            let independent_state = independent_state.clone();
+        let senders = Arc::new(self.senders.clone());
+        for (thread_type, mut rx) in self.receivers.drain(..) {
+            let senders = senders.clone();
+            let independent_state = self.independent_state.clone();
+            let database = self.database.clone();
+            let identity = self.identity.clone();
+            let allowed_watchers = self.allowed_watchers.clone();
             tokio::spawn(async move {
-                let (_, rx) = attachment.clone();
-
-                while let Ok(command) = rx.recv() {
-                    println!("Received a command, {:?}", command);
-                    match command {
-                        Command::GUI(gui_command) => match gui_command {
-                            GUICommand::CallRequest(remote_id) => {
-                                let attachments = attachments.try_lock().unwrap();
-                                let (tx, _) = attachments.get(&ThreadTypes::Coupler).unwrap();
-                                tx.try_send(Command::WS(WSCommand::CallRequest(remote_id)))
-                                    .unwrap();
-                            }
-                            GUICommand::CallAnswer(accepted, remote_id) => {
-                                let attachments = attachments.try_lock().unwrap();
-
-                                let state = independent_state.clone();
-
-                                if accepted {
-                                    {
-                                        let mut state = state.try_write().unwrap();
-                                        let connection = state.connections.get_mut(&remote_id);
-                                        if let Some(mut connection) = connection {
-                                            connection
-                                                .set_progress(ConnectionProgress::CallAnswerSent);
-                                        }
-                                        let (tx, _) = attachments.get(&ThreadTypes::GUI).unwrap();
-                                        tx.try_send(Command::GUI(GUICommand::UpdateState(
-                                            state.clone(),
-                                        )))
-                                        .unwrap();
-                                    }
-                                    let (tx_peer, _) = attachments.get(&ThreadTypes::Peer).unwrap();
-                                    tx_peer
-                                        .try_send(Command::Peer(PeerCommand::NewPeerConnection(
-                                            remote_id.clone(),
-                                        )))
-                                        .unwrap();
-                                } else {
-                                    {
-                                        let mut state = state.try_write().unwrap();
-                                        let connection = state.connections.get_mut(&remote_id);
-                                        if let Some(mut connection) = connection {
-                                            connection.set_progress(ConnectionProgress::Closed);
-                                        }
-                                        let (tx, _) = attachments.get(&ThreadTypes::GUI).unwrap();
-                                        tx.try_send(Command::GUI(GUICommand::UpdateState(
-                                            state.clone(),
-                                        )))
-                                        .unwrap();
-                                    }
-                                    let (tx_coupler, _) =
-                                        attachments.get(&ThreadTypes::Coupler).unwrap();
-                                    tx_coupler
-                                        .try_send(Command::WS(WSCommand::CallAnswer(false, None)))
-                                        .unwrap();
-                                }
-                            }
-                            _ => {}
-                        },
-                        Command::State(state_command) => match state_command {
-                            StateCommand::SetClientId(client_id) => {
-                                let state = independent_state.clone();
-                                let mut state = state.try_write().unwrap();
-                                state.connection_details.id = client_id;
-                                let attachments = attachments.try_lock().unwrap();
-                                let (tx, _) = attachments.get(&ThreadTypes::GUI).unwrap();
-                                tx.try_send(Command::GUI(GUICommand::UpdateState(state.clone())))
-                                    .unwrap();
-                            }
-                            StateCommand::SetProgress(remote_id, progress) => {
-                                println!("Connection Progress Updated: {:?}", progress);
-                                let state = independent_state.clone();
-                                let mut state = state.try_write().unwrap();
-                                let connection = state.connections.get_mut(&remote_id);
-                                if let Some(mut connection) = connection {
-                                    connection.set_progress(progress);
-                                } else {
-                                    let mut connection = Connection::new(remote_id.clone());
-                                    connection.set_progress(progress);
-                                    state.connections.insert(remote_id, connection);
-                                }
-                                let attachments = attachments.try_lock().unwrap();
-                                let (tx, _) = attachments.get(&ThreadTypes::GUI).unwrap();
-                                tx.try_send(Command::GUI(GUICommand::UpdateState(state.clone())))
-                                    .unwrap();
-                            }
-                            _ => {
-                                println!("Not implemented yet");
-                            }
-                        },
-                        Command::WS(ws_command) => match ws_command {
-                            WSCommand::CallAnswer(accepted, remote_sdp) => {
-                                let attachments = attachments.try_lock().unwrap();
-                                if (accepted) {
-                                    let (tx_peer, _) = attachments.get(&ThreadTypes::Peer).unwrap();
-                                    tx_peer
-                                        .try_send(Command::Peer(PeerCommand::EstablishConnection(
-                                            remote_sdp.unwrap(),
-                                            false,
-                                        )))
-                                        .unwrap();
-                                }
-                            }
-                            WSCommand::CallReply(remote_sdp) => {
-                                let attachments = attachments.try_lock().unwrap();
-                                let (tx_peer, _) = attachments.get(&ThreadTypes::Peer).unwrap();
-                                tx_peer
-                                    .try_send(Command::Peer(PeerCommand::EstablishConnection(
-                                        remote_sdp, true,
-                                    )))
-                                    .unwrap();
-                            }
-                            _ => {
-                                println!("Not implemented yet.");
-                            }
-                        },
-                        Command::Peer(peer_command) => match peer_command {
-                            PeerCommand::CallAnswer(local_sdp) => {
-                                let attachments = attachments.try_lock().unwrap();
-                                let (tx, _) = attachments.get(&ThreadTypes::Coupler).unwrap();
-                                tx.try_send(Command::WS(WSCommand::CallAnswer(
-                                    true,
-                                    Some(local_sdp),
-                                )))
-                                .unwrap();
-                            }
-                            PeerCommand::CallReply(local_sdp) => {
-                                let attachments = attachments.try_lock().unwrap();
-                                let (tx, _) = attachments.get(&ThreadTypes::Coupler).unwrap();
-                                tx.try_send(Command::WS(WSCommand::CallReply(local_sdp)))
-                                    .unwrap();
-                            }
-                            _ => {
-                                println!("Not implemented yet.");
-                            }
-                        },
-                        _ => {
-                            println!("Not implemented yet");
+                while let Some(command) = rx.recv().await {
+                    //The server forgets who may watch us once we are gone.
+                    let reconnected =
+                        matches!(command, Command::State(StateCommand::SetClientId(_)));
+                    let mut outgoing = Outgoing::new();
+                    let mut writes = Writes::new();
+                    let handled = handle_command(
+                        command,
+                        &independent_state,
+                        &database,
+                        &identity,
+                        &mut outgoing,
+                        &mut writes,
+                    )
+                    .await;
+                    let watchers = independent_state.read().await.acquaintances();
+                    {
+                        let mut allowed = allowed_watchers
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner);
+                        if reconnected || *allowed != watchers {
+                            *allowed = watchers.clone();
+                            outgoing.push(Command::WS(WSCommand::AllowWatchers(watchers)));
+                        }
+                    }
+                    //The state is let go of by now, so nobody waits on the disk with it.
+                    let written = query(&database, move |database| {
+                        writes.into_iter().try_for_each(|write| write(database))
+                    })
+                    .await;
+                    for e in [handled, written].into_iter().filter_map(Result::err) {
+                        outgoing.push(Command::GUI(GUICommand::Notify(e.to_string())));
+                        println!("{}", e);
+                    }
+                    for command in outgoing {
+                        if let Err(e) = route(&senders, command).await {
+                            notify(&senders, e).await;
                         }
                     }
                 }
+                println!("{:?} detached from the scheduler.", thread_type);
             });
         }
     }
 }
+
+async fn handle_command(
+    command: Command,
+    independent_state: &RwLock<IndependentState>,
+    database: &Arc<SyncMutex<Database>>,
+    identity: &Identity,
+    outgoing: &mut Outgoing,
+    writes: &mut Writes,
+) -> Result<(), ChaosError> {
+    match command {
+        Command::GUI(gui_command) => match gui_command {
+            GUICommand::CallRequest(remote_id) => {
+                let mut state = independent_state.write().await;
+                start_call(&mut state, remote_id, outgoing);
+            }
+            GUICommand::CallAnswer(accepted, remote_id) => {
+                let mut state = independent_state.write().await;
+                let progress = if accepted {
+                    ConnectionProgress::CallRequestAccepted
+                } else {
+                    ConnectionProgress::Closed
+                };
+                let Some(call_id) = advance_call(
+                    &mut state,
+                    &remote_id,
+                    None,
+                    ConnectionProgress::CallRequestReceived,
+                    progress,
+                ) else {
+                    println!("No call request from {} to answer.", remote_id);
+                    return Ok(());
+                };
+                update_gui(outgoing, &state);
+                if accepted {
+                    outgoing.push(Command::Peer(PeerCommand::NewPeerConnection(remote_id)));
+                } else {
+                    outgoing.push(Command::WS(WSCommand::CallAnswer(
+                        remote_id, call_id, false, None,
+                    )));
+                }
+            }
+            GUICommand::SendMessage(remote_id, message_content) => {
+                let mut state = independent_state.write().await;
+                let message =
+                    ChaosMessage::new(state.connection_details.id.clone(), message_content);
+                let connection = state
+                    .connections
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Connection::new(remote_id.clone()));
+                connection.add_message(message.clone());
+                //Until the connection is up the message waits in the database, it goes out with
+                //the rest of the queue once it is.
+                if connection.progress == ConnectionProgress::Established {
+                    outgoing.push(Command::Peer(PeerCommand::SendMessage(
+                        remote_id.clone(),
+                        message.clone(),
+                    )));
+                } else if connection.is_idle() {
+                    outgoing.push(Command::WS(WSCommand::WatchOnline(remote_id.clone())));
+                }
+                update_gui(outgoing, &state);
+                write_later(writes, move |database| {
+                    database.add_message(&remote_id, &message)
+                });
+            }
+            GUICommand::LoadHistory(remote_id) => {
+                let state = independent_state.read().await;
+                let Some(history_cursor) = state
+                    .connections
+                    .get(&remote_id)
+                    .and_then(|connection| connection.history_cursor())
+                else {
+                    return Ok(());
+                };
+                drop(state);
+                let query_remote_id = remote_id.clone();
+                let (messages, next_cursor) = query(database, move |database| {
+                    database.messages_before(&query_remote_id, Some(history_cursor))
+                })
+                .await?;
+                //Unless someone else loaded the page while we were reading it.
+                let mut state = independent_state.write().await;
+                let Some(connection) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .filter(|connection| connection.history_cursor() == Some(history_cursor))
+                else {
+                    return Ok(());
+                };
+                connection.prepend_messages(messages, next_cursor);
+                update_gui(outgoing, &state);
+            }
+            GUICommand::SetTyping(remote_id, typing) => {
+                let state = independent_state.read().await;
+                let connected = state.connections.get(&remote_id).is_some_and(|connection| {
+                    connection.progress == ConnectionProgress::Established
+                });
+                if !connected {
+                    return Ok(());
+                }
+                let control = if typing {
+                    ControlMessage::TypingStarted
+                } else {
+                    ControlMessage::TypingStopped
+                };
+                outgoing.push(Command::Peer(PeerCommand::SendControl(remote_id, control)));
+            }
+            GUICommand::MarkRead(remote_id) => {
+                let mut state = independent_state.write().await;
+                //Only what we can tell the remote about counts as read.
+                let Some(connection) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .filter(|connection| connection.progress == ConnectionProgress::Established)
+                else {
+                    return Ok(());
+                };
+                let read = connection.mark_read();
+                if read.is_empty() {
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                for message_id in read.iter().cloned() {
+                    let ack = ControlMessage::Ack(message_id, MessageStatus::Read);
+                    outgoing.push(Command::Peer(PeerCommand::SendControl(
+                        remote_id.clone(),
+                        ack,
+                    )));
+                }
+                write_later(writes, move |database| {
+                    read.iter().try_for_each(|message_id| {
+                        database.set_message_status(&remote_id, message_id, MessageStatus::Read)
+                    })
+                });
+            }
+            GUICommand::SendFile(remote_id, path) => {
+                let state = independent_state.read().await;
+                let connected = state.connections.get(&remote_id).is_some_and(|connection| {
+                    connection.progress == ConnectionProgress::Established
+                });
+                if !connected {
+                    return Err(ChaosError::NotConnected(remote_id));
+                }
+                outgoing.push(Command::Peer(PeerCommand::OfferFile(remote_id, path)));
+            }
+            GUICommand::AnswerFile(remote_id, transfer_id, accepted) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state.connections.get_mut(&remote_id) else {
+                    return Ok(());
+                };
+                let connected = connection.progress == ConnectionProgress::Established;
+                let Some(transfer) = connection.transfer_mut(&transfer_id).filter(|transfer| {
+                    transfer.incoming && transfer.status == TransferStatus::Offered
+                }) else {
+                    return Ok(());
+                };
+                if !accepted {
+                    transfer.status = TransferStatus::Cancelled;
+                    update_gui(outgoing, &state);
+                    let cancel = ControlMessage::FileCancel(transfer_id);
+                    outgoing.push(Command::Peer(PeerCommand::SendControl(remote_id, cancel)));
+                    return Ok(());
+                }
+                transfer.path = Some(storage::download_path(&transfer.offer.name));
+                //Accepted while the connection is down, it starts once the connection is back.
+                transfer.status = if connected {
+                    TransferStatus::Transferring
+                } else {
+                    TransferStatus::Interrupted
+                };
+                let transfer = transfer.clone();
+                update_gui(outgoing, &state);
+                if connected {
+                    outgoing.push(Command::Peer(PeerCommand::ReceiveFile(remote_id, transfer)));
+                }
+            }
+            GUICommand::CreateGroup(name, members) => {
+                let mut state = independent_state.write().await;
+                let client_id = state.connection_details.id.clone();
+                let mut info = GroupInfo::new(name, client_id, members.into_iter().collect());
+                info.sign(identity);
+                state.groups.insert(info.id, Group::new(info.clone()));
+                update_gui(outgoing, &state);
+                let update = ControlMessage::GroupUpdate(info.clone());
+                gossip(&state, info.members.iter(), None, update, outgoing);
+                write_later(writes, move |database| database.save_group(&info));
+            }
+            GUICommand::SendGroupMessage(group_id, message_content) => {
+                let mut state = independent_state.write().await;
+                let client_id = state.connection_details.id.clone();
+                let Some(group) = state
+                    .groups
+                    .get_mut(&group_id)
+                    .filter(|group| group.is_member(&client_id))
+                else {
+                    return Ok(());
+                };
+                let message = ChaosMessage::new(client_id.clone(), message_content);
+                group.history.add_message(message.clone());
+                update_gui(outgoing, &state);
+                //Members we can't reach yet get it once we are connected again.
+                for remote_id in state.groups[&group_id].info.others(&client_id) {
+                    if is_reachable(&state, remote_id, outgoing) {
+                        outgoing.push(Command::Peer(PeerCommand::SendGroupMessage(
+                            remote_id.clone(),
+                            group_id,
+                            message.clone(),
+                        )));
+                    }
+                }
+                write_later(writes, move |database| {
+                    database.add_group_message(&group_id, &message)
+                });
+            }
+            GUICommand::LoadGroupHistory(group_id) => {
+                let state = independent_state.read().await;
+                let Some(history_cursor) = state
+                    .groups
+                    .get(&group_id)
+                    .and_then(|group| group.history.history_cursor())
+                else {
+                    return Ok(());
+                };
+                drop(state);
+                let (messages, next_cursor) = query(database, move |database| {
+                    database.group_messages_before(&group_id, Some(history_cursor))
+                })
+                .await?;
+                let mut state = independent_state.write().await;
+                let Some(group) = state
+                    .groups
+                    .get_mut(&group_id)
+                    .filter(|group| group.history.history_cursor() == Some(history_cursor))
+                else {
+                    return Ok(());
+                };
+                group.history.prepend_messages(messages, next_cursor);
+                update_gui(outgoing, &state);
+            }
+            GUICommand::AddGroupMember(group_id, remote_id) => {
+                let mut state = independent_state.write().await;
+                change_members(
+                    &mut state,
+                    identity,
+                    &group_id,
+                    outgoing,
+                    writes,
+                    |members| members.insert(remote_id),
+                );
+            }
+            GUICommand::RemoveGroupMember(group_id, remote_id) => {
+                let mut state = independent_state.write().await;
+                change_members(
+                    &mut state,
+                    identity,
+                    &group_id,
+                    outgoing,
+                    writes,
+                    |members| members.remove(&remote_id),
+                );
+            }
+            GUICommand::CreateServer(name, members) => {
+                let mut state = independent_state.write().await;
+                let client_id = state.connection_details.id.clone();
+                let mut info = ServerInfo::new(name, client_id, members);
+                info.sign(identity);
+                state.servers.insert(info.id, Server::new(info.clone()));
+                update_gui(outgoing, &state);
+                let update = ControlMessage::ServerUpdate(info.clone());
+                gossip(&state, info.members.keys(), None, update, outgoing);
+                write_later(writes, move |database| database.save_server(&info));
+            }
+            GUICommand::EditServer(server_id, edit) => {
+                let mut state = independent_state.write().await;
+                let client_id = state.connection_details.id.clone();
+                let Some(server) = state
+                    .servers
+                    .get_mut(&server_id)
+                    .filter(|server| server.is_member(&client_id))
+                else {
+                    return Ok(());
+                };
+                let Some(mut info) = server.info.edited(edit, client_id) else {
+                    return Ok(());
+                };
+                info.sign(identity);
+                if !server.info.allows(&info) {
+                    return Err(ChaosError::NotAllowed(server.info.name.clone()));
+                }
+                let old_info = std::mem::replace(&mut server.info, info.clone());
+                update_gui(outgoing, &state);
+                let recipients = info.members.keys().chain(old_info.members.keys());
+                let update = ControlMessage::ServerUpdate(info.clone());
+                gossip(&state, recipients, None, update, outgoing);
+                write_later(writes, move |database| database.save_server(&info));
+            }
+            GUICommand::SendChannelMessage(server_id, channel_id, message_content) => {
+                let mut state = independent_state.write().await;
+                let client_id = state.connection_details.id.clone();
+                let Some(server) = state.servers.get_mut(&server_id).filter(|server| {
+                    server.is_member(&client_id) && server.info.channel(&channel_id).is_some()
+                }) else {
+                    return Ok(());
+                };
+                let message = ChaosMessage::new(client_id.clone(), message_content);
+                server.history_mut(channel_id).add_message(message.clone());
+                update_gui(outgoing, &state);
+                for remote_id in state.servers[&server_id].info.others(&client_id) {
+                    if is_reachable(&state, remote_id, outgoing) {
+                        outgoing.push(Command::Peer(PeerCommand::SendChannelMessage(
+                            remote_id.clone(),
+                            server_id,
+                            channel_id,
+                            message.clone(),
+                        )));
+                    }
+                }
+                write_later(writes, move |database| {
+                    database.add_channel_message(&server_id, &channel_id, &message)
+                });
+            }
+            GUICommand::LoadChannelHistory(server_id, channel_id) => {
+                let mut state = independent_state.write().await;
+                let Some(history) = state
+                    .servers
+                    .get_mut(&server_id)
+                    .map(|server| server.history_mut(channel_id))
+                else {
+                    return Ok(());
+                };
+                let Some(history_cursor) = history.history_cursor() else {
+                    return Ok(());
+                };
+                drop(state);
+                let (messages, next_cursor) = query(database, move |database| {
+                    database.channel_messages_before(&channel_id, Some(history_cursor))
+                })
+                .await?;
+                let mut state = independent_state.write().await;
+                let Some(history) = state
+                    .servers
+                    .get_mut(&server_id)
+                    .map(|server| server.history_mut(channel_id))
+                    .filter(|history| history.history_cursor() == Some(history_cursor))
+                else {
+                    return Ok(());
+                };
+                history.prepend_messages(messages, next_cursor);
+                update_gui(outgoing, &state);
+            }
+            GUICommand::SaveContact(remote_id, display_name, notes) => {
+                let mut state = independent_state.write().await;
+                let contact = state
+                    .contacts
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Contact::new(remote_id.clone()));
+                contact.display_name = display_name;
+                contact.notes = notes;
+                let contact = contact.clone();
+                update_gui(outgoing, &state);
+                write_later(writes, move |database| database.save_contact(&contact));
+            }
+            GUICommand::RemoveContact(remote_id) => {
+                let mut state = independent_state.write().await;
+                if state.contacts.remove(&remote_id).is_none() {
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                write_later(writes, move |database| database.remove_contact(&remote_id));
+            }
+            GUICommand::SendFriendRequest(remote_id) => {
+                let mut state = independent_state.write().await;
+                if remote_id == state.connection_details.id {
+                    return Ok(());
+                }
+                let public_key = state.connection_details.public_key.clone();
+                let contact = state
+                    .contacts
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Contact::new(remote_id.clone()));
+                use FriendStatus::*;
+                match contact.friend_status {
+                    Friends => return Ok(()),
+                    //They asked first, so asking back accepts.
+                    RequestReceived => {
+                        let remote_key = contact.public_key.clone();
+                        let answer = WSCommand::FriendAnswer(remote_id.clone(), true, public_key);
+                        outgoing.push(Command::WS(answer));
+                        befriend(&mut state, writes, &remote_id, remote_key);
+                    }
+                    Stranger | RequestSent => {
+                        contact.friend_status = RequestSent;
+                        contact.blocked = false;
+                        let contact = contact.clone();
+                        write_later(writes, move |database| database.save_contact(&contact));
+                        let request = WSCommand::FriendRequest(remote_id, public_key);
+                        outgoing.push(Command::WS(request));
+                    }
+                }
+                update_gui(outgoing, &state);
+            }
+            GUICommand::AnswerFriendRequest(remote_id, accepted) => {
+                let mut state = independent_state.write().await;
+                let public_key = state.connection_details.public_key.clone();
+                let Some(contact) = state
+                    .contacts
+                    .get_mut(&remote_id)
+                    .filter(|contact| contact.friend_status == FriendStatus::RequestReceived)
+                else {
+                    println!("No friend request from {} to answer.", remote_id);
+                    return Ok(());
+                };
+                if accepted {
+                    let remote_key = contact.public_key.clone();
+                    let answer = WSCommand::FriendAnswer(remote_id.clone(), true, public_key);
+                    outgoing.push(Command::WS(answer));
+                    befriend(&mut state, writes, &remote_id, remote_key);
+                } else {
+                    contact.friend_status = FriendStatus::Stranger;
+                    let contact = contact.clone();
+                    write_later(writes, move |database| database.save_contact(&contact));
+                    let answer = WSCommand::FriendAnswer(remote_id, false, String::new());
+                    outgoing.push(Command::WS(answer));
+                }
+                update_gui(outgoing, &state);
+            }
+            GUICommand::SetBlocked(remote_id, blocked) => {
+                let mut state = independent_state.write().await;
+                let contact = state
+                    .contacts
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Contact::new(remote_id.clone()));
+                contact.blocked = blocked;
+                //Blocking turns down whatever they are asking for right now.
+                if blocked && contact.friend_status == FriendStatus::RequestReceived {
+                    contact.friend_status = FriendStatus::Stranger;
+                    let answer = WSCommand::FriendAnswer(remote_id.clone(), false, String::new());
+                    outgoing.push(Command::WS(answer));
+                }
+                let contact = contact.clone();
+                write_later(writes, move |database| database.save_contact(&contact));
+                if blocked {
+                    if let Some(call_id) = advance_call(
+                        &mut state,
+                        &remote_id,
+                        None,
+                        ConnectionProgress::CallRequestReceived,
+                        ConnectionProgress::Closed,
+                    ) {
+                        outgoing.push(Command::WS(WSCommand::CallAnswer(
+                            remote_id.clone(),
+                            call_id,
+                            false,
+                            None,
+                        )));
+                    }
+                    //Any other call with them ends here, along with the connection it set up.
+                    if let Some(connection) = state
+                        .connections
+                        .get_mut(&remote_id)
+                        .filter(|connection| !connection.is_idle())
+                    {
+                        connection.set_progress(ConnectionProgress::Closed);
+                        outgoing.push(Command::Peer(PeerCommand::CloseConnection(remote_id)));
+                    }
+                }
+                update_gui(outgoing, &state);
+            }
+            _ => {}
+        },
+        Command::State(state_command) => match state_command {
+            StateCommand::SetClientId(client_id) => {
+                //Back on the signaling server, find out who we can deliver the queue to and which
+                //group and server members we can connect to.
+                let queued_remotes = query(database, |database| database.queued_remotes()).await?;
+                let mut state = independent_state.write().await;
+                state.connection_details.id = client_id;
+                update_gui(outgoing, &state);
+                let mut remote_ids = queued_remotes.into_iter().collect::<BTreeSet<UserId>>();
+                remote_ids.extend(state.co_members());
+                //Friend requests that went unanswered while either of us was away go out again.
+                for remote_id in state.pending_friends() {
+                    let public_key = state.connection_details.public_key.clone();
+                    let request = WSCommand::FriendRequest(remote_id.clone(), public_key);
+                    outgoing.push(Command::WS(request));
+                }
+                for remote_id in remote_ids {
+                    let idle = state
+                        .connections
+                        .get(&remote_id)
+                        .is_none_or(Connection::is_idle);
+                    if idle {
+                        outgoing.push(Command::WS(WSCommand::WatchOnline(remote_id)));
+                    }
+                }
+            }
+            StateCommand::SetSignalingStatus(signaling_status) => {
+                let mut state = independent_state.write().await;
+                state.signaling_status = signaling_status;
+                update_gui(outgoing, &state);
+            }
+            StateCommand::SetProgress(remote_id, progress) => {
+                println!("Connection Progress Updated: {:?}", progress);
+                //In order, the remote drops whatever it already got.
+                let queued = if progress == ConnectionProgress::Established {
+                    let query_remote_id = remote_id.clone();
+                    query(database, move |database| database.queued(&query_remote_id)).await?
+                } else {
+                    Vec::new()
+                };
+                let mut state = independent_state.write().await;
+                let connection = state
+                    .connections
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Connection::new(remote_id.clone()));
+                let call_ended = progress != ConnectionProgress::Established
+                    && connection.voice != VoiceStatus::Idle;
+                connection.set_progress(progress);
+                update_gui(outgoing, &state);
+                if call_ended {
+                    outgoing.push(Command::Peer(PeerCommand::StopAudio(remote_id.clone())));
+                }
+                if progress != ConnectionProgress::Established {
+                    return Ok(());
+                }
+                for message in queued {
+                    outgoing.push(Command::Peer(PeerCommand::SendMessage(
+                        remote_id.clone(),
+                        message,
+                    )));
+                }
+                //The receiver picks interrupted files up where they stopped.
+                let Some(connection) = state.connections.get_mut(&remote_id) else {
+                    return Ok(());
+                };
+                for transfer in connection.transfers().to_vec() {
+                    if transfer.incoming && transfer.status == TransferStatus::Interrupted {
+                        outgoing.push(Command::Peer(PeerCommand::ReceiveFile(
+                            remote_id.clone(),
+                            transfer,
+                        )));
+                    }
+                }
+                //Catch each other up on the groups and servers we share and on what we said in them
+                //while apart, the remote drops the messages it already has.
+                let client_id = &state.connection_details.id;
+                for group in state.shared_groups(&remote_id) {
+                    let update = ControlMessage::GroupUpdate(group.info.clone());
+                    outgoing.push(Command::Peer(PeerCommand::SendControl(
+                        remote_id.clone(),
+                        update,
+                    )));
+                    for message in group.history.messages() {
+                        if &message.client_id == client_id {
+                            outgoing.push(Command::Peer(PeerCommand::SendGroupMessage(
+                                remote_id.clone(),
+                                group.info.id,
+                                message.clone(),
+                            )));
+                        }
+                    }
+                }
+                for server in state.shared_servers(&remote_id) {
+                    let update = ControlMessage::ServerUpdate(server.info.clone());
+                    outgoing.push(Command::Peer(PeerCommand::SendControl(
+                        remote_id.clone(),
+                        update,
+                    )));
+                    for channel in &server.info.channels {
+                        let Some(history) = server.history(&channel.id) else {
+                            continue;
+                        };
+                        for message in history.messages() {
+                            if &message.client_id == client_id {
+                                outgoing.push(Command::Peer(PeerCommand::SendChannelMessage(
+                                    remote_id.clone(),
+                                    server.info.id,
+                                    channel.id,
+                                    message.clone(),
+                                )));
+                            }
+                        }
+                    }
+                }
+            }
+            StateCommand::SetRemoteKey(remote_id, public_key) => {
+                let mut state = independent_state.write().await;
+                state
+                    .connections
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Connection::new(remote_id.clone()))
+                    .set_remote_key(public_key.clone());
+                update_gui(outgoing, &state);
+                write_later(writes, move |database| {
+                    database.save_connection(&remote_id, &public_key)
+                });
+            }
+            StateCommand::AddMessage(remote_id, message) => {
+                //Remotes only write as themselves.
+                if message.client_id != remote_id {
+                    println!(
+                        "Dropping a message from {} sent as someone else.",
+                        remote_id
+                    );
+                    return Ok(());
+                }
+                if independent_state.read().await.is_blocked(&remote_id) {
+                    println!("Dropping a message from blocked {}.", remote_id);
+                    return Ok(());
+                }
+                let message = ChaosMessage {
+                    status: MessageStatus::Delivered,
+                    ..message
+                };
+                let (query_remote_id, query_message) = (remote_id.clone(), message.clone());
+                let added = query(database, move |database| {
+                    database.add_message(&query_remote_id, &query_message)
+                })
+                .await?;
+                let mut state = independent_state.write().await;
+                let connection = state
+                    .connections
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Connection::new(remote_id.clone()));
+                //Sending the message is what they were typing for.
+                connection.set_remote_typing(false);
+                //A message sent again only needs acking again.
+                if added {
+                    connection.add_message(message.clone());
+                }
+                update_gui(outgoing, &state);
+                let ack = ControlMessage::Ack(message.id, MessageStatus::Delivered);
+                outgoing.push(Command::Peer(PeerCommand::SendControl(remote_id, ack)));
+            }
+            StateCommand::SetRemoteTyping(remote_id, typing) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state.connections.get_mut(&remote_id) else {
+                    return Ok(());
+                };
+                connection.set_remote_typing(typing);
+                update_gui(outgoing, &state);
+            }
+            StateCommand::SetMessageStatus(remote_id, message_id, status) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state.connections.get_mut(&remote_id) else {
+                    return Ok(());
+                };
+                if !connection.set_message_status(&message_id, status) {
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                write_later(writes, move |database| {
+                    database.set_message_status(&remote_id, &message_id, status)
+                });
+            }
+            StateCommand::AddTransfer(remote_id, transfer) => {
+                let mut state = independent_state.write().await;
+                if transfer.incoming && state.is_blocked(&remote_id) {
+                    return Ok(());
+                }
+                let Some(connection) = state.connections.get_mut(&remote_id) else {
+                    return Ok(());
+                };
+                connection.add_transfer(transfer);
+                update_gui(outgoing, &state);
+            }
+            StateCommand::FileAccepted(remote_id, transfer_id, offset) => {
+                let mut state = independent_state.write().await;
+                let Some(transfer) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .and_then(|connection| connection.transfer_mut(&transfer_id))
+                    .filter(|transfer| !transfer.incoming)
+                else {
+                    return Ok(());
+                };
+                transfer.status = TransferStatus::Transferring;
+                transfer.transferred = offset;
+                let transfer = transfer.clone();
+                update_gui(outgoing, &state);
+                outgoing.push(Command::Peer(PeerCommand::SendFile(
+                    remote_id, transfer, offset,
+                )));
+            }
+            StateCommand::SetTransferProgress(remote_id, transfer_id, transferred) => {
+                let mut state = independent_state.write().await;
+                let Some(transfer) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .and_then(|connection| connection.transfer_mut(&transfer_id))
+                else {
+                    return Ok(());
+                };
+                transfer.transferred = transferred;
+                update_gui(outgoing, &state);
+            }
+            StateCommand::SetTransferStatus(remote_id, transfer_id, status) => {
+                let mut state = independent_state.write().await;
+                let Some(transfer) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .and_then(|connection| connection.transfer_mut(&transfer_id))
+                else {
+                    return Ok(());
+                };
+                transfer.status = status;
+                if status == TransferStatus::Completed {
+                    transfer.transferred = transfer.offer.size;
+                }
+                update_gui(outgoing, &state);
+            }
+            StateCommand::SetRemoteVoice(remote_id, voice) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .filter(|connection| connection.progress == ConnectionProgress::Established)
+                else {
+                    return Ok(());
+                };
+                use VoiceStatus::*;
+                match (connection.voice, voice) {
+                    (Idle, Ringing) => connection.voice = Ringing,
+                    //We rang each other at the same time, so both of us want the call.
+                    (Calling, Ringing) => {
+                        connection.voice = Active;
+                        let accept = ControlMessage::VoiceAccept;
+                        outgoing.push(Command::Peer(PeerCommand::SendControl(
+                            remote_id.clone(),
+                            accept,
+                        )));
+                        outgoing.push(Command::Peer(PeerCommand::StartAudio(remote_id)));
+                    }
+                    (Calling, Active) => {
+                        connection.voice = Active;
+                        outgoing.push(Command::Peer(PeerCommand::StartAudio(remote_id)));
+                    }
+                    (_, Idle) if connection.end_voice() => {
+                        outgoing.push(Command::Peer(PeerCommand::StopAudio(remote_id)));
+                    }
+                    _ => return Ok(()),
+                }
+                update_gui(outgoing, &state);
+            }
+            StateCommand::AddGroupMessage(remote_id, group_id, message) => {
+                let state = independent_state.read().await;
+                let client_id = &state.connection_details.id;
+                //Only members write to a group, and only as themselves.
+                let allowed = !state.is_blocked(&remote_id)
+                    && message.client_id == remote_id
+                    && state.groups.get(&group_id).is_some_and(|group| {
+                        group.is_member(client_id) && group.is_member(&remote_id)
+                    });
+                if !allowed {
+                    println!(
+                        "Dropping a message to group {} from {}.",
+                        group_id, remote_id
+                    );
+                    return Ok(());
+                }
+                drop(state);
+                let message = ChaosMessage {
+                    status: MessageStatus::Delivered,
+                    ..message
+                };
+                let query_message = message.clone();
+                let added = query(database, move |database| {
+                    database.add_group_message(&group_id, &query_message)
+                })
+                .await?;
+                let mut state = independent_state.write().await;
+                let Some(group) = state.groups.get_mut(&group_id).filter(|_| added) else {
+                    return Ok(());
+                };
+                group.history.add_message(message);
+                update_gui(outgoing, &state);
+            }
+            StateCommand::SetGroupMessageStatus(group_id, message_id, status) => {
+                let mut state = independent_state.write().await;
+                let Some(group) = state.groups.get_mut(&group_id) else {
+                    return Ok(());
+                };
+                if !group.history.set_message_status(&message_id, status) {
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                write_later(writes, move |database| {
+                    database.set_group_message_status(&group_id, &message_id, status)
+                });
+            }
+            StateCommand::UpdateGroup(remote_id, info) => {
+                let mut state = independent_state.write().await;
+                let client_id = state.connection_details.id.clone();
+                let old_members = match state.groups.get(&info.id) {
+                    //Members that missed changes catch up, removed ones find out they are out.
+                    Some(group) if group.info.supersedes(&info) => {
+                        if info.members.contains(&remote_id) {
+                            let update = ControlMessage::GroupUpdate(group.info.clone());
+                            outgoing
+                                .push(Command::Peer(PeerCommand::SendControl(remote_id, update)));
+                        }
+                        return Ok(());
+                    }
+                    //Only members pass changes on, and only the ones members signed.
+                    Some(group)
+                        if !info.supersedes(&group.info)
+                            || !group.is_member(&remote_id)
+                            || !group.info.allows(&info) =>
+                    {
+                        return Ok(());
+                    }
+                    Some(group) => group.info.members.clone(),
+                    //Members can add us to groups we don't know yet.
+                    None if info.members.contains(&client_id)
+                        && info.members.contains(&remote_id)
+                        && info.is_signed() =>
+                    {
+                        BTreeSet::new()
+                    }
+                    None => return Ok(()),
+                };
+                state
+                    .groups
+                    .entry(info.id)
+                    .or_insert_with(|| Group::new(info.clone()))
+                    .info = info.clone();
+                update_gui(outgoing, &state);
+                let recipients = info.members.union(&old_members);
+                let update = ControlMessage::GroupUpdate(info.clone());
+                gossip(&state, recipients, Some(&remote_id), update, outgoing);
+                write_later(writes, move |database| database.save_group(&info));
+            }
+            StateCommand::AddChannelMessage(remote_id, server_id, channel_id, message) => {
+                let state = independent_state.read().await;
+                let client_id = &state.connection_details.id;
+                let allowed = !state.is_blocked(&remote_id)
+                    && message.client_id == remote_id
+                    && state.servers.get(&server_id).is_some_and(|server| {
+                        server.is_member(client_id)
+                            && server.is_member(&remote_id)
+                            && server.info.channel(&channel_id).is_some()
+                    });
+                if !allowed {
+                    println!(
+                        "Dropping a message to server {} from {}.",
+                        server_id, remote_id
+                    );
+                    return Ok(());
+                }
+                drop(state);
+                let message = ChaosMessage {
+                    status: MessageStatus::Delivered,
+                    ..message
+                };
+                let query_message = message.clone();
+                let added = query(database, move |database| {
+                    database.add_channel_message(&server_id, &channel_id, &query_message)
+                })
+                .await?;
+                let mut state = independent_state.write().await;
+                let Some(server) = state.servers.get_mut(&server_id).filter(|_| added) else {
+                    return Ok(());
+                };
+                server.history_mut(channel_id).add_message(message);
+                update_gui(outgoing, &state);
+            }
+            StateCommand::SetChannelMessageStatus(server_id, channel_id, message_id, status) => {
+                let mut state = independent_state.write().await;
+                let Some(server) = state.servers.get_mut(&server_id) else {
+                    return Ok(());
+                };
+                if !server
+                    .history_mut(channel_id)
+                    .set_message_status(&message_id, status)
+                {
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                write_later(writes, move |database| {
+                    database.set_channel_message_status(&channel_id, &message_id, status)
+                });
+            }
+            StateCommand::UpdateServer(remote_id, info) => {
+                let mut state = independent_state.write().await;
+                let client_id = state.connection_details.id.clone();
+                let old_members = match state.servers.get(&info.id) {
+                    Some(server) if server.info.supersedes(&info) => {
+                        if info.is_member(&remote_id) {
+                            let update = ControlMessage::ServerUpdate(server.info.clone());
+                            outgoing
+                                .push(Command::Peer(PeerCommand::SendControl(remote_id, update)));
+                        }
+                        return Ok(());
+                    }
+                    //Members only pass on what the author was allowed to change.
+                    Some(server)
+                        if !info.supersedes(&server.info)
+                            || !server.is_member(&remote_id)
+                            || !server.info.allows(&info) =>
+                    {
+                        return Ok(());
+                    }
+                    Some(server) => server.info.members.keys().cloned().collect(),
+                    //Members can add us to servers we don't know yet.
+                    None if info.is_member(&client_id)
+                        && info.is_member(&remote_id)
+                        && info.is_signed() =>
+                    {
+                        BTreeSet::new()
+                    }
+                    None => return Ok(()),
+                };
+                state
+                    .servers
+                    .entry(info.id)
+                    .or_insert_with(|| Server::new(info.clone()))
+                    .info = info.clone();
+                update_gui(outgoing, &state);
+                let recipients = info.members.keys().chain(&old_members);
+                let update = ControlMessage::ServerUpdate(info.clone());
+                gossip(&state, recipients, Some(&remote_id), update, outgoing);
+                write_later(writes, move |database| database.save_server(&info));
+            }
+        },
+        Command::WS(ws_command) => match ws_command {
+            WSCommand::CallRequest(remote_id, call_id) => {
+                let mut state = independent_state.write().await;
+                if state.is_blocked(&remote_id) {
+                    println!("Turning down a call from blocked {}.", remote_id);
+                    outgoing.push(Command::WS(WSCommand::CallAnswer(
+                        remote_id, call_id, false, None,
+                    )));
+                    return Ok(());
+                }
+                let keep_own_call = state.connection_details.id < remote_id;
+                let is_friend = state.is_friend(&remote_id);
+                let connection = state
+                    .connections
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Connection::new(remote_id.clone()));
+                //When we call each other at the same time, the call from the lower id goes on.
+                if connection.progress == ConnectionProgress::CallRequestSent && keep_own_call {
+                    println!("Keeping our own call to {}.", remote_id);
+                    return Ok(());
+                }
+                connection.call_id = Some(call_id);
+                //Friends get through, anyone else needs the user's permission.
+                if !is_friend {
+                    connection.set_progress(ConnectionProgress::CallRequestReceived);
+                    update_gui(outgoing, &state);
+                    return Ok(());
+                }
+                connection.set_progress(ConnectionProgress::CallRequestAccepted);
+                update_gui(outgoing, &state);
+                outgoing.push(Command::Peer(PeerCommand::NewPeerConnection(remote_id)));
+            }
+            WSCommand::CallRequestFailure(remote_id, call_id) => {
+                println!("{} could not be reached.", remote_id);
+                let queued = has_queued(database, &remote_id).await?;
+                let mut state = independent_state.write().await;
+                let Some(connection) = state.connections.get_mut(&remote_id) else {
+                    return Ok(());
+                };
+                if connection.call_id.as_ref() != Some(&call_id) {
+                    return Ok(());
+                }
+                connection.set_progress(ConnectionProgress::Closed);
+                update_gui(outgoing, &state);
+                if wants_connection(&state, queued, &remote_id) {
+                    outgoing.push(Command::WS(WSCommand::WatchOnline(remote_id)));
+                }
+            }
+            WSCommand::Online(remote_id) => {
+                let queued = has_queued(database, &remote_id).await?;
+                let mut state = independent_state.write().await;
+                if state
+                    .pending_friends()
+                    .any(|pending_id| *pending_id == remote_id)
+                {
+                    let public_key = state.connection_details.public_key.clone();
+                    let request = WSCommand::FriendRequest(remote_id.clone(), public_key);
+                    outgoing.push(Command::WS(request));
+                }
+                let idle = state
+                    .connections
+                    .get(&remote_id)
+                    .is_none_or(Connection::is_idle);
+                if !idle {
+                    return Ok(());
+                }
+                if wants_connection(&state, queued, &remote_id) {
+                    start_call(&mut state, remote_id, outgoing);
+                }
+            }
+            WSCommand::CallAnswer(remote_id, call_id, accepted, remote_sdp) => {
+                let mut state = independent_state.write().await;
+                let remote_sdp = remote_sdp.filter(|_| accepted);
+                let progress = match remote_sdp {
+                    Some(_) => ConnectionProgress::CallAnswerReceived,
+                    None => ConnectionProgress::Closed,
+                };
+                if advance_call(
+                    &mut state,
+                    &remote_id,
+                    Some(&call_id),
+                    ConnectionProgress::CallRequestSent,
+                    progress,
+                )
+                .is_none()
+                {
+                    println!("Unexpected call answer from {}.", remote_id);
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                if let Some(remote_sdp) = remote_sdp {
+                    outgoing.push(Command::Peer(PeerCommand::EstablishConnection(
+                        remote_id, remote_sdp, false,
+                    )));
+                }
+            }
+            WSCommand::CallReply(remote_id, call_id, remote_sdp) => {
+                let mut state = independent_state.write().await;
+                if advance_call(
+                    &mut state,
+                    &remote_id,
+                    Some(&call_id),
+                    ConnectionProgress::CallAnswerSent,
+                    ConnectionProgress::CallReplyReceived,
+                )
+                .is_none()
+                {
+                    println!("Unexpected call reply from {}.", remote_id);
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                outgoing.push(Command::Peer(PeerCommand::EstablishConnection(
+                    remote_id, remote_sdp, true,
+                )));
+            }
+            WSCommand::IceCandidate(remote_id, call_id, candidate) => {
+                let state = independent_state.read().await;
+                let current_call_id = state
+                    .connections
+                    .get(&remote_id)
+                    .and_then(|connection| connection.call_id.as_ref());
+                if current_call_id != Some(&call_id) {
+                    return Ok(());
+                }
+                outgoing.push(Command::Peer(PeerCommand::AddIceCandidate(
+                    remote_id, candidate,
+                )));
+            }
+            WSCommand::FriendRequest(remote_id, remote_key) => {
+                let mut state = independent_state.write().await;
+                check_key(&remote_id, &remote_key)?;
+                let public_key = state.connection_details.public_key.clone();
+                if state.is_blocked(&remote_id) {
+                    println!("Turning down a friend request from blocked {}.", remote_id);
+                    let answer = WSCommand::FriendAnswer(remote_id, false, String::new());
+                    outgoing.push(Command::WS(answer));
+                    return Ok(());
+                }
+                let contact = state
+                    .contacts
+                    .entry(remote_id.clone())
+                    .or_insert_with(|| Contact::new(remote_id.clone()));
+                use FriendStatus::*;
+                match contact.friend_status {
+                    //Either we asked them too, or our answer got lost on the way.
+                    RequestSent | Friends => {
+                        let answer = WSCommand::FriendAnswer(remote_id.clone(), true, public_key);
+                        outgoing.push(Command::WS(answer));
+                        befriend(&mut state, writes, &remote_id, remote_key);
+                    }
+                    Stranger | RequestReceived => {
+                        contact.friend_status = RequestReceived;
+                        contact.public_key = remote_key;
+                        let contact = contact.clone();
+                        write_later(writes, move |database| database.save_contact(&contact));
+                    }
+                }
+                update_gui(outgoing, &state);
+            }
+            WSCommand::FriendAnswer(remote_id, accepted, remote_key) => {
+                let mut state = independent_state.write().await;
+                let Some(contact) = state
+                    .contacts
+                    .get_mut(&remote_id)
+                    .filter(|contact| contact.friend_status == FriendStatus::RequestSent)
+                else {
+                    println!("Unexpected friend answer from {}.", remote_id);
+                    return Ok(());
+                };
+                if accepted {
+                    check_key(&remote_id, &remote_key)?;
+                    befriend(&mut state, writes, &remote_id, remote_key);
+                } else {
+                    contact.friend_status = FriendStatus::Stranger;
+                    let contact = contact.clone();
+                    write_later(writes, move |database| database.save_contact(&contact));
+                }
+                update_gui(outgoing, &state);
+            }
+            //They are offline, the request goes out again once they are back.
+            WSCommand::FriendRequestFailure(remote_id) => {
+                let state = independent_state.read().await;
+                if state
+                    .pending_friends()
+                    .any(|pending_id| *pending_id == remote_id)
+                {
+                    outgoing.push(Command::WS(WSCommand::WatchOnline(remote_id)));
+                }
+            }
+            _ => {
+                println!("Not implemented yet.");
+            }
+        },
+        Command::Peer(peer_command) => match peer_command {
+            PeerCommand::CallAnswer(remote_id, local_sdp) => {
+                let mut state = independent_state.write().await;
+                let Some(call_id) = advance_call(
+                    &mut state,
+                    &remote_id,
+                    None,
+                    ConnectionProgress::CallRequestAccepted,
+                    ConnectionProgress::CallAnswerSent,
+                ) else {
+                    return Ok(());
+                };
+                update_gui(outgoing, &state);
+                outgoing.push(Command::WS(WSCommand::CallAnswer(
+                    remote_id,
+                    call_id,
+                    true,
+                    Some(local_sdp),
+                )));
+            }
+            PeerCommand::CallReply(remote_id, local_sdp) => {
+                let mut state = independent_state.write().await;
+                let Some(call_id) = advance_call(
+                    &mut state,
+                    &remote_id,
+                    None,
+                    ConnectionProgress::CallAnswerReceived,
+                    ConnectionProgress::CallReplySent,
+                ) else {
+                    return Ok(());
+                };
+                update_gui(outgoing, &state);
+                outgoing.push(Command::WS(WSCommand::CallReply(
+                    remote_id, call_id, local_sdp,
+                )));
+            }
+            PeerCommand::LocalIceCandidate(remote_id, candidate) => {
+                let state = independent_state.read().await;
+                let Some(call_id) = state
+                    .connections
+                    .get(&remote_id)
+                    .and_then(|connection| connection.call_id.clone())
+                else {
+                    return Ok(());
+                };
+                outgoing.push(Command::WS(WSCommand::IceCandidate(
+                    remote_id, call_id, candidate,
+                )));
+            }
+            _ => {
+                println!("Not implemented yet.");
+            }
+        },
+        Command::Media(media_command) => match media_command {
+            MediaCommand::StartCall(remote_id) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .filter(|connection| connection.progress == ConnectionProgress::Established)
+                else {
+                    return Err(ChaosError::NotConnected(remote_id));
+                };
+                if connection.voice != VoiceStatus::Idle {
+                    return Ok(());
+                }
+                connection.voice = VoiceStatus::Calling;
+                update_gui(outgoing, &state);
+                let ring = ControlMessage::VoiceRing;
+                outgoing.push(Command::Peer(PeerCommand::SendControl(remote_id, ring)));
+            }
+            MediaCommand::AnswerCall(remote_id, accepted) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .filter(|connection| connection.voice == VoiceStatus::Ringing)
+                else {
+                    return Ok(());
+                };
+                let control = if accepted {
+                    connection.voice = VoiceStatus::Active;
+                    ControlMessage::VoiceAccept
+                } else {
+                    connection.end_voice();
+                    ControlMessage::VoiceHangUp
+                };
+                update_gui(outgoing, &state);
+                outgoing.push(Command::Peer(PeerCommand::SendControl(
+                    remote_id.clone(),
+                    control,
+                )));
+                if accepted {
+                    outgoing.push(Command::Peer(PeerCommand::StartAudio(remote_id)));
+                }
+            }
+            MediaCommand::SetMuted(remote_id, muted) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state
+                    .connections
+                    .get_mut(&remote_id)
+                    .filter(|connection| connection.voice == VoiceStatus::Active)
+                else {
+                    return Ok(());
+                };
+                connection.muted = muted;
+                update_gui(outgoing, &state);
+                outgoing.push(Command::Peer(PeerCommand::MuteAudio(remote_id, muted)));
+            }
+            MediaCommand::HangUp(remote_id) => {
+                let mut state = independent_state.write().await;
+                let Some(connection) = state.connections.get_mut(&remote_id) else {
+                    return Ok(());
+                };
+                if !connection.end_voice() {
+                    return Ok(());
+                }
+                update_gui(outgoing, &state);
+                let hang_up = ControlMessage::VoiceHangUp;
+                outgoing.push(Command::Peer(PeerCommand::SendControl(
+                    remote_id.clone(),
+                    hang_up,
+                )));
+                outgoing.push(Command::Peer(PeerCommand::StopAudio(remote_id)));
+            }
+        },
+        //Failures reported by the other threads go the same way as our own.
+        Command::Error(error) => return Err(error),
+    }
+    Ok(())
+}
+//Sends to every thread subscribed to the command's topic, waiting a while if their queues are
+//full.
+async fn route(senders: &Senders, command: Command) -> Result<(), ChaosError> {
+    let topic = command.topic();
+    let subscribers = ROUTES
+        .iter()
+        .filter(|(route_topic, _)| *route_topic == topic)
+        .map(|(_, thread_type)| *thread_type)
+        .collect::<Vec<ThreadTypes>>();
+    if subscribers.is_empty() {
+        println!("Nothing subscribes to {:?}, dropping the command.", topic);
+    }
+    for thread_type in subscribers {
+        let thread_gone = || ChaosError::ThreadGone(format!("{:?}", thread_type));
+        let tx = senders.get(&thread_type).ok_or_else(thread_gone)?;
+        tx.send_timeout(command.clone(), ROUTE_TIMEOUT)
+            .await
+            .map_err(|e| match e {
+                SendTimeoutError::Timeout(_) => {
+                    ChaosError::ThreadBusy(format!("{:?}", thread_type), format!("{:?}", topic))
+                }
+                SendTimeoutError::Closed(_) => thread_gone(),
+            })?;
+    }
+    Ok(())
+}
+async fn notify(senders: &Senders, e: ChaosError) {
+    println!("{}", e);
+    let notify = Command::GUI(GUICommand::Notify(e.to_string()));
+    if route(senders, notify).await.is_err() {
+        println!("Could not notify the gui.");
+    }
+}
+//Calls remote_id, replacing whatever call we had with them.
+fn start_call(state: &mut IndependentState, remote_id: UserId, outgoing: &mut Outgoing) {
+    let call_id = new_call_id();
+    let connection = state
+        .connections
+        .entry(remote_id.clone())
+        .or_insert_with(|| Connection::new(remote_id.clone()));
+    connection.call_id = Some(call_id.clone());
+    connection.set_progress(ConnectionProgress::CallRequestSent);
+    update_gui(outgoing, state);
+    outgoing.push(Command::WS(WSCommand::CallRequest(remote_id, call_id)));
+}
+//Whether we have queued messages for remote_id or share a group or server with them.
+fn wants_connection(state: &IndependentState, queued: bool, remote_id: &UserId) -> bool {
+    if state.is_blocked(remote_id) {
+        return false;
+    }
+    queued
+        || state.shared_groups(remote_id).next().is_some()
+        || state.shared_servers(remote_id).next().is_some()
+}
+async fn has_queued(
+    database: &Arc<SyncMutex<Database>>,
+    remote_id: &UserId,
+) -> Result<bool, ChaosError> {
+    let remote_id = remote_id.clone();
+    let queued = query(database, move |database| database.queued(&remote_id)).await?;
+    Ok(!queued.is_empty())
+}
+//Whether we can send to remote_id right now, if not we call them once they are online.
+fn is_reachable(state: &IndependentState, remote_id: &UserId, outgoing: &mut Outgoing) -> bool {
+    let connection = state.connections.get(remote_id);
+    if connection.is_some_and(|connection| connection.progress == ConnectionProgress::Established) {
+        return true;
+    }
+    if connection.is_none_or(Connection::is_idle) {
+        outgoing.push(Command::WS(WSCommand::WatchOnline(remote_id.clone())));
+    }
+    false
+}
+//Tells the members of a group or server, and whoever was one before the change, how it looks
+//now. Those we can't reach hear about it once we are connected again.
+fn gossip<'a>(
+    state: &IndependentState,
+    recipients: impl IntoIterator<Item = &'a UserId>,
+    from: Option<&UserId>,
+    update: ControlMessage,
+    outgoing: &mut Outgoing,
+) {
+    let client_id = &state.connection_details.id;
+    let recipients = recipients.into_iter().collect::<BTreeSet<&UserId>>();
+    for remote_id in recipients {
+        if remote_id == client_id || Some(remote_id) == from {
+            continue;
+        }
+        if is_reachable(state, remote_id, outgoing) {
+            outgoing.push(Command::Peer(PeerCommand::SendControl(
+                remote_id.clone(),
+                update.clone(),
+            )));
+        }
+    }
+}
+//Changes who is in a group we are in, change returns whether there was anything to change.
+fn change_members(
+    state: &mut IndependentState,
+    identity: &Identity,
+    group_id: &GroupId,
+    outgoing: &mut Outgoing,
+    writes: &mut Writes,
+    change: impl FnOnce(&mut BTreeSet<UserId>) -> bool,
+) {
+    let client_id = state.connection_details.id.clone();
+    let Some(group) = state
+        .groups
+        .get_mut(group_id)
+        .filter(|group| group.is_member(&client_id))
+    else {
+        return;
+    };
+    let mut members = group.info.members.clone();
+    if !change(&mut members) {
+        return;
+    }
+    let mut info = group.info.with_members(members, client_id);
+    info.sign(identity);
+    let old_members = std::mem::replace(&mut group.info, info.clone()).members;
+    update_gui(outgoing, state);
+    let update = ControlMessage::GroupUpdate(info.clone());
+    gossip(
+        state,
+        info.members.union(&old_members),
+        None,
+        update,
+        outgoing,
+    );
+    write_later(writes, move |database| database.save_group(&info));
+}
+//Friends know each other's key, so their calls get through without asking, like those of anyone
+//we verified on an earlier call.
+fn befriend(
+    state: &mut IndependentState,
+    writes: &mut Writes,
+    remote_id: &UserId,
+    public_key: String,
+) {
+    let contact = state
+        .contacts
+        .entry(remote_id.clone())
+        .or_insert_with(|| Contact::new(remote_id.clone()));
+    contact.friend_status = FriendStatus::Friends;
+    contact.public_key = public_key.clone();
+    let contact = contact.clone();
+    state
+        .connections
+        .entry(remote_id.clone())
+        .or_insert_with(|| Connection::new(remote_id.clone()))
+        .set_remote_key(public_key.clone());
+    let remote_id = remote_id.clone();
+    write_later(writes, move |database| {
+        database.save_contact(&contact)?;
+        database.save_connection(&remote_id, &public_key)
+    });
+}
+//The signaling server vouches for the sender's id, the id vouches for the key.
+fn check_key(remote_id: &UserId, public_key: &str) -> Result<(), ChaosError> {
+    if crypto::user_id(public_key).ok().as_ref() == Some(remote_id) {
+        return Ok(());
+    }
+    let e = "Their public key does not match their id.".to_string();
+    Err(ChaosError::Handshake(remote_id.clone(), e))
+}
+fn update_gui(outgoing: &mut Outgoing, state: &IndependentState) {
+    outgoing.push(Command::GUI(GUICommand::UpdateState(state.clone())));
+}
+fn write_later<T>(
+    writes: &mut Writes,
+    write: impl FnOnce(&Database) -> anyhow::Result<T> + Send + 'static,
+) {
+    writes.push(Box::new(move |database| write(database).map(|_| ())));
+}
+//Sqlite blocks on the disk, so it runs off the runtime's workers.
+async fn query<T: Send + 'static>(
+    database: &Arc<SyncMutex<Database>>,
+    query: impl FnOnce(&Database) -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, ChaosError> {
+    let database = database.clone();
+    tokio::task::spawn_blocking(move || query(&lock_database(&database)))
+        .await
+        .map_err(|e| ChaosError::Database(e.to_string()))?
+        .map_err(|e| ChaosError::Database(e.to_string()))
+}
+//A panic while holding the database can't leave it half written, so keep using it.
+fn lock_database(database: &SyncMutex<Database>) -> MutexGuard<'_, Database> {
+    database.lock().unwrap_or_else(PoisonError::into_inner)
+}
+//For the other threads, the scheduler going away while they still run is not worth a panic.
+pub async fn send(tx: &Sender<Command>, command: Command) {
+    if let Err(e) = tx.send(command).await {
+        println!("Could not reach the scheduler: {}", e);
+    }
+}
+//Hands a failure to the scheduler, which shows it to the user.
+pub async fn report(tx: &Sender<Command>, error: ChaosError) {
+    send(tx, Command::Error(error)).await;
+}
+//Moves a call on to its next step, but only if it is the call we expect and where we expect it
+//to be. Returns the call id on success.
+fn advance_call(
+    state: &mut IndependentState,
+    remote_id: &UserId,
+    call_id: Option<&CallId>,
+    from: ConnectionProgress,
+    to: ConnectionProgress,
+) -> Option<CallId> {
+    let connection = state.connections.get_mut(remote_id)?;
+    let current_call_id = connection.call_id.clone()?;
+    if connection.progress != from || call_id.is_some_and(|call_id| *call_id != current_call_id) {
+        return None;
+    }
+    connection.set_progress(to);
+    Some(current_call_id)
+}
+fn new_call_id() -> CallId {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
 impl Attach for Scheduler {
     fn attach(&mut self, attachment: ChannelAttachment, thread: Option<ThreadTypes>) {
-        self.attachments.try_lock().unwrap().insert(
-            thread.expect("Scheduler attachment provided with no thread type"),
-            attachment,
+        let thread = thread.expect("Scheduler attachment provided with no thread type");
+        let (tx, rx) = attachment;
+        self.senders.insert(thread, tx);
+        self.receivers.push((thread, rx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    //The state after remote_id calls us, and what the scheduler sent out for it.
+    async fn call_request(
+        state: IndependentState,
+        remote_id: &UserId,
+    ) -> (IndependentState, Outgoing) {
+        let state = RwLock::new(state);
+        let database = Database::open(Path::new(":memory:")).unwrap();
+        let database = Arc::new(SyncMutex::new(database));
+        let (mut outgoing, mut writes) = (Outgoing::new(), Writes::new());
+        let command = WSCommand::CallRequest(remote_id.clone(), "call".to_string());
+        handle_command(
+            Command::WS(command),
+            &state,
+            &database,
+            &Identity::generate(),
+            &mut outgoing,
+            &mut writes,
+        )
+        .await
+        .unwrap();
+        (state.into_inner(), outgoing)
+    }
+
+    fn with_contact(remote_id: &UserId, change: impl FnOnce(&mut Contact)) -> IndependentState {
+        let mut state = IndependentState::default();
+        let mut contact = Contact::new(remote_id.clone());
+        change(&mut contact);
+        state.contacts.insert(remote_id.clone(), contact);
+        state
+    }
+
+    fn progress(state: &IndependentState, remote_id: &UserId) -> Option<ConnectionProgress> {
+        state
+            .connections
+            .get(remote_id)
+            .map(|connection| connection.progress)
+    }
+
+    fn connects(outgoing: &Outgoing) -> bool {
+        outgoing
+            .iter()
+            .any(|command| matches!(command, Command::Peer(PeerCommand::NewPeerConnection(_))))
+    }
+
+    #[tokio::test]
+    async fn friends_get_through() {
+        let remote_id = Identity::generate().user_id();
+        let state = with_contact(&remote_id, |contact| {
+            contact.friend_status = FriendStatus::Friends;
+        });
+        let (state, outgoing) = call_request(state, &remote_id).await;
+        assert_eq!(
+            progress(&state, &remote_id),
+            Some(ConnectionProgress::CallRequestAccepted)
         );
+        assert!(connects(&outgoing));
+    }
+
+    #[tokio::test]
+    async fn blocked_ids_are_turned_down() {
+        let remote_id = Identity::generate().user_id();
+        let state = with_contact(&remote_id, |contact| {
+            contact.friend_status = FriendStatus::Friends;
+            contact.blocked = true;
+        });
+        let (state, outgoing) = call_request(state, &remote_id).await;
+        assert_eq!(progress(&state, &remote_id), None);
+        let answer = WSCommand::CallAnswer(remote_id, "call".to_string(), false, None);
+        assert_eq!(outgoing, vec![Command::WS(answer)]);
+    }
+
+    #[tokio::test]
+    async fn others_are_asked_about() {
+        let remote_id = Identity::generate().user_id();
+        let known = Identity::generate();
+        let mut states = vec![
+            IndependentState::default(),
+            with_contact(&remote_id, |contact| {
+                contact.friend_status = FriendStatus::RequestSent;
+            }),
+        ];
+        //Having connected before is not enough.
+        let mut connected_before = IndependentState::default();
+        let mut connection = Connection::new(remote_id.clone());
+        connection.set_remote_key(known.public_key());
+        connected_before
+            .connections
+            .insert(remote_id.clone(), connection);
+        states.push(connected_before);
+        for state in states {
+            let (state, outgoing) = call_request(state, &remote_id).await;
+            assert_eq!(
+                progress(&state, &remote_id),
+                Some(ConnectionProgress::CallRequestReceived)
+            );
+            assert!(!connects(&outgoing));
+        }
     }
 }
@@ -1,15 +1,431 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
 use std::{collections::HashMap, sync::Arc};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::utils::crypto::{self, Identity};
 
 pub type UserId = String;
 pub type SDP = String;
+pub type CallId = String;
+pub type MessageId = Uuid;
+pub type TransferId = Uuid;
+pub type GroupId = Uuid;
+pub type ServerId = Uuid;
+pub type ChannelId = Uuid;
+pub type CategoryId = Uuid;
+pub type RoleId = Uuid;
 
 #[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct ChaosMessage {
+    pub id: MessageId,
     pub client_id: String,
     pub message_content: String,
+    pub timestamp: DateTime<Utc>,
+    pub status: MessageStatus,
+}
+impl ChaosMessage {
+    pub fn new(client_id: UserId, message_content: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            client_id,
+            message_content,
+            timestamp: Utc::now(),
+            status: MessageStatus::Pending,
+        }
+    }
+}
+//Messages we receive start out Delivered and become Read once the user has seen them.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum MessageStatus {
+    #[default]
+    Pending,
+    Sent,
+    Delivered,
+    Read,
+}
+//Travels next to chat messages but is never stored.
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub enum ControlMessage {
+    TypingStarted,
+    TypingStopped,
+    //The remote got, or read, one of our messages.
+    Ack(MessageId, MessageStatus),
+    FileOffer(FileOffer),
+    //Send the file from this offset on, the receiver has everything before it.
+    FileAccept(TransferId, u64),
+    FileCancel(TransferId),
+    //The receiver has the whole file and it matches the offer.
+    FileDone(TransferId),
+    VoiceRing,
+    VoiceAccept,
+    //Ends a call or turns down a ring.
+    VoiceHangUp,
+    //The sender's view of a group we share, passed on to the other members if it is news.
+    GroupUpdate(GroupInfo),
+    //Same for a server.
+    ServerUpdate(ServerInfo),
+}
+//Any member can change a group, the change with the higher version wins and equal versions go to
+//the higher user id. Concurrent changes can lose one another, which is fine for small teams.
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+pub struct GroupInfo {
+    pub id: GroupId,
+    pub name: String,
+    pub members: BTreeSet<UserId>,
+    pub version: u64,
+    pub changed_by: UserId,
+    #[serde(default)]
+    pub signed: RevisionSignature,
+}
+//The author's key and their signature over a group or server revision, so members can only pass
+//changes on, not make them in someone else's name.
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug, Default)]
+pub struct RevisionSignature {
+    pub public_key: String,
+    pub signature: String,
+}
+impl RevisionSignature {
+    //revision is the info with the signature left empty.
+    fn new(identity: &Identity, revision: &impl Serialize) -> Self {
+        let revision = serde_json::to_vec(revision).unwrap_or_default();
+        Self {
+            public_key: identity.public_key(),
+            signature: identity.sign_revision(&revision),
+        }
+    }
+    fn is_by(&self, author: &UserId, revision: &impl Serialize) -> bool {
+        serde_json::to_vec(revision)
+            .ok()
+            .and_then(|revision| {
+                crypto::verify_revision(&revision, &self.public_key, &self.signature).ok()
+            })
+            .as_ref()
+            == Some(author)
+    }
+}
+impl GroupInfo {
+    pub fn new(name: String, creator: UserId, mut members: BTreeSet<UserId>) -> Self {
+        members.insert(creator.clone());
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            members,
+            version: 0,
+            changed_by: creator,
+            signed: RevisionSignature::default(),
+        }
+    }
+    pub fn sign(&mut self, identity: &Identity) {
+        self.signed = RevisionSignature::new(identity, &self.unsigned());
+    }
+    //Whether changed_by really made this revision.
+    pub fn is_signed(&self) -> bool {
+        self.signed.is_by(&self.changed_by, &self.unsigned())
+    }
+    fn unsigned(&self) -> Self {
+        Self {
+            signed: RevisionSignature::default(),
+            ..self.clone()
+        }
+    }
+    pub fn supersedes(&self, other: &GroupInfo) -> bool {
+        (self.version, &self.changed_by) > (other.version, &other.changed_by)
+    }
+    //Whether newer was made and signed by someone in the group.
+    pub fn allows(&self, newer: &GroupInfo) -> bool {
+        self.members.contains(&newer.changed_by) && newer.is_signed()
+    }
+    //The next version of the group, with members changed by client_id.
+    pub fn with_members(&self, members: BTreeSet<UserId>, client_id: UserId) -> Self {
+        Self {
+            members,
+            version: self.version + 1,
+            changed_by: client_id,
+            signed: RevisionSignature::default(),
+            ..self.clone()
+        }
+    }
+    //Everyone in the group but client_id.
+    pub fn others<'a>(&'a self, client_id: &'a UserId) -> impl Iterator<Item = &'a UserId> {
+        self.members
+            .iter()
+            .filter(move |member| *member != client_id)
+    }
+}
+//What a role lets its members change about a server, the owner can change everything.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Serialize, Deserialize, Debug)]
+pub enum Permission {
+    //The server's name, its categories and channels.
+    ManageChannels,
+    //Roles and who has them.
+    ManageRoles,
+    //Who is on the server, anyone can leave on their own.
+    ManageMembers,
+}
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+pub struct Role {
+    pub id: RoleId,
+    pub name: String,
+    pub permissions: BTreeSet<Permission>,
+}
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+pub struct Category {
+    pub id: CategoryId,
+    pub name: String,
+}
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+pub struct TextChannel {
+    pub id: ChannelId,
+    pub name: String,
+    pub category: Option<CategoryId>,
+}
+//Travels between members like GroupInfo, but a change is only taken on if its author had the
+//permissions for it.
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+pub struct ServerInfo {
+    pub id: ServerId,
+    pub name: String,
+    pub owner: UserId,
+    //Every member and the roles they have.
+    pub members: BTreeMap<UserId, BTreeSet<RoleId>>,
+    pub roles: Vec<Role>,
+    pub categories: Vec<Category>,
+    //In the order they are listed.
+    pub channels: Vec<TextChannel>,
+    pub version: u64,
+    pub changed_by: UserId,
+    #[serde(default)]
+    pub signed: RevisionSignature,
+}
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub enum ServerEdit {
+    Rename(String),
+    AddCategory(String),
+    AddChannel(String, Option<CategoryId>),
+    RemoveChannel(ChannelId),
+    AddRole(String, BTreeSet<Permission>),
+    //Gives the member the role, or takes it away.
+    SetRole(UserId, RoleId, bool),
+    AddMember(UserId),
+    //Removing ourselves leaves the server.
+    RemoveMember(UserId),
+}
+impl ServerInfo {
+    //New servers start out with a general channel.
+    pub fn new(name: String, owner: UserId, members: impl IntoIterator<Item = UserId>) -> Self {
+        let mut members = members
+            .into_iter()
+            .map(|member| (member, BTreeSet::new()))
+            .collect::<BTreeMap<UserId, BTreeSet<RoleId>>>();
+        members.insert(owner.clone(), BTreeSet::new());
+        let category = Category {
+            id: Uuid::new_v4(),
+            name: "Text Channels".to_string(),
+        };
+        let channel = TextChannel {
+            id: Uuid::new_v4(),
+            name: "general".to_string(),
+            category: Some(category.id),
+        };
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            owner: owner.clone(),
+            members,
+            roles: Vec::new(),
+            categories: vec![category],
+            channels: vec![channel],
+            version: 0,
+            changed_by: owner,
+            signed: RevisionSignature::default(),
+        }
+    }
+    pub fn sign(&mut self, identity: &Identity) {
+        self.signed = RevisionSignature::new(identity, &self.unsigned());
+    }
+    //Whether changed_by really made this revision.
+    pub fn is_signed(&self) -> bool {
+        self.signed.is_by(&self.changed_by, &self.unsigned())
+    }
+    fn unsigned(&self) -> Self {
+        Self {
+            signed: RevisionSignature::default(),
+            ..self.clone()
+        }
+    }
+    pub fn supersedes(&self, other: &ServerInfo) -> bool {
+        (self.version, &self.changed_by) > (other.version, &other.changed_by)
+    }
+    pub fn is_member(&self, user_id: &UserId) -> bool {
+        self.members.contains_key(user_id)
+    }
+    //Everyone on the server but client_id.
+    pub fn others<'a>(&'a self, client_id: &'a UserId) -> impl Iterator<Item = &'a UserId> {
+        self.members
+            .keys()
+            .filter(move |member| *member != client_id)
+    }
+    pub fn channel(&self, channel_id: &ChannelId) -> Option<&TextChannel> {
+        self.channels
+            .iter()
+            .find(|channel| &channel.id == channel_id)
+    }
+    pub fn can(&self, user_id: &UserId, permission: Permission) -> bool {
+        let Some(role_ids) = self.members.get(user_id) else {
+            return false;
+        };
+        user_id == &self.owner
+            || self
+                .roles
+                .iter()
+                .any(|role| role_ids.contains(&role.id) && role.permissions.contains(&permission))
+    }
+    //The next version with client_id's edit made, None if it changes nothing. Whether they were
+    //allowed to make it is up to allows.
+    pub fn edited(&self, edit: ServerEdit, client_id: UserId) -> Option<Self> {
+        let mut info = self.clone();
+        use ServerEdit::*;
+        match edit {
+            Rename(name) => info.name = name,
+            AddCategory(name) => info.categories.push(Category {
+                id: Uuid::new_v4(),
+                name,
+            }),
+            AddChannel(name, category) => {
+                if category.is_some_and(|category| {
+                    !info.categories.iter().any(|known| known.id == category)
+                }) {
+                    return None;
+                }
+                info.channels.push(TextChannel {
+                    id: Uuid::new_v4(),
+                    name,
+                    category,
+                });
+            }
+            RemoveChannel(channel_id) => info.channels.retain(|channel| channel.id != channel_id),
+            AddRole(name, permissions) => info.roles.push(Role {
+                id: Uuid::new_v4(),
+                name,
+                permissions,
+            }),
+            SetRole(user_id, role_id, granted) => {
+                if !info.roles.iter().any(|role| role.id == role_id) {
+                    return None;
+                }
+                let role_ids = info.members.get_mut(&user_id)?;
+                if granted {
+                    role_ids.insert(role_id);
+                } else {
+                    role_ids.remove(&role_id);
+                }
+            }
+            AddMember(user_id) => {
+                info.members.entry(user_id).or_default();
+            }
+            RemoveMember(user_id) => {
+                info.members.remove(&user_id);
+            }
+        }
+        if info == *self {
+            return None;
+        }
+        info.version += 1;
+        info.changed_by = client_id;
+        info.signed = RevisionSignature::default();
+        Some(info)
+    }
+    //Whether the author of newer signed it and was allowed to make every change between the two.
+    pub fn allows(&self, newer: &ServerInfo) -> bool {
+        let author = &newer.changed_by;
+        let may = |changed: bool, permission| !changed || self.can(author, permission);
+        let channels_changed = self.name != newer.name
+            || self.categories != newer.categories
+            || self.channels != newer.channels;
+        let roles_changed = self.roles != newer.roles
+            || newer.members.iter().any(|(member, role_ids)| {
+                self.members
+                    .get(member)
+                    .map_or(!role_ids.is_empty(), |old_role_ids| {
+                        old_role_ids != role_ids
+                    })
+            });
+        let mut without_author = self.members.clone();
+        let left = without_author.remove(author).is_some() && without_author == newer.members;
+        let members_changed = !self.members.keys().eq(newer.members.keys());
+        //Nobody takes the owner off their server or takes roles away from them but the owner.
+        let owner_demoted = self.members.get(&self.owner).is_some_and(|old_role_ids| {
+            !newer
+                .members
+                .get(&self.owner)
+                .is_some_and(|role_ids| role_ids.is_superset(old_role_ids))
+        });
+        newer.is_signed()
+            && newer.owner == self.owner
+            && (!owner_demoted || author == &self.owner)
+            && may(channels_changed, Permission::ManageChannels)
+            && may(roles_changed, Permission::ManageRoles)
+            && (left || may(members_changed, Permission::ManageMembers))
+    }
+}
+#[derive(PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
+pub struct FileOffer {
+    pub id: TransferId,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TransferStatus {
+    #[default]
+    Offered,
+    Transferring,
+    //The connection dropped, the receiver asks for the rest once it is back.
+    Interrupted,
+    Completed,
+    Cancelled,
+    Failed,
+}
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct FileTransfer {
+    pub offer: FileOffer,
+    pub incoming: bool,
+    //Where the file is read from or written to, an incoming file only has one once accepted.
+    pub path: Option<PathBuf>,
+    pub transferred: u64,
+    pub status: TransferStatus,
+}
+impl FileTransfer {
+    pub fn new(offer: FileOffer, incoming: bool, path: Option<PathBuf>) -> Self {
+        Self {
+            offer,
+            incoming,
+            path,
+            transferred: 0,
+            status: TransferStatus::Offered,
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            TransferStatus::Completed | TransferStatus::Cancelled | TransferStatus::Failed
+        )
+    }
+}
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum VoiceStatus {
+    #[default]
+    Idle,
+    //We are ringing the remote.
+    Calling,
+    //The remote is ringing us.
+    Ringing,
+    Active,
 }
 #[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
 pub enum ConnectionProgress {
@@ -23,18 +439,38 @@ pub enum ConnectionProgress {
     CallReplySent,
     CallReplyReceived,
     Established,
+    Disconnected,
+    Failed,
 }
 
+//Whether we can currently reach the signaling server.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum SignalingStatus {
+    #[default]
+    Offline,
+    Connecting,
+    Online,
+}
 #[derive(PartialEq, Default, Serialize, Deserialize, Clone, Debug)]
 pub struct ConnectionDetails {
     pub id: UserId,
     pub sdp: SDP,
+    pub public_key: String,
 }
 #[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
 pub struct Connection {
     remote: ConnectionDetails,
     messages: Vec<ChaosMessage>,
+    //Where older stored history starts, None once everything is loaded.
+    history_cursor: Option<i64>,
     pub progress: ConnectionProgress,
+    pub call_id: Option<CallId>,
+    //Cleared by the peer thread once the remote goes quiet.
+    remote_typing: bool,
+    transfers: Vec<FileTransfer>,
+    //Voice calls run on top of an established connection.
+    pub voice: VoiceStatus,
+    pub muted: bool,
 }
 
 impl Connection {
@@ -42,29 +478,315 @@ impl Connection {
         Self {
             remote: ConnectionDetails {
                 id: remote_id,
-                sdp: Default::default(),
+                ..Default::default()
             },
             messages: Default::default(),
+            history_cursor: None,
             progress: Default::default(),
+            call_id: None,
+            remote_typing: false,
+            transfers: Vec::new(),
+            voice: VoiceStatus::Idle,
+            muted: false,
         }
     }
     pub fn set_progress(&mut self, progress: ConnectionProgress) {
         self.progress = progress;
+        //Nobody we aren't connected to can be typing to us or talking to us.
+        if progress != ConnectionProgress::Established {
+            self.remote_typing = false;
+            self.end_voice();
+        }
+        //Transfers go on through a short disconnect, their channels only go away with the
+        //connection.
+        if matches!(
+            progress,
+            ConnectionProgress::Failed | ConnectionProgress::Closed
+        ) {
+            for transfer in self.transfers.iter_mut() {
+                if transfer.status == TransferStatus::Transferring {
+                    transfer.status = TransferStatus::Interrupted;
+                }
+            }
+        }
+    }
+    pub fn add_message(&mut self, message: ChaosMessage) {
+        self.messages.push(message);
+    }
+    pub fn prepend_messages(&mut self, messages: Vec<ChaosMessage>, history_cursor: Option<i64>) {
+        self.messages.splice(0..0, messages);
+        self.history_cursor = history_cursor;
+    }
+    pub fn history_cursor(&self) -> Option<i64> {
+        self.history_cursor
+    }
+    pub fn set_remote_key(&mut self, public_key: String) {
+        self.remote.public_key = public_key;
+    }
+    //We verified who they are on an earlier call.
+    pub fn is_known(&self) -> bool {
+        !self.remote.public_key.is_empty()
+    }
+    //No call with them is under way.
+    pub fn is_idle(&self) -> bool {
+        matches!(
+            self.progress,
+            ConnectionProgress::Closed
+                | ConnectionProgress::Disconnected
+                | ConnectionProgress::Failed
+        )
+    }
+    pub fn messages(&self) -> &[ChaosMessage] {
+        &self.messages
+    }
+    //Acks can arrive out of order, so a status only ever moves forward.
+    pub fn set_message_status(&mut self, message_id: &MessageId, status: MessageStatus) -> bool {
+        let Some(message) = self
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|message| &message.id == message_id)
+        else {
+            return false;
+        };
+        if message.status >= status {
+            return false;
+        }
+        message.status = status;
+        true
+    }
+    //Marks everything the remote sent us as read and returns what changed.
+    pub fn mark_read(&mut self) -> Vec<MessageId> {
+        let remote_id = &self.remote.id;
+        self.messages
+            .iter_mut()
+            .filter(|message| {
+                &message.client_id == remote_id && message.status < MessageStatus::Read
+            })
+            .map(|message| {
+                message.status = MessageStatus::Read;
+                message.id
+            })
+            .collect()
+    }
+    pub fn has_unread(&self) -> bool {
+        self.messages.iter().any(|message| {
+            message.client_id == self.remote.id && message.status < MessageStatus::Read
+        })
+    }
+    pub fn set_remote_typing(&mut self, typing: bool) {
+        self.remote_typing = typing;
+    }
+    pub fn is_remote_typing(&self) -> bool {
+        self.remote_typing
+    }
+    //Returns whether there was a call to end.
+    pub fn end_voice(&mut self) -> bool {
+        let ended = self.voice != VoiceStatus::Idle;
+        self.voice = VoiceStatus::Idle;
+        self.muted = false;
+        ended
+    }
+    pub fn add_transfer(&mut self, transfer: FileTransfer) {
+        self.transfers.push(transfer);
+    }
+    pub fn transfers(&self) -> &[FileTransfer] {
+        &self.transfers
+    }
+    //Finished transfers stay the way they ended.
+    pub fn transfer_mut(&mut self, transfer_id: &TransferId) -> Option<&mut FileTransfer> {
+        self.transfers
+            .iter_mut()
+            .find(|transfer| &transfer.offer.id == transfer_id && !transfer.is_finished())
+    }
+}
+//The messages of a group or server channel loaded so far.
+#[derive(PartialEq, Default, Serialize, Deserialize, Clone, Debug)]
+pub struct History {
+    messages: Vec<ChaosMessage>,
+    //Where older stored history starts, None once everything is loaded.
+    history_cursor: Option<i64>,
+}
+impl History {
+    pub fn add_message(&mut self, message: ChaosMessage) {
+        self.messages.push(message);
+    }
+    pub fn prepend_messages(&mut self, messages: Vec<ChaosMessage>, history_cursor: Option<i64>) {
+        self.messages.splice(0..0, messages);
+        self.history_cursor = history_cursor;
+    }
+    pub fn history_cursor(&self) -> Option<i64> {
+        self.history_cursor
+    }
+    pub fn messages(&self) -> &[ChaosMessage] {
+        &self.messages
+    }
+    //Only tracks whether our messages went out, members don't ack these.
+    pub fn set_message_status(&mut self, message_id: &MessageId, status: MessageStatus) -> bool {
+        let Some(message) = self
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|message| &message.id == message_id && message.status < status)
+        else {
+            return false;
+        };
+        message.status = status;
+        true
+    }
+}
+//Chats with several people, each member sending to every other one directly. Groups we left or
+//got removed from stay around with their history, without us in them.
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
+pub struct Group {
+    pub info: GroupInfo,
+    pub history: History,
+}
+impl Group {
+    pub fn new(info: GroupInfo) -> Self {
+        Self {
+            info,
+            history: History::default(),
+        }
+    }
+    pub fn is_member(&self, client_id: &UserId) -> bool {
+        self.info.members.contains(client_id)
+    }
+}
+//Stored as its position, like MessageStatus.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FriendStatus {
+    #[default]
+    Stranger,
+    RequestSent,
+    RequestReceived,
+    Friends,
+}
+//An entry in the contact book, kept whether or not we ever connected to them.
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
+pub struct Contact {
+    pub id: UserId,
+    pub display_name: String,
+    //Empty until a friend request tells us, it is checked against the id first.
+    pub public_key: String,
+    pub notes: String,
+    pub friend_status: FriendStatus,
+    //Calls and friend requests from blocked contacts are turned down without asking.
+    pub blocked: bool,
+}
+impl Contact {
+    pub fn new(id: UserId) -> Self {
+        Self {
+            display_name: id.clone(),
+            id,
+            public_key: String::new(),
+            notes: String::new(),
+            friend_status: FriendStatus::Stranger,
+            blocked: false,
+        }
+    }
+}
+//Works like a group with its members split over channels. Like groups, servers stay around once
+//we are off them.
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
+pub struct Server {
+    pub info: ServerInfo,
+    histories: HashMap<ChannelId, History>,
+}
+impl Server {
+    pub fn new(info: ServerInfo) -> Self {
+        Self {
+            info,
+            histories: HashMap::new(),
+        }
+    }
+    pub fn history(&self, channel_id: &ChannelId) -> Option<&History> {
+        self.histories.get(channel_id)
+    }
+    pub fn history_mut(&mut self, channel_id: ChannelId) -> &mut History {
+        self.histories.entry(channel_id).or_default()
+    }
+    pub fn is_member(&self, client_id: &UserId) -> bool {
+        self.info.is_member(client_id)
     }
 }
 #[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
 pub struct IndependentState {
     pub connection_details: ConnectionDetails,
     pub connections: HashMap<UserId, Connection>,
+    pub groups: HashMap<GroupId, Group>,
+    pub servers: HashMap<ServerId, Server>,
+    pub contacts: HashMap<UserId, Contact>,
+    pub signaling_status: SignalingStatus,
 }
 impl Default for IndependentState {
     fn default() -> Self {
         Self {
             connection_details: ConnectionDetails::default(),
             connections: Default::default(),
+            groups: Default::default(),
+            servers: Default::default(),
+            contacts: Default::default(),
+            signaling_status: Default::default(),
         }
     }
 }
+impl IndependentState {
+    //Groups we are in together with remote_id.
+    pub fn shared_groups<'a>(&'a self, remote_id: &'a UserId) -> impl Iterator<Item = &'a Group> {
+        let client_id = &self.connection_details.id;
+        self.groups
+            .values()
+            .filter(move |group| group.is_member(client_id) && group.is_member(remote_id))
+    }
+    pub fn shared_servers<'a>(&'a self, remote_id: &'a UserId) -> impl Iterator<Item = &'a Server> {
+        let client_id = &self.connection_details.id;
+        self.servers
+            .values()
+            .filter(move |server| server.is_member(client_id) && server.is_member(remote_id))
+    }
+    //Everyone we have had to do with and haven't blocked: contacts, whoever we talked to and
+    //co-members.
+    pub fn acquaintances(&self) -> BTreeSet<UserId> {
+        let mut acquaintances = self.co_members();
+        acquaintances.extend(self.contacts.keys().cloned());
+        acquaintances.extend(self.connections.keys().cloned());
+        acquaintances.retain(|user_id| !self.is_blocked(user_id));
+        acquaintances
+    }
+    //Everyone we are in a group or on a server with.
+    pub fn co_members(&self) -> BTreeSet<UserId> {
+        let client_id = &self.connection_details.id;
+        let groups = self
+            .groups
+            .values()
+            .filter(|group| group.is_member(client_id))
+            .flat_map(|group| group.info.others(client_id));
+        let servers = self
+            .servers
+            .values()
+            .filter(|server| server.is_member(client_id))
+            .flat_map(|server| server.info.others(client_id));
+        groups.chain(servers).cloned().collect()
+    }
+    pub fn is_friend(&self, remote_id: &UserId) -> bool {
+        self.contacts
+            .get(remote_id)
+            .is_some_and(|contact| contact.friend_status == FriendStatus::Friends)
+    }
+    pub fn is_blocked(&self, remote_id: &UserId) -> bool {
+        self.contacts
+            .get(remote_id)
+            .is_some_and(|contact| contact.blocked)
+    }
+    //Contacts whose answer to our friend request is still out.
+    pub fn pending_friends(&self) -> impl Iterator<Item = &UserId> {
+        self.contacts
+            .values()
+            .filter(|contact| contact.friend_status == FriendStatus::RequestSent)
+            .map(|contact| &contact.id)
+    }
+}
 pub type SharedState = Arc<RwLock<IndependentState>>;
 //This contains the placeholders for gui inputs
 #[derive(Default, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
@@ -72,6 +794,9 @@ pub enum SidebarButton {
     #[default]
     NewConnection,
     Chat(UserId),
+    Group(GroupId),
+    Channel(ServerId, ChannelId),
+    Contacts,
 }
 
 #[derive(Default, Clone, Serialize, Deserialize)]
@@ -80,4 +805,243 @@ pub struct GUIState {
     pub current_message: String,
     pub current_sidebar_button: SidebarButton,
     pub display_state: IndependentState,
+    //Errors reported by the scheduler, until the user dismisses them.
+    pub notifications: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A two member group and the next revision of it by each of them.
+    fn group() -> (Identity, Identity, GroupInfo) {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let members = BTreeSet::from([bob.user_id()]);
+        let mut info = GroupInfo::new("chaos".to_string(), alice.user_id(), members);
+        info.sign(&alice);
+        (alice, bob, info)
+    }
+
+    fn group_edit(info: &GroupInfo, by: &Identity) -> GroupInfo {
+        let mut newer = info.with_members(info.members.clone(), by.user_id());
+        newer.sign(by);
+        newer
+    }
+
+    #[test]
+    fn stale_group_revision_is_ignored() {
+        let (alice, _, info) = group();
+        let newer = group_edit(&info, &alice);
+        assert!(newer.supersedes(&info));
+        assert!(!info.supersedes(&newer));
+        assert!(!newer.supersedes(&newer));
+    }
+
+    #[test]
+    fn equal_group_versions_go_to_the_higher_user_id() {
+        let (alice, bob, info) = group();
+        let by_alice = group_edit(&info, &alice);
+        let by_bob = group_edit(&info, &bob);
+        assert_eq!(by_alice.version, by_bob.version);
+        let alice_wins = alice.user_id() > bob.user_id();
+        assert_eq!(by_alice.supersedes(&by_bob), alice_wins);
+        assert_eq!(by_bob.supersedes(&by_alice), !alice_wins);
+    }
+
+    #[test]
+    fn unsigned_or_forged_group_revision_is_rejected() {
+        let (alice, bob, info) = group();
+        assert!(info.allows(&group_edit(&info, &bob)));
+        let unsigned = info.with_members(info.members.clone(), bob.user_id());
+        assert!(!info.allows(&unsigned));
+        //Signed by bob in alice's name.
+        let mut forged = info.with_members(BTreeSet::from([bob.user_id()]), alice.user_id());
+        forged.sign(&bob);
+        assert!(!info.allows(&forged));
+        let mut tampered = group_edit(&info, &alice);
+        tampered.members.insert(Identity::generate().user_id());
+        assert!(!info.allows(&tampered));
+    }
+
+    #[test]
+    fn non_member_group_edit_is_rejected() {
+        let (_, _, info) = group();
+        let outsider = Identity::generate();
+        let mut newer = info.with_members(
+            info.members
+                .iter()
+                .cloned()
+                .chain([outsider.user_id()])
+                .collect(),
+            outsider.user_id(),
+        );
+        newer.sign(&outsider);
+        assert!(newer.is_signed());
+        assert!(!info.allows(&newer));
+    }
+
+    //The next revision with by's edit, signed by them.
+    fn edit(info: &ServerInfo, edit: ServerEdit, by: &Identity) -> ServerInfo {
+        let mut newer = info.edited(edit, by.user_id()).unwrap();
+        newer.sign(by);
+        newer
+    }
+
+    //The owner gives member a new role with the given permissions.
+    fn grant(
+        info: &ServerInfo,
+        owner: &Identity,
+        member: &Identity,
+        permissions: &[Permission],
+    ) -> ServerInfo {
+        let name = format!("{:?}", permissions);
+        let permissions = permissions.iter().copied().collect();
+        let with_role = edit(info, ServerEdit::AddRole(name.clone(), permissions), owner);
+        let role_id = with_role
+            .roles
+            .iter()
+            .find(|role| role.name == name)
+            .unwrap()
+            .id;
+        let granted = edit(
+            &with_role,
+            ServerEdit::SetRole(member.user_id(), role_id, true),
+            owner,
+        );
+        assert!(info.allows(&with_role) && with_role.allows(&granted));
+        granted
+    }
+
+    fn server(owner: &Identity, members: &[&Identity]) -> ServerInfo {
+        let members = members.iter().map(|member| member.user_id());
+        let mut info = ServerInfo::new("chaos".to_string(), owner.user_id(), members);
+        info.sign(owner);
+        info
+    }
+
+    #[test]
+    fn each_permission_is_needed_for_its_changes() {
+        let owner = Identity::generate();
+        let member = Identity::generate();
+        let edits = [
+            (
+                Permission::ManageChannels,
+                ServerEdit::Rename("renamed".to_string()),
+            ),
+            (
+                Permission::ManageRoles,
+                ServerEdit::AddRole("mod".to_string(), BTreeSet::new()),
+            ),
+            (
+                Permission::ManageMembers,
+                ServerEdit::AddMember(Identity::generate().user_id()),
+            ),
+        ];
+        let info = server(&owner, &[&member]);
+        for (permission, server_edit) in edits {
+            assert!(info.allows(&edit(&info, server_edit.clone(), &owner)));
+            assert!(!info.allows(&edit(&info, server_edit.clone(), &member)));
+            let others = [
+                Permission::ManageChannels,
+                Permission::ManageRoles,
+                Permission::ManageMembers,
+            ]
+            .into_iter()
+            .filter(|other| *other != permission)
+            .collect::<Vec<Permission>>();
+            let with_others = grant(&info, &owner, &member, &others);
+            assert!(!with_others.allows(&edit(&with_others, server_edit.clone(), &member)));
+            let with_permission = grant(&info, &owner, &member, &[permission]);
+            assert!(with_permission.allows(&edit(&with_permission, server_edit, &member)));
+        }
+    }
+
+    #[test]
+    fn members_can_leave_without_permission() {
+        let owner = Identity::generate();
+        let member = Identity::generate();
+        let info = server(&owner, &[&member]);
+        let left = edit(&info, ServerEdit::RemoveMember(member.user_id()), &member);
+        assert!(info.allows(&left));
+        let other = Identity::generate();
+        let info = server(&owner, &[&member, &other]);
+        let removed = edit(&info, ServerEdit::RemoveMember(other.user_id()), &member);
+        assert!(!info.allows(&removed));
+    }
+
+    #[test]
+    fn unsigned_revision_is_rejected() {
+        let owner = Identity::generate();
+        let info = server(&owner, &[]);
+        let newer = info
+            .edited(ServerEdit::Rename("renamed".to_string()), owner.user_id())
+            .unwrap();
+        assert!(!info.allows(&newer));
+    }
+
+    #[test]
+    fn bad_signature_is_rejected() {
+        let owner = Identity::generate();
+        let member = Identity::generate();
+        let info = server(&owner, &[&member]);
+        //Signed by the member in the owner's name.
+        let mut forged = info
+            .edited(ServerEdit::Rename("renamed".to_string()), owner.user_id())
+            .unwrap();
+        forged.sign(&member);
+        assert!(!info.allows(&forged));
+        //Changed after the owner signed it.
+        let mut tampered = edit(&info, ServerEdit::Rename("renamed".to_string()), &owner);
+        tampered.name = "tampered".to_string();
+        assert!(!info.allows(&tampered));
+    }
+
+    #[test]
+    fn owner_cannot_change() {
+        let owner = Identity::generate();
+        let member = Identity::generate();
+        let info = server(&owner, &[&member]);
+        let mut newer = info
+            .edited(ServerEdit::Rename("renamed".to_string()), owner.user_id())
+            .unwrap();
+        newer.owner = member.user_id();
+        newer.sign(&owner);
+        assert!(!info.allows(&newer));
+        let everything = [
+            Permission::ManageChannels,
+            Permission::ManageRoles,
+            Permission::ManageMembers,
+        ];
+        let info = grant(&info, &owner, &member, &everything);
+        let mut newer = info
+            .edited(ServerEdit::Rename("renamed".to_string()), member.user_id())
+            .unwrap();
+        newer.owner = member.user_id();
+        newer.sign(&member);
+        assert!(!info.allows(&newer));
+    }
+
+    #[test]
+    fn only_the_owner_removes_or_demotes_the_owner() {
+        let owner = Identity::generate();
+        let member = Identity::generate();
+        let info = server(&owner, &[&member]);
+        let info = grant(
+            &info,
+            &owner,
+            &member,
+            &[Permission::ManageMembers, Permission::ManageRoles],
+        );
+        let removed = edit(&info, ServerEdit::RemoveMember(owner.user_id()), &member);
+        assert!(!info.allows(&removed));
+        let left = edit(&info, ServerEdit::RemoveMember(owner.user_id()), &owner);
+        assert!(info.allows(&left));
+
+        let info = grant(&info, &owner, &owner, &[Permission::ManageChannels]);
+        let role_id = *info.members[&owner.user_id()].iter().next().unwrap();
+        let demote = ServerEdit::SetRole(owner.user_id(), role_id, false);
+        assert!(!info.allows(&edit(&info, demote.clone(), &member)));
+        assert!(info.allows(&edit(&info, demote, &owner)));
+    }
 }
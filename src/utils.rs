@@ -1,7 +1,9 @@
 use crate::scheduler::{ChannelAttachment, ThreadTypes};
 
+pub mod audio;
 pub mod crypto;
 pub mod message;
+pub mod storage;
 pub mod webrtc;
 
 pub trait Attach<T = ChannelAttachment> {
@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+use crate::config::Config;
+
+#[cfg(feature = "audio-device")]
+mod device;
+
+//Calls carry 48 kHz mono audio in 20 ms frames.
+pub const SAMPLE_RATE: u32 = 48000;
+pub const FRAME_SAMPLES: usize = 960;
+//The sink writes its header every second, so the file stays readable if we never get to close it.
+const WAV_FLUSH_FRAMES: usize = 50;
+
+pub trait AudioSource: Send {
+    //Fills the frame with the next 20 ms, silence where there is nothing to send.
+    fn read_frame(&mut self, frame: &mut [i16]);
+}
+pub trait AudioSink: Send {
+    fn write_frame(&mut self, frame: &[i16]);
+}
+//Gives every call a source for what we say and a sink for what the remote says.
+pub trait AudioDevice: Send + Sync {
+    fn source(&self) -> Result<Box<dyn AudioSource>>;
+    fn sink(&self) -> Result<Box<dyn AudioSink>>;
+}
+
+//The WAV files from the config if it names any, the system's audio devices otherwise.
+pub fn from_config(config: &Config) -> Arc<dyn AudioDevice> {
+    if config.audio_input.is_some() || config.audio_output.is_some() {
+        return Arc::new(WavFiles {
+            input: config.audio_input.clone(),
+            output: config.audio_output.clone(),
+        });
+    }
+    system_device()
+}
+#[cfg(feature = "audio-device")]
+fn system_device() -> Arc<dyn AudioDevice> {
+    Arc::new(device::SystemDevice)
+}
+#[cfg(not(feature = "audio-device"))]
+fn system_device() -> Arc<dyn AudioDevice> {
+    println!("Built without audio device support, calls are silent.");
+    Arc::new(Silence)
+}
+
+pub struct Silence;
+impl AudioSource for Silence {
+    fn read_frame(&mut self, frame: &mut [i16]) {
+        frame.fill(0);
+    }
+}
+impl AudioSink for Silence {
+    fn write_frame(&mut self, _frame: &[i16]) {}
+}
+impl AudioDevice for Silence {
+    fn source(&self) -> Result<Box<dyn AudioSource>> {
+        Ok(Box::new(Silence))
+    }
+    fn sink(&self) -> Result<Box<dyn AudioSink>> {
+        Ok(Box::new(Silence))
+    }
+}
+
+//Every call plays the input file from the start and records over the output file, silence
+//stands in for the one that isn't set.
+pub struct WavFiles {
+    pub input: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+}
+impl AudioDevice for WavFiles {
+    fn source(&self) -> Result<Box<dyn AudioSource>> {
+        match &self.input {
+            Some(path) => Ok(Box::new(WavSource::open(path)?)),
+            None => Ok(Box::new(Silence)),
+        }
+    }
+    fn sink(&self) -> Result<Box<dyn AudioSink>> {
+        match &self.output {
+            Some(path) => Ok(Box::new(WavSink::create(path)?)),
+            None => Ok(Box::new(Silence)),
+        }
+    }
+}
+
+//Plays the file once, then silence.
+pub struct WavSource {
+    samples: std::vec::IntoIter<i16>,
+}
+impl WavSource {
+    pub fn open(path: &Path) -> Result<Self> {
+        let wav_error = |e| anyhow!("{}: {}", path.display(), e);
+        let reader = WavReader::open(path).map_err(wav_error)?;
+        let spec = reader.spec();
+        if spec.sample_rate != SAMPLE_RATE
+            || spec.sample_format != SampleFormat::Int
+            || spec.bits_per_sample != 16
+        {
+            bail!("{} is not 48 kHz 16 bit audio.", path.display());
+        }
+        //Calls are mono, so only the first channel goes out.
+        let samples = reader
+            .into_samples::<i16>()
+            .step_by(spec.channels.max(1) as usize)
+            .collect::<Result<Vec<i16>, _>>()
+            .map_err(wav_error)?;
+        Ok(Self {
+            samples: samples.into_iter(),
+        })
+    }
+}
+impl AudioSource for WavSource {
+    fn read_frame(&mut self, frame: &mut [i16]) {
+        for sample in frame.iter_mut() {
+            *sample = self.samples.next().unwrap_or(0);
+        }
+    }
+}
+
+pub struct WavSink {
+    writer: WavWriter<BufWriter<File>>,
+    frames: usize,
+}
+impl WavSink {
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let writer =
+            WavWriter::create(path, spec).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(Self { writer, frames: 0 })
+    }
+}
+impl AudioSink for WavSink {
+    fn write_frame(&mut self, frame: &[i16]) {
+        let written = frame
+            .iter()
+            .try_for_each(|sample| self.writer.write_sample(*sample));
+        self.frames += 1;
+        let flushed = match written {
+            Ok(()) if self.frames.is_multiple_of(WAV_FLUSH_FRAMES) => self.writer.flush(),
+            written => written,
+        };
+        if let Err(e) = flushed {
+            println!("Could not record audio: {}", e);
+        }
+    }
+}
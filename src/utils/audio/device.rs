@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, SampleRate, Stream, StreamConfig};
+
+use super::{AudioDevice, AudioSink, AudioSource, SAMPLE_RATE};
+
+//At most 200 ms wait between a device and a call, older samples are dropped so the delay can't
+//keep growing when the two run at slightly different speeds.
+const MAX_BUFFERED: usize = SAMPLE_RATE as usize / 5;
+
+type Samples = Arc<Mutex<VecDeque<i16>>>;
+
+//The system's default microphone and speakers.
+pub struct SystemDevice;
+impl AudioDevice for SystemDevice {
+    fn source(&self) -> Result<Box<dyn AudioSource>> {
+        let samples = Samples::default();
+        let stop = run_stream(true, samples.clone())?;
+        Ok(Box::new(DeviceSource {
+            samples,
+            _stop: stop,
+        }))
+    }
+    fn sink(&self) -> Result<Box<dyn AudioSink>> {
+        let samples = Samples::default();
+        let stop = run_stream(false, samples.clone())?;
+        Ok(Box::new(DeviceSink {
+            samples,
+            _stop: stop,
+        }))
+    }
+}
+
+//The stream stops once this is dropped.
+struct DeviceSource {
+    samples: Samples,
+    _stop: Sender<()>,
+}
+impl AudioSource for DeviceSource {
+    fn read_frame(&mut self, frame: &mut [i16]) {
+        let mut samples = lock(&self.samples);
+        for sample in frame.iter_mut() {
+            *sample = samples.pop_front().unwrap_or(0);
+        }
+    }
+}
+struct DeviceSink {
+    samples: Samples,
+    _stop: Sender<()>,
+}
+impl AudioSink for DeviceSink {
+    fn write_frame(&mut self, frame: &[i16]) {
+        push_samples(&mut lock(&self.samples), frame.iter().copied());
+    }
+}
+
+//Streams have to stay on the thread that built them, so each gets a thread of its own that
+//keeps it playing until the returned sender is dropped.
+fn run_stream(input: bool, samples: Samples) -> Result<Sender<()>> {
+    let (started_tx, started_rx) = mpsc::channel::<Result<()>>();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        let stream = match build_stream(input, samples) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            }
+        };
+        let _ = started_tx.send(stream.play().map_err(|e| anyhow!(e)));
+        //Nothing is ever sent, this returns once the sender is gone.
+        let _ = stop_rx.recv();
+    });
+    started_rx
+        .recv()
+        .map_err(|_| anyhow!("The audio thread stopped."))??;
+    Ok(stop_tx)
+}
+//Devices are opened as 48 kHz mono floats, what every backend can convert to.
+fn build_stream(input: bool, samples: Samples) -> Result<Stream> {
+    let host = cpal::default_host();
+    let config = StreamConfig {
+        channels: 1,
+        sample_rate: SampleRate(SAMPLE_RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let on_error = |e| println!("Audio device error: {}", e);
+    let stream = if input {
+        let device = host
+            .default_input_device()
+            .ok_or_else(|| anyhow!("No microphone found."))?;
+        device.build_input_stream(
+            &config,
+            move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                let data = data.iter().map(|sample| (sample * i16::MAX as f32) as i16);
+                push_samples(&mut lock(&samples), data);
+            },
+            on_error,
+            None,
+        )?
+    } else {
+        let device = host
+            .default_output_device()
+            .ok_or_else(|| anyhow!("No speakers found."))?;
+        device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let mut samples = lock(&samples);
+                for sample in data.iter_mut() {
+                    *sample = samples.pop_front().unwrap_or(0) as f32 / i16::MAX as f32;
+                }
+            },
+            on_error,
+            None,
+        )?
+    };
+    Ok(stream)
+}
+fn push_samples(samples: &mut VecDeque<i16>, new_samples: impl Iterator<Item = i16>) {
+    samples.extend(new_samples);
+    let excess = samples.len().saturating_sub(MAX_BUFFERED);
+    samples.drain(..excess);
+}
+//A panicking audio callback leaves the samples as they were, which is fine to keep playing.
+fn lock(samples: &Samples) -> MutexGuard<'_, VecDeque<i16>> {
+    samples.lock().unwrap_or_else(PoisonError::into_inner)
+}
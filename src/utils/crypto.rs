@@ -1,5 +1,27 @@
-use anyhow::{Ok, Result};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
 use base64::prelude::*;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::state::UserId;
+
+const HANDSHAKE_CONTEXT: &[u8] = b"chaos-handshake-v1";
+const SESSION_CONTEXT: &[u8] = b"chaos-session-v1";
+const CHALLENGE_CONTEXT: &[u8] = b"chaos-challenge-v1";
+const REVISION_CONTEXT: &[u8] = b"chaos-revision-v1";
+//Short enough to share by hand, long enough that nobody finds a second key for the same id.
+const USER_ID_BYTES: usize = 16;
+
 pub fn encode_b64(input: &str) -> String {
     BASE64_STANDARD.encode(input)
 }
@@ -8,3 +30,376 @@ pub fn decode_b64(input: &str) -> Result<String> {
     let s = String::from_utf8(utf8o)?;
     Ok(s)
 }
+
+//Long-term ed25519 keypair used to sign every session handshake.
+pub struct Identity {
+    signing_key: SigningKey,
+}
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+    //Reads the key from path, or creates and saves a new one on first launch.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let secret_key = decode_key(fs::read_to_string(path)?.trim())?;
+            return Ok(Self {
+                signing_key: SigningKey::from_bytes(&secret_key),
+            });
+        }
+        let identity = Self::generate();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(
+            path,
+            BASE64_STANDARD.encode(identity.signing_key.to_bytes()),
+        )?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(identity)
+    }
+    pub fn public_key(&self) -> String {
+        BASE64_STANDARD.encode(self.signing_key.verifying_key().as_bytes())
+    }
+    pub fn user_id(&self) -> UserId {
+        key_user_id(self.signing_key.verifying_key().as_bytes())
+    }
+    pub fn sign_challenge(&self, challenge: &str) -> String {
+        let transcript = [CHALLENGE_CONTEXT, challenge.as_bytes()].concat();
+        BASE64_STANDARD.encode(self.signing_key.sign(&transcript).to_bytes())
+    }
+    //Signs a revision of a group or server, so nobody can pass their changes off as ours.
+    pub fn sign_revision(&self, revision: &[u8]) -> String {
+        let transcript = [REVISION_CONTEXT, revision].concat();
+        BASE64_STANDARD.encode(self.signing_key.sign(&transcript).to_bytes())
+    }
+}
+
+//The user id is a digest of the identity key, so anyone holding the key can check it.
+pub fn user_id(public_key: &str) -> Result<UserId> {
+    Ok(key_user_id(&decode_key(public_key)?))
+}
+fn key_user_id(public_key: &[u8; 32]) -> UserId {
+    Sha256::digest(public_key)[..USER_ID_BYTES]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+pub fn new_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut challenge);
+    BASE64_STANDARD.encode(challenge)
+}
+//Checks a signed challenge and returns the user id it proves ownership of.
+pub fn verify_challenge(challenge: &str, public_key: &str, signature: &str) -> Result<UserId> {
+    let identity_key = VerifyingKey::from_bytes(&decode_key(public_key)?)?;
+    let signature = Signature::from_slice(&BASE64_STANDARD.decode(signature)?)?;
+    let transcript = [CHALLENGE_CONTEXT, challenge.as_bytes()].concat();
+    identity_key
+        .verify(&transcript, &signature)
+        .map_err(|_| anyhow!("Challenge signature does not match the identity key."))?;
+    user_id(public_key)
+}
+
+//Checks a signed revision and returns the user id of its author.
+pub fn verify_revision(revision: &[u8], public_key: &str, signature: &str) -> Result<UserId> {
+    let identity_key = VerifyingKey::from_bytes(&decode_key(public_key)?)?;
+    let signature = Signature::from_slice(&BASE64_STANDARD.decode(signature)?)?;
+    let transcript = [REVISION_CONTEXT, revision].concat();
+    identity_key
+        .verify(&transcript, &signature)
+        .map_err(|_| anyhow!("Revision signature does not match the identity key."))?;
+    user_id(public_key)
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct Handshake {
+    pub identity_key: String,
+    pub ephemeral_key: String,
+    pub signature: String,
+}
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone)]
+pub struct SealedFrame {
+    pub counter: u64,
+    pub ciphertext: String,
+}
+
+struct SessionKeys {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    send_counter: u64,
+    recv_counter: u64,
+}
+
+//One end of an encrypted data channel session. Both ends sign their ephemeral key together with
+//the DTLS fingerprints from the SDPs, so a signaling server that swaps descriptions can't pass the
+//handshake.
+pub struct Session {
+    ephemeral: Option<EphemeralSecret>,
+    ephemeral_public: PublicKey,
+    keys: Option<SessionKeys>,
+}
+impl Default for Session {
+    fn default() -> Self {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        Self {
+            ephemeral: Some(ephemeral),
+            ephemeral_public,
+            keys: None,
+        }
+    }
+}
+impl Session {
+    pub fn is_established(&self) -> bool {
+        self.keys.is_some()
+    }
+    pub fn handshake(
+        &self,
+        identity: &Identity,
+        local_fingerprint: &str,
+        remote_fingerprint: &str,
+    ) -> Handshake {
+        let transcript = handshake_transcript(
+            identity.signing_key.verifying_key().as_bytes(),
+            self.ephemeral_public.as_bytes(),
+            local_fingerprint,
+            remote_fingerprint,
+        );
+        Handshake {
+            identity_key: identity.public_key(),
+            ephemeral_key: BASE64_STANDARD.encode(self.ephemeral_public.as_bytes()),
+            signature: BASE64_STANDARD.encode(identity.signing_key.sign(&transcript).to_bytes()),
+        }
+    }
+    //Verifies the remote handshake and derives the session keys, returns the remote identity key.
+    pub fn accept(
+        &mut self,
+        handshake: &Handshake,
+        local_fingerprint: &str,
+        remote_fingerprint: &str,
+    ) -> Result<String> {
+        let identity_key = VerifyingKey::from_bytes(&decode_key(&handshake.identity_key)?)?;
+        let ephemeral_key = PublicKey::from(decode_key(&handshake.ephemeral_key)?);
+        let signature = Signature::from_slice(&BASE64_STANDARD.decode(&handshake.signature)?)?;
+        let transcript = handshake_transcript(
+            identity_key.as_bytes(),
+            ephemeral_key.as_bytes(),
+            remote_fingerprint,
+            local_fingerprint,
+        );
+        identity_key
+            .verify(&transcript, &signature)
+            .map_err(|_| anyhow!("Handshake signature does not match the SDP fingerprints."))?;
+
+        let ephemeral = self
+            .ephemeral
+            .take()
+            .ok_or(anyhow!("Session handshake was already accepted."))?;
+        let shared_secret = ephemeral.diffie_hellman(&ephemeral_key);
+        //Both ends order the fingerprints the same way so they agree on which half is whose.
+        let local_first = local_fingerprint < remote_fingerprint;
+        let (first, second) = if local_first {
+            (local_fingerprint, remote_fingerprint)
+        } else {
+            (remote_fingerprint, local_fingerprint)
+        };
+        let info = [SESSION_CONTEXT, first.as_bytes(), second.as_bytes()].concat();
+        let mut okm = [0u8; 64];
+        Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+            .expand(&info, &mut okm)
+            .map_err(|_| anyhow!("Could not derive session keys."))?;
+        let (first_key, second_key) = okm.split_at(32);
+        let (send, recv) = if local_first {
+            (first_key, second_key)
+        } else {
+            (second_key, first_key)
+        };
+        self.keys = Some(SessionKeys {
+            send: ChaCha20Poly1305::new(Key::from_slice(send)),
+            recv: ChaCha20Poly1305::new(Key::from_slice(recv)),
+            send_counter: 0,
+            recv_counter: 0,
+        });
+        Ok(handshake.identity_key.clone())
+    }
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<SealedFrame> {
+        let keys = self
+            .keys
+            .as_mut()
+            .ok_or(anyhow!("Session is not established yet."))?;
+        let counter = keys.send_counter;
+        let ciphertext = keys
+            .send
+            .encrypt(&counter_nonce(counter), plaintext)
+            .map_err(|_| anyhow!("Could not encrypt frame."))?;
+        keys.send_counter += 1;
+        Ok(SealedFrame {
+            counter,
+            ciphertext: BASE64_STANDARD.encode(ciphertext),
+        })
+    }
+    pub fn open(&mut self, frame: &SealedFrame) -> Result<Vec<u8>> {
+        let keys = self
+            .keys
+            .as_mut()
+            .ok_or(anyhow!("Session is not established yet."))?;
+        if frame.counter < keys.recv_counter {
+            bail!("Replayed frame {}.", frame.counter);
+        }
+        let ciphertext = BASE64_STANDARD.decode(&frame.ciphertext)?;
+        let plaintext = keys
+            .recv
+            .decrypt(&counter_nonce(frame.counter), ciphertext.as_slice())
+            .map_err(|_| anyhow!("Could not decrypt frame {}.", frame.counter))?;
+        keys.recv_counter = frame.counter + 1;
+        Ok(plaintext)
+    }
+}
+
+//Pulls the DTLS fingerprint out of an SDP, e.g. `a=fingerprint:sha-256 AB:CD:...`.
+pub fn sdp_fingerprint(sdp: &str) -> Option<String> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .map(|fingerprint| fingerprint.to_string())
+}
+
+fn handshake_transcript(
+    identity_key: &[u8],
+    ephemeral_key: &[u8],
+    sender_fingerprint: &str,
+    receiver_fingerprint: &str,
+) -> Vec<u8> {
+    [
+        HANDSHAKE_CONTEXT,
+        identity_key,
+        ephemeral_key,
+        sender_fingerprint.as_bytes(),
+        b"\n",
+        receiver_fingerprint.as_bytes(),
+    ]
+    .concat()
+}
+fn decode_key(input: &str) -> Result<[u8; 32]> {
+    BASE64_STANDARD
+        .decode(input)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid key length."))
+}
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE_FINGERPRINT: &str = "sha-256 AA:AA";
+    const BOB_FINGERPRINT: &str = "sha-256 BB:BB";
+
+    fn established() -> (Session, Session) {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let mut alice_session = Session::default();
+        let mut bob_session = Session::default();
+        let alice_handshake = alice_session.handshake(&alice, ALICE_FINGERPRINT, BOB_FINGERPRINT);
+        let bob_handshake = bob_session.handshake(&bob, BOB_FINGERPRINT, ALICE_FINGERPRINT);
+        let bob_key = alice_session
+            .accept(&bob_handshake, ALICE_FINGERPRINT, BOB_FINGERPRINT)
+            .unwrap();
+        let alice_key = bob_session
+            .accept(&alice_handshake, BOB_FINGERPRINT, ALICE_FINGERPRINT)
+            .unwrap();
+        assert_eq!(user_id(&bob_key).unwrap(), bob.user_id());
+        assert_eq!(user_id(&alice_key).unwrap(), alice.user_id());
+        (alice_session, bob_session)
+    }
+
+    #[test]
+    fn user_id_is_the_key_digest() {
+        let identity = Identity::generate();
+        assert_eq!(identity.user_id().len(), USER_ID_BYTES * 2);
+        assert_eq!(identity.user_id(), user_id(&identity.public_key()).unwrap());
+        assert!(user_id("not a key").is_err());
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let (mut alice, mut bob) = established();
+        for text in ["hello", "", "second frame"] {
+            let frame = alice.seal(text.as_bytes()).unwrap();
+            assert_eq!(bob.open(&frame).unwrap(), text.as_bytes());
+        }
+        let frame = bob.seal(b"reply").unwrap();
+        assert_eq!(alice.open(&frame).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn replayed_frame_is_rejected() {
+        let (mut alice, mut bob) = established();
+        let first = alice.seal(b"first").unwrap();
+        let second = alice.seal(b"second").unwrap();
+        bob.open(&first).unwrap();
+        bob.open(&second).unwrap();
+        assert!(bob.open(&first).is_err());
+        assert!(bob.open(&second).is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let (mut alice, mut bob) = established();
+        let mut frame = alice.seal(b"hello").unwrap();
+        let mut ciphertext = BASE64_STANDARD.decode(&frame.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        frame.ciphertext = BASE64_STANDARD.encode(ciphertext);
+        assert!(bob.open(&frame).is_err());
+        //A frame moved to another counter doesn't open either.
+        let mut frame = alice.seal(b"hello").unwrap();
+        frame.counter += 1;
+        assert!(bob.open(&frame).is_err());
+    }
+
+    #[test]
+    fn handshake_with_swapped_fingerprint_is_rejected() {
+        let alice = Identity::generate();
+        let alice_session = Session::default();
+        let mut bob_session = Session::default();
+        let handshake = alice_session.handshake(&alice, ALICE_FINGERPRINT, BOB_FINGERPRINT);
+        //The signaling server handed bob a different description than alice sent.
+        assert!(bob_session
+            .accept(&handshake, BOB_FINGERPRINT, "sha-256 CC:CC")
+            .is_err());
+        assert!(bob_session
+            .accept(&handshake, "sha-256 CC:CC", ALICE_FINGERPRINT)
+            .is_err());
+        assert!(!bob_session.is_established());
+    }
+
+    #[test]
+    fn handshake_with_foreign_key_is_rejected() {
+        let alice = Identity::generate();
+        let mallory = Identity::generate();
+        let alice_session = Session::default();
+        let mut bob_session = Session::default();
+        //Mallory swaps their own key into alice's handshake, the signature no longer matches it.
+        let mut handshake = alice_session.handshake(&alice, ALICE_FINGERPRINT, BOB_FINGERPRINT);
+        handshake.identity_key = mallory.public_key();
+        assert!(bob_session
+            .accept(&handshake, BOB_FINGERPRINT, ALICE_FINGERPRINT)
+            .is_err());
+        //A valid handshake still proves the key, which is not the id it claims to be.
+        let handshake = alice_session.handshake(&alice, ALICE_FINGERPRINT, BOB_FINGERPRINT);
+        let key = bob_session
+            .accept(&handshake, BOB_FINGERPRINT, ALICE_FINGERPRINT)
+            .unwrap();
+        assert_ne!(user_id(&key).unwrap(), mallory.user_id());
+    }
+}
@@ -0,0 +1,44 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+const DATA_DIR_VAR: &str = "CHAOS_DATA_DIR";
+
+//Where chaos keeps its files, CHAOS_DATA_DIR wins so several clients can run on one machine.
+pub fn data_dir() -> PathBuf {
+    if let Some(data_dir) = env::var_os(DATA_DIR_VAR) {
+        return PathBuf::from(data_dir);
+    }
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("chaos")
+}
+//Received files go to the user's downloads, or next to everything else when the data dir is set.
+pub fn downloads_dir() -> PathBuf {
+    if env::var_os(DATA_DIR_VAR).is_some() {
+        return data_dir().join("downloads");
+    }
+    dirs::download_dir().unwrap_or_else(|| data_dir().join("downloads"))
+}
+//Where a received file called name goes, numbered rather than over a file that is already there.
+pub fn download_path(name: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".to_string());
+    let downloads_dir = downloads_dir();
+    let mut path = downloads_dir.join(&name);
+    let mut copy = 1;
+    while path.exists() {
+        let stem = Path::new(&name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let numbered = match Path::new(&name).extension() {
+            Some(extension) => format!("{} ({}).{}", stem, copy, extension.to_string_lossy()),
+            None => format!("{} ({})", stem, copy),
+        };
+        path = downloads_dir.join(numbered);
+        copy += 1;
+    }
+    path
+}
@@ -0,0 +1,132 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+//Kills the process when the test fails halfway.
+struct Process(Child);
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn data_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("chaos-cli-test-{}", Uuid::new_v4()))
+}
+
+fn cli(data_dir: &Path, signaling_url: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_chaos-cli"));
+    command
+        .env("CHAOS_DATA_DIR", data_dir)
+        .env("RUST_LOG", "off")
+        .args(["--signaling-url", signaling_url]);
+    command
+}
+
+fn whoami(data_dir: &Path) -> String {
+    let output = cli(data_dir, "ws://unused")
+        .arg("whoami")
+        .output()
+        .expect("Could not run chaos-cli.");
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+fn lines(stdout: ChildStdout) -> Receiver<String> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+//Waits for a line containing text, returns everything read until then.
+fn read_until(lines: &Receiver<String>, text: &str) -> Vec<String> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut read = Vec::new();
+    loop {
+        let line = lines
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .unwrap_or_else(|_| panic!("No `{}` in {:?}", text, read));
+        let found = line.contains(text);
+        read.push(line);
+        if found {
+            return read;
+        }
+    }
+}
+
+#[test]
+fn piped_script_goes_out_before_exit() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut signal = Process(
+        Command::new(env!("CARGO_BIN_EXE_chaos-signal"))
+            .arg(address.to_string())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Could not start chaos-signal."),
+    );
+    //Kept until the end, the server stops once nobody reads what it prints.
+    let signal_lines = lines(signal.0.stdout.take().unwrap());
+    read_until(&signal_lines, "listening");
+    let signaling_url = format!("ws://{}/couple", address);
+
+    let (a_dir, b_dir) = (data_dir(), data_dir());
+    let (a_id, b_id) = (whoami(&a_dir), whoami(&b_dir));
+
+    let mut b = Process(
+        cli(&b_dir, &signaling_url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Could not start chaos-cli."),
+    );
+    let b_lines = lines(b.0.stdout.take().unwrap());
+
+    //The whole script is in the pipe and stdin is closed before anything went out.
+    let mut a = Process(
+        cli(&a_dir, &signaling_url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Could not start chaos-cli."),
+    );
+    let a_lines = lines(a.0.stdout.take().unwrap());
+    let mut a_stdin = a.0.stdin.take().unwrap();
+    writeln!(a_stdin, "call {}\nsend {} piped hello", b_id, b_id).unwrap();
+    drop(a_stdin);
+
+    read_until(&b_lines, &format!("call request from {}", a_id));
+    writeln!(b.0.stdin.as_mut().unwrap(), "accept {}", a_id).unwrap();
+    read_until(&b_lines, "piped hello");
+
+    //A exits on its own once the message got there.
+    for _ in 0..TIMEOUT.as_secs() * 10 {
+        if a.0.try_wait().unwrap().is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let status = a.0.try_wait().unwrap().expect("chaos-cli did not exit.");
+    assert!(status.success());
+    let a_output = a_lines.try_iter().collect::<Vec<String>>();
+    assert!(!a_output.iter().any(|line| line.contains("Gave up waiting")));
+
+    let _ = std::fs::remove_dir_all(a_dir);
+    let _ = std::fs::remove_dir_all(b_dir);
+}